@@ -1,5 +1,6 @@
 export const idlFactory = ({ IDL }) => {
   const Result = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
+  const Result_1 = IDL.Variant({ 'Ok' : IDL.Nat, 'Err' : IDL.Text });
   const CapacityInfo = IDL.Record({
     'reward_capacity' : IDL.Nat64,
     'rest_charged' : IDL.Nat,
@@ -9,7 +10,7 @@ export const idlFactory = ({ IDL }) => {
     'invitation_expire' : IDL.Nat64,
     'invitee_count' : IDL.Nat64,
   });
-  const Result_1 = IDL.Variant({ 'Ok' : CapacityInfo, 'Err' : IDL.Text });
+  const Result_2 = IDL.Variant({ 'Ok' : CapacityInfo, 'Err' : IDL.Text });
   const StatsData = IDL.Record({
     'fee' : IDL.Nat,
     'closed' : IDL.Bool,
@@ -18,9 +19,41 @@ export const idlFactory = ({ IDL }) => {
     'fee_with_inviter' : IDL.Nat,
     'ledger' : IDL.Principal,
     'base_expire' : IDL.Nat64,
+    'governance' : IDL.Opt(IDL.Principal),
     'extend_expire' : IDL.Nat64,
   });
-  const Result_2 = IDL.Variant({ 'Ok' : StatsData, 'Err' : IDL.Text });
+  const Result_3 = IDL.Variant({ 'Ok' : StatsData, 'Err' : IDL.Text });
+  const TopUpSource = IDL.Variant({
+    'CyclesMinting' : IDL.Record({ 'e8s' : IDL.Nat64 }),
+    'Canister' : IDL.Record({ 'cycles' : IDL.Nat64, 'canister' : IDL.Principal }),
+  });
+  const TopUpConfig = IDL.Record({
+    'source' : TopUpSource,
+    'threshold' : IDL.Nat64,
+  });
+  const CyclesSample = IDL.Record({
+    'balance' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const TopUpRecord = IDL.Record({
+    'source' : TopUpSource,
+    'error' : IDL.Opt(IDL.Text),
+    'cycles' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const CyclesInfo = IDL.Record({
+    'balance' : IDL.Nat64,
+    'burn_rate' : IDL.Nat64,
+    'last_sample' : IDL.Opt(CyclesSample),
+    'config' : IDL.Opt(TopUpConfig),
+    'top_ups' : IDL.Vec(TopUpRecord),
+  });
+  const PendingSweep = IDL.Record({
+    'created_at' : IDL.Nat64,
+    'reconcile' : IDL.Bool,
+    'in_flight' : IDL.Bool,
+    'amount' : IDL.Nat64,
+  });
   return IDL.Service({
     'activeCapacity' : IDL.Func(
         [IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Principal)],
@@ -28,19 +61,34 @@ export const idlFactory = ({ IDL }) => {
         [],
       ),
     'clearExpireCapacity' : IDL.Func([], [Result], []),
+    'confirmDeposit' : IDL.Func([], [Result_1], []),
     'getAllCapacityInfo' : IDL.Func(
         [IDL.Nat64, IDL.Nat64],
         [IDL.Vec(CapacityInfo)],
         ['query'],
       ),
-    'getCapacityInfo' : IDL.Func([IDL.Principal], [Result_1], ['query']),
-    'getStats' : IDL.Func([], [Result_2], ['query']),
+    'getCapacityInfo' : IDL.Func([IDL.Principal], [Result_2], ['query']),
+    'getCyclesInfo' : IDL.Func([], [CyclesInfo], ['query']),
+    'getDepositAccount' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Vec(IDL.Nat8)],
+        ['query'],
+      ),
+    'getPendingSweeps' : IDL.Func(
+        [],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, PendingSweep))],
+        ['query'],
+      ),
+    'getStats' : IDL.Func([], [Result_3], ['query']),
+    'resolveSweep' : IDL.Func([IDL.Principal, IDL.Bool], [Result_1], []),
     'setBaseExpire' : IDL.Func([IDL.Nat64], [Result], []),
     'setClosed' : IDL.Func([IDL.Bool], [Result], []),
     'setExtendExpire' : IDL.Func([IDL.Nat64], [Result], []),
     'setFee' : IDL.Func([IDL.Nat], [Result], []),
     'setFeeTo' : IDL.Func([IDL.Principal], [Result], []),
     'setFeeWithInviter' : IDL.Func([IDL.Nat], [Result], []),
+    'setGovernance' : IDL.Func([IDL.Opt(IDL.Principal)], [Result], []),
+    'setTopUpConfig' : IDL.Func([IDL.Opt(TopUpConfig)], [Result], []),
   });
 };
 export const init = ({ IDL }) => {
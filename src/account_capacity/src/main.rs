@@ -1,4 +1,6 @@
 use candid::{candid_method, Nat};
+use common::cycles::{self, CyclesInfo, CyclesMonitor, TopUpConfig};
+use common::deposits::{self, PendingSweep, PendingSweeps};
 use ic_cdk::{
     api::{call::CallResult, time},
     export::{
@@ -59,6 +61,8 @@ struct StatsData {
     extend_expire: u64,
 
     ledger: Principal,
+
+    governance: Option<Principal>,
 }
 
 impl Default for StatsData {
@@ -73,6 +77,8 @@ impl Default for StatsData {
 
             base_expire: 0,
             extend_expire: 0,
+
+            governance: None,
         }
     }
 }
@@ -83,6 +89,8 @@ thread_local! {
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     static CAPACITY_INFO: RefCell<AccountCapacityInfo> = RefCell::new(AccountCapacityInfo::default());
     static CHARGE_BLOCK: RefCell<ChargeBlock> = RefCell::new(ChargeBlock::default());
+    static CYCLES: RefCell<CyclesMonitor> = RefCell::new(CyclesMonitor::default());
+    static SWEEPS: RefCell<PendingSweeps> = RefCell::new(PendingSweeps::default());
 }
 
 #[init]
@@ -168,6 +176,16 @@ fn set_extend_expire(extend_expire: u64) -> Result<(), String> {
     })
 }
 
+#[update(name = "setGovernance", guard = "_is_auth")]
+#[candid_method(update, rename = "setGovernance")]
+fn set_governance(governance: Option<Principal>) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.governance = governance;
+        Ok(())
+    })
+}
+
 #[query(name = "getStats")]
 #[candid_method(query, rename = "getStats")]
 fn get_stats() -> Result<StatsData, String> {
@@ -211,6 +229,43 @@ fn get_all_capacity_info(start: usize, limit: usize) -> Vec<CapacityInfo> {
     })
 }
 
+// The ICP paid into this subaccount of the canister is credited to `account`
+// by confirmDeposit.
+#[query(name = "getDepositAccount")]
+#[candid_method(query, rename = "getDepositAccount")]
+fn get_deposit_account(account: Principal) -> AccountIdentifier {
+    deposits::deposit_account(&ic_cdk::api::id(), &account)
+}
+
+// Sweeps the caller's deposit subaccount to fee_to and adds it to
+// rest_charged, activeCapacity can then be called without a block index.
+#[update(name = "confirmDeposit", guard = "_is_closed")]
+#[candid_method(update, rename = "confirmDeposit")]
+async fn confirm_deposit() -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let (ledger, fee_to) = STATS.with(|s| {
+        let stats = s.borrow();
+        (stats.ledger, stats.fee_to)
+    });
+    let amount = deposits::sweep_deposit(&SWEEPS, ledger, caller, fee_to).await?;
+    Ok(_credit_deposit(caller, amount))
+}
+
+#[query(name = "getPendingSweeps", guard = "_is_auth")]
+#[candid_method(query, rename = "getPendingSweeps")]
+fn get_pending_sweeps() -> Vec<(Principal, PendingSweep)> {
+    SWEEPS.with(|s| s.borrow().iter().map(|(k, v)| (*k, v.clone())).collect())
+}
+
+// Settles a sweep the ledger no longer deduplicates, once its history shows
+// whether the transfer went through
+#[update(name = "resolveSweep", guard = "_is_auth")]
+#[candid_method(update, rename = "resolveSweep")]
+fn resolve_sweep(account: Principal, went_through: bool) -> Result<Nat, String> {
+    let amount = deposits::resolve_sweep(&SWEEPS, account, went_through)?;
+    Ok(_credit_deposit(account, amount))
+}
+
 #[update(name = "activeCapacity", guard = "_is_closed")]
 #[candid_method(update, rename = "activeCapacity")]
 async fn active_capacity(
@@ -222,10 +277,10 @@ async fn active_capacity(
     let (ledger, fee, fee_with_inviter, fee_to) = STATS.with(|s| {
         let stats = s.borrow();
         (
-            stats.ledger.clone(),
+            stats.ledger,
             stats.fee.clone(),
             stats.fee_with_inviter.clone(),
-            stats.fee_to.clone(),
+            stats.fee_to,
         )
     });
 
@@ -239,14 +294,7 @@ async fn active_capacity(
 
     let res = CAPACITY_INFO.with(|s| {
         let mut capacity_info = s.borrow_mut();
-        if !capacity_info.contains_key(&caller) {
-            capacity_info.insert(caller, CapacityInfo::default());
-        }
-        let info = capacity_info.get_mut(&caller);
-        let mut info = match info {
-            Some(i) => i,
-            None => return Err("account not found".to_string()),
-        };
+        let info = capacity_info.entry(caller).or_default();
 
         if charge_amount > 0 {
             save_charge_block(block_index)?;
@@ -308,17 +356,39 @@ fn clear_expire_capacity() -> Result<(), String> {
     })
 }
 
+#[query(name = "getCyclesInfo")]
+#[candid_method(query, rename = "getCyclesInfo")]
+fn get_cycles_info() -> CyclesInfo {
+    CYCLES.with(|c| c.borrow().info(ic_cdk::api::canister_balance()))
+}
+
+// None turns automatic top-ups off
+#[update(name = "setTopUpConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setTopUpConfig")]
+fn set_top_up_config(config: Option<TopUpConfig>) -> Result<(), String> {
+    CYCLES.with(|c| c.borrow_mut().set_config(config));
+    Ok(())
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    cycles::heartbeat(&CYCLES).await;
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     let stats = STATS.with(|s| s.borrow().clone());
     let capacity_info = CAPACITY_INFO.with(|a| a.borrow().clone());
     let charge_block = CHARGE_BLOCK.with(|a| a.borrow().clone());
-    ic_cdk::storage::stable_save((stats, capacity_info, charge_block)).unwrap();
+    let cycles = CYCLES.with(|c| c.borrow().clone());
+    let sweeps = SWEEPS.with(|s| s.borrow().clone());
+    ic_cdk::storage::stable_save((stats, capacity_info, charge_block, cycles, sweeps)).unwrap();
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (stats_stored, capacity_store, charge_block) = ic_cdk::storage::stable_restore().unwrap();
+    let (stats_stored, capacity_store, charge_block, cycles_stored, sweeps_stored) =
+        ic_cdk::storage::stable_restore().unwrap();
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         *stats = stats_stored;
@@ -331,6 +401,14 @@ fn post_upgrade() {
         let mut store = a.borrow_mut();
         *store = charge_block;
     });
+    CYCLES.with(|c| {
+        let mut cycles = c.borrow_mut();
+        *cycles = Option::<CyclesMonitor>::unwrap_or_default(cycles_stored);
+    });
+    SWEEPS.with(|s| {
+        let mut sweeps = s.borrow_mut();
+        *sweeps = Option::<PendingSweeps>::unwrap_or_default(sweeps_stored);
+    });
 }
 
 async fn query_one_block(ledger: Principal, block_index: BlockIndex) -> CallResult<Option<Block>> {
@@ -341,17 +419,16 @@ async fn query_one_block(ledger: Principal, block_index: BlockIndex) -> CallResu
 
     let blocks_result = query_blocks(ledger, args.clone()).await?;
 
-    if blocks_result.blocks.len() >= 1 {
+    if !blocks_result.blocks.is_empty() {
         debug_assert_eq!(blocks_result.first_block_index, block_index);
         return Ok(blocks_result.blocks.into_iter().next());
     }
 
     if let Some(func) = blocks_result.archived_blocks.into_iter().find_map(|b| {
-        (b.start <= block_index && (block_index - b.start) < b.length).then(|| b.callback)
+        (b.start <= block_index && (block_index - b.start) < b.length).then_some(b.callback)
     }) {
-        match query_archived_blocks(&func, args).await? {
-            Ok(range) => return Ok(range.blocks.into_iter().next()),
-            _ => (),
+        if let Ok(range) = query_archived_blocks(&func, args).await? {
+            return Ok(range.blocks.into_iter().next());
         }
     }
     Ok(None)
@@ -403,6 +480,17 @@ async fn check_charge_block(
     Ok(amount.e8s())
 }
 
+// Adds swept e8s to what `account` has charged, returns its rest_charged
+fn _credit_deposit(account: Principal, amount: u64) -> Nat {
+    CAPACITY_INFO.with(|s| {
+        let mut capacity_info = s.borrow_mut();
+        let info = capacity_info.entry(account).or_default();
+        info.charged += amount;
+        info.rest_charged += amount;
+        info.rest_charged.clone()
+    })
+}
+
 fn check_inviter(inviter: Option<Principal>) -> Result<(), String> {
     CAPACITY_INFO.with(|s| {
         let capacity_info = s.borrow();
@@ -434,14 +522,14 @@ fn reward_inviter(inviter: Principal) -> Result<(), String> {
     CAPACITY_INFO.with(|s| {
         let mut capacity_info = s.borrow_mut();
         let info = capacity_info.get_mut(&inviter);
-        let mut info = match info {
+        let info = match info {
             Some(i) => i,
             None => return Err("inviter not found".to_string()),
         };
 
         info.invitee_count += 1;
 
-        if INVITE_REWARD.len() == 0 {
+        if INVITE_REWARD.is_empty() {
             return Ok(());
         }
 
@@ -486,7 +574,8 @@ fn save_charge_block(block_index: Option<BlockIndex>) -> Result<(), String> {
 fn _is_auth() -> Result<(), String> {
     STATS.with(|s| {
         let stats = s.borrow();
-        if ic_cdk::api::caller() == stats.owner {
+        let caller = ic_cdk::api::caller();
+        if caller == stats.owner || Some(caller) == stats.governance {
             Ok(())
         } else {
             Err("invalid caller".to_string())
@@ -516,3 +605,19 @@ fn main() {
 
 #[cfg(any(target_arch = "wasm32", test))]
 fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deposits_add_to_the_charged_balance() {
+        let account = Principal::from_slice(&[1, 1]);
+        assert_eq!(_credit_deposit(account, 100), Nat::from(100));
+        assert_eq!(_credit_deposit(account, 50), Nat::from(150));
+
+        let info = CAPACITY_INFO.with(|s| s.borrow()[&account].clone());
+        assert_eq!(info.charged, Nat::from(150));
+        assert_eq!(info.long_term_capacity, 0);
+    }
+}
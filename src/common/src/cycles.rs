@@ -0,0 +1,282 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::thread::LocalKey;
+
+// cycles balance is sampled at most this often, ns
+const CYCLES_SAMPLE_INTERVAL: u64 = 3_600_000_000_000;
+// wait between two top-up attempts, ns
+const TOP_UP_RETRY_INTERVAL: u64 = 3_600_000_000_000;
+// "TPUP", memo of ledger transfers the cycles minting canister turns into a top-up
+const TOP_UP_MEMO: u64 = 0x5055_5054;
+// only the latest top-ups are kept
+pub const MAX_TOP_UPS: usize = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TopUpSource {
+    // asked through `requestCycles(cycles)`, the canister deposits them to this one
+    Canister { canister: Principal, cycles: u64 },
+    // ICP sent from this canister's ledger account to the cycles minting canister
+    CyclesMinting { e8s: u64 },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TopUpConfig {
+    // a top-up is requested once the balance is below it
+    pub threshold: u64,
+    pub source: TopUpSource,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TopUpRecord {
+    pub timestamp: u64,
+    pub source: TopUpSource,
+    // cycles received
+    pub cycles: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CyclesSample {
+    pub timestamp: u64,
+    pub balance: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CyclesInfo {
+    pub balance: u64,
+    // cycles per day between the last two samples
+    pub burn_rate: u64,
+    pub last_sample: Option<CyclesSample>,
+    pub config: Option<TopUpConfig>,
+    // oldest first, at most MAX_TOP_UPS
+    pub top_ups: Vec<TopUpRecord>,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct CyclesMonitor {
+    config: Option<TopUpConfig>,
+    last_sample: Option<CyclesSample>,
+    burn_rate: u64,
+    // received since the last sample, so top-ups do not hide the burn
+    received: u64,
+    topping_up: bool,
+    last_attempt: u64,
+    top_ups: VecDeque<TopUpRecord>,
+}
+
+impl CyclesMonitor {
+    pub fn info(&self, balance: u64) -> CyclesInfo {
+        CyclesInfo {
+            balance,
+            burn_rate: self.burn_rate,
+            last_sample: self.last_sample.clone(),
+            config: self.config.clone(),
+            top_ups: self.top_ups.iter().cloned().collect(),
+        }
+    }
+
+    // None turns automatic top-ups off
+    pub fn set_config(&mut self, config: Option<TopUpConfig>) {
+        self.config = config;
+    }
+
+    fn sample(&mut self, now: u64, balance: u64) {
+        if let Some(last) = &self.last_sample {
+            if now < last.timestamp + CYCLES_SAMPLE_INTERVAL {
+                return;
+            }
+            let spent = last
+                .balance
+                .saturating_add(self.received)
+                .saturating_sub(balance);
+            let elapsed = (now - last.timestamp) as u128;
+            self.burn_rate = (spent as u128 * 86_400_000_000_000 / elapsed) as u64;
+        }
+        self.last_sample = Some(CyclesSample {
+            timestamp: now,
+            balance,
+        });
+        self.received = 0;
+    }
+
+    // samples the balance and returns where to ask for cycles when a top-up is due
+    fn start_top_up(&mut self, now: u64, balance: u64) -> Option<TopUpSource> {
+        self.sample(now, balance);
+        if self.topping_up || now < self.last_attempt + TOP_UP_RETRY_INTERVAL {
+            return None;
+        }
+        let source = match &self.config {
+            Some(config) if balance < config.threshold => config.source.clone(),
+            _ => return None,
+        };
+        self.topping_up = true;
+        self.last_attempt = now;
+        Some(source)
+    }
+
+    fn finish_top_up(&mut self, record: TopUpRecord) {
+        self.topping_up = false;
+        self.received = self.received.saturating_add(record.cycles);
+        if self.top_ups.len() >= MAX_TOP_UPS {
+            self.top_ups.pop_front();
+        }
+        self.top_ups.push_back(record);
+    }
+}
+
+// Samples the cycles balance and tops it up when it is below the threshold.
+// Does nothing while no top-up is configured.
+pub async fn heartbeat(monitor: &'static LocalKey<RefCell<CyclesMonitor>>) {
+    if monitor.with(|m| m.borrow().config.is_none()) {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let source = match monitor.with(|m| {
+        m.borrow_mut()
+            .start_top_up(now, ic_cdk::api::canister_balance())
+    }) {
+        Some(source) => source,
+        None => return,
+    };
+    let before = ic_cdk::api::canister_balance();
+    let res = match &source {
+        TopUpSource::Canister { canister, cycles } => {
+            top_up_from_canister(*canister, *cycles).await
+        }
+        TopUpSource::CyclesMinting { e8s } => top_up_from_cmc(*e8s).await,
+    };
+    let received = ic_cdk::api::canister_balance().saturating_sub(before);
+    monitor.with(|m| {
+        m.borrow_mut().finish_top_up(TopUpRecord {
+            timestamp: now,
+            source,
+            cycles: received,
+            error: res.err(),
+        })
+    });
+}
+
+#[derive(CandidType, Deserialize)]
+struct NotifyTopUpArg {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+async fn top_up_from_canister(canister: Principal, cycles: u64) -> Result<(), String> {
+    let res: CallResult<()> = ic_cdk::call(canister, "requestCycles", (cycles,)).await;
+    res.map_err(|(code, msg)| format!("{:?}: {}", code, msg))
+}
+
+async fn top_up_from_cmc(e8s: u64) -> Result<(), String> {
+    let cmc = ic_ledger_types::MAINNET_CYCLES_MINTING_CANISTER_ID;
+    let id = ic_cdk::api::id();
+    // the cycles minting canister reads the canister to top up from the subaccount
+    let mut subaccount = [0; 32];
+    subaccount[0] = id.as_slice().len() as u8;
+    subaccount[1..1 + id.as_slice().len()].copy_from_slice(id.as_slice());
+    let args = ic_ledger_types::TransferArgs {
+        memo: ic_ledger_types::Memo(TOP_UP_MEMO),
+        amount: ic_ledger_types::Tokens::from_e8s(e8s),
+        fee: ic_ledger_types::DEFAULT_FEE,
+        from_subaccount: None,
+        to: ic_ledger_types::AccountIdentifier::new(&cmc, &ic_ledger_types::Subaccount(subaccount)),
+        created_at_time: None,
+    };
+    let block_index = ic_ledger_types::transfer(ic_ledger_types::MAINNET_LEDGER_CANISTER_ID, args)
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?
+        .map_err(|e| format!("{:?}", e))?;
+    let arg = NotifyTopUpArg {
+        block_index,
+        canister_id: id,
+    };
+    let res: CallResult<(Result<Nat, NotifyError>,)> =
+        ic_cdk::call(cmc, "notify_top_up", (arg,)).await;
+    match res {
+        Ok((Ok(_),)) => Ok(()),
+        Ok((Err(e),)) => Err(format!("{:?}", e)),
+        Err((code, msg)) => Err(format!("{:?}: {}", code, msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3_600_000_000_000;
+
+    fn config(threshold: u64) -> Option<TopUpConfig> {
+        Some(TopUpConfig {
+            threshold,
+            source: TopUpSource::CyclesMinting { e8s: 100 },
+        })
+    }
+
+    fn record(timestamp: u64, cycles: u64) -> TopUpRecord {
+        TopUpRecord {
+            timestamp,
+            source: TopUpSource::CyclesMinting { e8s: 100 },
+            cycles,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn burn_rate_leaves_out_top_ups() {
+        let mut monitor = CyclesMonitor::default();
+        monitor.sample(0, 1_000_000);
+        // too early for a new sample
+        monitor.sample(HOUR - 1, 0);
+        assert_eq!(monitor.info(0).burn_rate, 0);
+
+        monitor.finish_top_up(record(HOUR / 2, 500_000));
+        monitor.sample(HOUR, 1_400_000);
+        assert_eq!(monitor.info(0).burn_rate, 100_000 * 24);
+        assert_eq!(monitor.info(0).last_sample.unwrap().balance, 1_400_000);
+    }
+
+    #[test]
+    fn top_ups_start_below_the_threshold_and_wait_between_attempts() {
+        let mut monitor = CyclesMonitor::default();
+        assert!(monitor.start_top_up(HOUR, 10).is_none());
+
+        monitor.set_config(config(100));
+        assert!(monitor.start_top_up(HOUR, 100).is_none());
+        assert!(monitor.start_top_up(HOUR, 99).is_some());
+        // one at a time
+        assert!(monitor.start_top_up(3 * HOUR, 99).is_none());
+
+        monitor.finish_top_up(record(HOUR, 0));
+        assert!(monitor.start_top_up(2 * HOUR - 1, 99).is_none());
+        assert!(monitor.start_top_up(2 * HOUR, 99).is_some());
+    }
+
+    #[test]
+    fn only_the_latest_top_ups_are_kept() {
+        let mut monitor = CyclesMonitor::default();
+        for i in 0..MAX_TOP_UPS as u64 + 5 {
+            monitor.finish_top_up(record(i, 1));
+        }
+        let top_ups = monitor.info(0).top_ups;
+        assert_eq!(top_ups.len(), MAX_TOP_UPS);
+        assert_eq!(top_ups[0].timestamp, 5);
+        assert_eq!(top_ups[MAX_TOP_UPS - 1].timestamp, MAX_TOP_UPS as u64 + 4);
+    }
+}
@@ -0,0 +1,293 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::CallResult;
+use ic_ledger_types::{
+    account_balance, transfer, AccountBalanceArgs, AccountIdentifier, Memo, Subaccount, Timestamp,
+    Tokens, TransferArgs, TransferError, TransferResult, DEFAULT_FEE, DEFAULT_SUBACCOUNT,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::thread::LocalKey;
+
+// A sweep whose transfer may or may not have reached the ledger. It is kept
+// until account_balance tells which, so a deposit is credited exactly once.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct PendingSweep {
+    // e8s moved to the fee account
+    pub amount: u64,
+    // created_at_time of the transfer, a retry is deduplicated by the ledger
+    pub created_at: u64,
+    // a transfer is awaited
+    pub in_flight: bool,
+    // the ledger no longer deduplicates the transfer, so it is not sent again.
+    // It is settled once the balance shows it went through, or by resolve_sweep.
+    pub reconcile: bool,
+}
+
+pub type PendingSweeps = BTreeMap<Principal, PendingSweep>;
+
+#[derive(Debug, PartialEq)]
+enum Sweep {
+    // the pending transfer went through, credit it
+    Done(u64),
+    // send this transfer
+    Transfer(PendingSweep),
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    // the transfer went through
+    Credit,
+    // it did not
+    Drop,
+    // unknown, sent again by the next sweep
+    Retry,
+    // unknown and no longer deduplicated
+    Reconcile,
+}
+
+// [len][principal] fits the 32 bytes and is unique per account
+pub fn deposit_subaccount(account: &Principal) -> Subaccount {
+    let bytes = account.as_slice();
+    let mut subaccount = [0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    Subaccount(subaccount)
+}
+
+// The ICP paid here by `account` is credited to it once swept
+pub fn deposit_account(canister: &Principal, account: &Principal) -> AccountIdentifier {
+    AccountIdentifier::new(canister, &deposit_subaccount(account))
+}
+
+// Only sweeps move funds out of a deposit subaccount, so a balance short of a
+// pending sweep means its transfer went through.
+fn start_sweep(pending: Option<&PendingSweep>, balance: u64, now: u64) -> Result<Sweep, String> {
+    let fee = DEFAULT_FEE.e8s();
+    match pending {
+        Some(p) if p.in_flight => Err("deposit in progress".to_string()),
+        Some(p) if balance < p.amount + fee => Ok(Sweep::Done(p.amount)),
+        Some(p) if p.reconcile => Err("deposit awaits reconciliation".to_string()),
+        Some(p) => Ok(Sweep::Transfer(PendingSweep {
+            in_flight: true,
+            ..p.clone()
+        })),
+        None if balance <= fee => Err("no deposit".to_string()),
+        None => Ok(Sweep::Transfer(PendingSweep {
+            amount: balance - fee,
+            created_at: now,
+            in_flight: true,
+            reconcile: false,
+        })),
+    }
+}
+
+fn sweep_outcome(result: &CallResult<TransferResult>) -> Outcome {
+    match result {
+        Ok(Ok(_)) | Ok(Err(TransferError::TxDuplicate { .. })) => Outcome::Credit,
+        // the ledger checked the transfer and refused it
+        Ok(Err(TransferError::InsufficientFunds { .. }))
+        | Ok(Err(TransferError::BadFee { .. })) => Outcome::Drop,
+        // an earlier attempt may have gone through
+        Ok(Err(TransferError::TxTooOld { .. })) | Ok(Err(TransferError::TxCreatedInFuture)) => {
+            Outcome::Reconcile
+        }
+        Err(_) => Outcome::Retry,
+    }
+}
+
+// Moves the deposit of `account` to `to` and returns the e8s to credit. The
+// sweep is recorded in `sweeps` before the transfer is sent; when the call
+// fails it stays there and the next sweep settles it.
+pub async fn sweep_deposit(
+    sweeps: &'static LocalKey<RefCell<PendingSweeps>>,
+    ledger: Principal,
+    account: Principal,
+    to: Principal,
+) -> Result<u64, String> {
+    let subaccount = deposit_subaccount(&account);
+    let args = AccountBalanceArgs {
+        account: AccountIdentifier::new(&ic_cdk::api::id(), &subaccount),
+    };
+    let balance = account_balance(ledger, args)
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+
+    let now = ic_cdk::api::time();
+    let sweep = sweeps.with(|s| {
+        let mut sweeps = s.borrow_mut();
+        let sweep = start_sweep(sweeps.get(&account), balance.e8s(), now)?;
+        match &sweep {
+            Sweep::Done(_) => sweeps.remove(&account),
+            Sweep::Transfer(p) => sweeps.insert(account, p.clone()),
+        };
+        Ok::<_, String>(sweep)
+    })?;
+    let pending = match sweep {
+        Sweep::Done(amount) => return Ok(amount),
+        Sweep::Transfer(pending) => pending,
+    };
+
+    let args = TransferArgs {
+        memo: Memo(0),
+        amount: Tokens::from_e8s(pending.amount),
+        fee: DEFAULT_FEE,
+        from_subaccount: Some(subaccount),
+        to: AccountIdentifier::new(&to, &DEFAULT_SUBACCOUNT),
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: pending.created_at,
+        }),
+    };
+    let result = transfer(ledger, args).await;
+    let outcome = sweep_outcome(&result);
+    sweeps.with(|s| {
+        let mut sweeps = s.borrow_mut();
+        match outcome {
+            Outcome::Credit | Outcome::Drop => {
+                sweeps.remove(&account);
+            }
+            Outcome::Retry | Outcome::Reconcile => {
+                if let Some(p) = sweeps.get_mut(&account) {
+                    p.in_flight = false;
+                    p.reconcile |= outcome == Outcome::Reconcile;
+                }
+            }
+        }
+    });
+    match result {
+        Ok(Err(e)) if outcome != Outcome::Credit => Err(format!("{:?}", e)),
+        Err((code, msg)) => Err(format!("{:?}: {}", code, msg)),
+        _ => Ok(pending.amount),
+    }
+}
+
+// Settles a sweep awaiting reconciliation once the ledger history was
+// checked, returns the e8s to credit
+pub fn resolve_sweep(
+    sweeps: &'static LocalKey<RefCell<PendingSweeps>>,
+    account: Principal,
+    went_through: bool,
+) -> Result<u64, String> {
+    sweeps.with(|s| {
+        let mut sweeps = s.borrow_mut();
+        match sweeps.get(&account) {
+            Some(p) if p.reconcile => {}
+            _ => return Err("sweep not found".to_string()),
+        }
+        let p = sweeps.remove(&account).unwrap();
+        Ok(if went_through { p.amount } else { 0 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(amount: u64, in_flight: bool) -> PendingSweep {
+        PendingSweep {
+            amount,
+            created_at: 7,
+            in_flight,
+            reconcile: false,
+        }
+    }
+
+    #[test]
+    fn test_start_sweep() {
+        let fee = DEFAULT_FEE.e8s();
+        assert_eq!(start_sweep(None, fee, 9), Err("no deposit".to_string()));
+        assert_eq!(
+            start_sweep(None, fee + 100, 9),
+            Ok(Sweep::Transfer(PendingSweep {
+                amount: 100,
+                created_at: 9,
+                in_flight: true,
+                reconcile: false,
+            }))
+        );
+        assert_eq!(
+            start_sweep(Some(&pending(100, true)), fee + 100, 9),
+            Err("deposit in progress".to_string())
+        );
+    }
+
+    #[test]
+    fn test_start_sweep_settles_pending() {
+        let fee = DEFAULT_FEE.e8s();
+        // the earlier transfer went through, deposits after it wait for the next sweep
+        assert_eq!(
+            start_sweep(Some(&pending(100, false)), 50, 9),
+            Ok(Sweep::Done(100))
+        );
+        // it did not, send it again with its original created_at
+        assert_eq!(
+            start_sweep(Some(&pending(100, false)), fee + 150, 9),
+            Ok(Sweep::Transfer(pending(100, true)))
+        );
+    }
+
+    #[test]
+    fn test_sweep_outcome() {
+        use ic_cdk::api::call::RejectionCode;
+        assert_eq!(sweep_outcome(&Ok(Ok(3))), Outcome::Credit);
+        assert_eq!(
+            sweep_outcome(&Ok(Err(TransferError::TxDuplicate { duplicate_of: 3 }))),
+            Outcome::Credit
+        );
+        assert_eq!(
+            sweep_outcome(&Ok(Err(TransferError::InsufficientFunds {
+                balance: Tokens::from_e8s(0),
+            }))),
+            Outcome::Drop
+        );
+        assert_eq!(
+            sweep_outcome(&Err((RejectionCode::SysTransient, String::new()))),
+            Outcome::Retry
+        );
+        assert_eq!(
+            sweep_outcome(&Ok(Err(TransferError::TxTooOld {
+                allowed_window_nanos: 0,
+            }))),
+            Outcome::Reconcile
+        );
+    }
+
+    #[test]
+    fn test_reconciled_sweeps_are_not_sent_again() {
+        let fee = DEFAULT_FEE.e8s();
+        let reconcile = PendingSweep {
+            reconcile: true,
+            ..pending(100, false)
+        };
+        // the first transfer went through and nothing was deposited since
+        assert_eq!(start_sweep(Some(&reconcile), 0, 9), Ok(Sweep::Done(100)));
+        // either it did not, or more was deposited since
+        assert_eq!(
+            start_sweep(Some(&reconcile), fee + 150, 9),
+            Err("deposit awaits reconciliation".to_string())
+        );
+    }
+
+    thread_local! {
+        static SWEEPS: RefCell<PendingSweeps> = RefCell::new(PendingSweeps::default());
+    }
+
+    #[test]
+    fn test_resolve_sweep() {
+        let account = Principal::from_slice(&[1, 1]);
+        SWEEPS.with(|s| s.borrow_mut().insert(account, pending(100, false)));
+        assert!(resolve_sweep(&SWEEPS, account, true).is_err());
+
+        SWEEPS.with(|s| s.borrow_mut().get_mut(&account).unwrap().reconcile = true);
+        assert_eq!(resolve_sweep(&SWEEPS, account, true), Ok(100));
+        assert!(SWEEPS.with(|s| s.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_deposit_subaccount() {
+        let a = Principal::from_slice(&[1, 1]);
+        let b = Principal::from_slice(&[1, 1, 0]);
+        let subaccount = deposit_subaccount(&a);
+        assert_eq!(subaccount.0[..3], [2, 1, 1]);
+        assert_ne!(subaccount, deposit_subaccount(&b));
+    }
+}
@@ -0,0 +1,2 @@
+pub mod cycles;
+pub mod deposits;
@@ -0,0 +1,92 @@
+export const idlFactory = ({ IDL }) => {
+  const Result = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
+  const Ballot = IDL.Record({ 'weight' : IDL.Nat, 'in_favor' : IDL.Bool });
+  const Result_1 = IDL.Variant({ 'Ok' : Ballot, 'Err' : IDL.Text });
+  const ProposalStatus = IDL.Variant({
+    'Failed' : IDL.Null,
+    'Open' : IDL.Null,
+    'Rejected' : IDL.Null,
+    'Executed' : IDL.Null,
+    'Accepted' : IDL.Null,
+  });
+  const Proposal = IDL.Record({
+    'id' : IDL.Nat64,
+    'status' : ProposalStatus,
+    'result' : IDL.Text,
+    'method' : IDL.Text,
+    'votes_no' : IDL.Nat,
+    'args' : IDL.Vec(IDL.Nat8),
+    'description' : IDL.Text,
+    'deadline' : IDL.Nat64,
+    'created_at' : IDL.Nat64,
+    'target' : IDL.Principal,
+    'proposer' : IDL.Principal,
+    'votes_yes' : IDL.Nat,
+    'total_supply' : IDL.Nat,
+    'snapshot_id' : IDL.Nat64,
+  });
+  const Result_2 = IDL.Variant({ 'Ok' : Proposal, 'Err' : IDL.Text });
+  const StatsData = IDL.Record({
+    'token' : IDL.Principal,
+    'owner' : IDL.Principal,
+    'voting_period' : IDL.Nat64,
+    'quorum_bps' : IDL.Nat64,
+    'proposal_threshold' : IDL.Nat,
+    'next_id' : IDL.Nat64,
+  });
+  const Result_3 = IDL.Variant({ 'Ok' : StatsData, 'Err' : IDL.Text });
+  const Result_4 = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text });
+  const Result_5 = IDL.Variant({ 'Ok' : IDL.Nat, 'Err' : IDL.Text });
+  const TopUpSource = IDL.Variant({
+    'CyclesMinting' : IDL.Record({ 'e8s' : IDL.Nat64 }),
+    'Canister' : IDL.Record({ 'cycles' : IDL.Nat64, 'canister' : IDL.Principal }),
+  });
+  const TopUpConfig = IDL.Record({
+    'source' : TopUpSource,
+    'threshold' : IDL.Nat64,
+  });
+  const CyclesSample = IDL.Record({
+    'balance' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const TopUpRecord = IDL.Record({
+    'source' : TopUpSource,
+    'error' : IDL.Opt(IDL.Text),
+    'cycles' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const CyclesInfo = IDL.Record({
+    'balance' : IDL.Nat64,
+    'burn_rate' : IDL.Nat64,
+    'last_sample' : IDL.Opt(CyclesSample),
+    'config' : IDL.Opt(TopUpConfig),
+    'top_ups' : IDL.Vec(TopUpRecord),
+  });
+  return IDL.Service({
+    'execute' : IDL.Func([IDL.Nat64], [Result], []),
+    'getBallot' : IDL.Func([IDL.Nat64, IDL.Principal], [Result_1], ['query']),
+    'getCyclesInfo' : IDL.Func([], [CyclesInfo], ['query']),
+    'getProposal' : IDL.Func([IDL.Nat64], [Result_2], ['query']),
+    'getProposals' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(Proposal)],
+        ['query'],
+      ),
+    'getStats' : IDL.Func([], [Result_3], ['query']),
+    'propose' : IDL.Func(
+        [IDL.Text, IDL.Principal, IDL.Text, IDL.Vec(IDL.Nat8)],
+        [Result_4],
+        [],
+      ),
+    'setOwner' : IDL.Func([IDL.Principal], [Result], []),
+    'setProposalThreshold' : IDL.Func([IDL.Nat], [Result], []),
+    'setQuorum' : IDL.Func([IDL.Nat64], [Result], []),
+    'setToken' : IDL.Func([IDL.Principal], [Result], []),
+    'setTopUpConfig' : IDL.Func([IDL.Opt(TopUpConfig)], [Result], []),
+    'setVotingPeriod' : IDL.Func([IDL.Nat64], [Result], []),
+    'vote' : IDL.Func([IDL.Nat64, IDL.Bool], [Result_5], []),
+  });
+};
+export const init = ({ IDL }) => {
+  return [IDL.Principal, IDL.Nat64, IDL.Nat64, IDL.Nat];
+};
@@ -0,0 +1,608 @@
+use candid::parser::value::IDLValue;
+use candid::{candid_method, IDLArgs, Nat};
+use common::cycles::{self, CyclesInfo, CyclesMonitor, TopUpConfig};
+use ic_cdk::{
+    api::{call::CallResult, time},
+    export::{
+        candid::{CandidType, Deserialize},
+        Principal,
+    },
+};
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+#[derive(CandidType, Debug, PartialEq, Deserialize)]
+pub enum TxError {
+    InsufficientBalance,
+    InsufficientAllowance,
+    Unauthorized,
+    LedgerTrap,
+    AmountTooSmall,
+    BlockUsed,
+    ErrorOperationStyle,
+    ErrorTo,
+    Other(String),
+    Frozen,
+}
+pub type TxReceipt = Result<Nat, TxError>;
+
+type ProposalStore = BTreeMap<u64, Proposal>;
+type BallotStore = BTreeMap<(u64, Principal), Ballot>;
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+enum ProposalStatus {
+    Open,
+    Accepted,
+    Rejected,
+    Executed,
+    Failed,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Proposal {
+    pub id: u64,
+    pub proposer: Principal,
+    pub description: String,
+
+    // the call made when the proposal is executed
+    pub target: Principal,
+    pub method: String,
+    pub args: Vec<u8>,
+
+    // voting weight is the token balance at this snapshot
+    pub snapshot_id: u64,
+    pub total_supply: Nat,
+
+    pub created_at: u64,
+    pub deadline: u64,
+    pub votes_yes: Nat,
+    pub votes_no: Nat,
+    pub status: ProposalStatus,
+    // the reply of the executed call in text form, or why it failed
+    pub result: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Ballot {
+    pub in_favor: bool,
+    pub weight: Nat,
+}
+
+#[derive(Clone, Debug, Deserialize, CandidType)]
+struct StatsData {
+    owner: Principal,
+    token: Principal,
+
+    // seconds
+    voting_period: u64,
+    // share of the snapshot supply that must vote, in basis points
+    quorum_bps: u64,
+    // minimum snapshot balance needed to create a proposal
+    proposal_threshold: Nat,
+
+    next_id: u64,
+}
+
+impl Default for StatsData {
+    fn default() -> Self {
+        StatsData {
+            owner: Principal::anonymous(),
+            token: Principal::anonymous(),
+            voting_period: 0,
+            quorum_bps: 0,
+            proposal_threshold: Nat::from(0),
+            next_id: 0,
+        }
+    }
+}
+
+thread_local! {
+    static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
+    static PROPOSALS: RefCell<ProposalStore> = RefCell::new(ProposalStore::default());
+    static BALLOTS: RefCell<BallotStore> = RefCell::new(BallotStore::default());
+    static CYCLES: RefCell<CyclesMonitor> = RefCell::new(CyclesMonitor::default());
+}
+
+#[init]
+#[candid_method(init)]
+fn init(token: Principal, voting_period: u64, quorum_bps: u64, proposal_threshold: Nat) {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.owner = ic_cdk::api::caller();
+        stats.token = token;
+        stats.voting_period = voting_period;
+        stats.quorum_bps = quorum_bps;
+        stats.proposal_threshold = proposal_threshold;
+    });
+}
+
+#[update(name = "setToken", guard = "_is_auth")]
+#[candid_method(update, rename = "setToken")]
+fn set_token(token: Principal) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.token = token;
+        Ok(())
+    })
+}
+
+#[update(name = "setVotingPeriod", guard = "_is_auth")]
+#[candid_method(update, rename = "setVotingPeriod")]
+fn set_voting_period(voting_period: u64) -> Result<(), String> {
+    if _deadline(0, voting_period).is_none() {
+        return Err("invalid voting period".to_string());
+    }
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.voting_period = voting_period;
+        Ok(())
+    })
+}
+
+#[update(name = "setQuorum", guard = "_is_auth")]
+#[candid_method(update, rename = "setQuorum")]
+fn set_quorum(quorum_bps: u64) -> Result<(), String> {
+    if quorum_bps > 10000 {
+        return Err("invalid quorum".to_string());
+    }
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.quorum_bps = quorum_bps;
+        Ok(())
+    })
+}
+
+#[update(name = "setProposalThreshold", guard = "_is_auth")]
+#[candid_method(update, rename = "setProposalThreshold")]
+fn set_proposal_threshold(proposal_threshold: Nat) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.proposal_threshold = proposal_threshold;
+        Ok(())
+    })
+}
+
+#[update(name = "setOwner", guard = "_is_auth")]
+#[candid_method(update, rename = "setOwner")]
+fn set_owner(owner: Principal) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.owner = owner;
+        Ok(())
+    })
+}
+
+#[query(name = "getStats")]
+#[candid_method(query, rename = "getStats")]
+fn get_stats() -> Result<StatsData, String> {
+    STATS.with(|s| {
+        let stats = s.borrow();
+        Ok(stats.clone())
+    })
+}
+
+#[query(name = "getProposal")]
+#[candid_method(query, rename = "getProposal")]
+fn get_proposal(id: u64) -> Result<Proposal, String> {
+    PROPOSALS.with(|p| {
+        let proposals = p.borrow();
+        match proposals.get(&id) {
+            Some(p) => Ok(p.clone()),
+            None => Err("proposal not found".to_string()),
+        }
+    })
+}
+
+// newest first
+#[query(name = "getProposals")]
+#[candid_method(query, rename = "getProposals")]
+fn get_proposals(start: usize, limit: usize) -> Vec<Proposal> {
+    PROPOSALS.with(|p| {
+        let proposals = p.borrow();
+        proposals
+            .values()
+            .rev()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query(name = "getBallot")]
+#[candid_method(query, rename = "getBallot")]
+fn get_ballot(id: u64, voter: Principal) -> Result<Ballot, String> {
+    BALLOTS.with(|b| {
+        let ballots = b.borrow();
+        match ballots.get(&(id, voter)) {
+            Some(b) => Ok(b.clone()),
+            None => Err("ballot not found".to_string()),
+        }
+    })
+}
+
+#[update]
+#[candid_method(update)]
+async fn propose(
+    description: String,
+    target: Principal,
+    method: String,
+    args: Vec<u8>,
+) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let (token, voting_period, proposal_threshold) = STATS.with(|s| {
+        let stats = s.borrow();
+        (
+            stats.token,
+            stats.voting_period,
+            stats.proposal_threshold.clone(),
+        )
+    });
+
+    let now = time();
+    let deadline = match _deadline(now, voting_period) {
+        Some(deadline) => deadline,
+        None => return Err("invalid voting period".to_string()),
+    };
+
+    // checked on the live balance first, so that only holders above the
+    // threshold can make the token take a snapshot
+    let call_result: CallResult<(Nat,)> = ic_cdk::call(token, "balanceOf", (caller,)).await;
+    let balance = match call_result {
+        Ok((balance,)) => balance,
+        Err(e) => return Err(format!("{:?} {}", e.0, e.1)),
+    };
+    if balance < proposal_threshold || balance == 0 {
+        return Err("insufficient balance".to_string());
+    }
+
+    let call_result: CallResult<(u64,)> = ic_cdk::call(token, "takeSnapshot", ()).await;
+    let snapshot_id = match call_result {
+        Ok((snapshot_id,)) => snapshot_id,
+        Err(e) => return Err(format!("{:?} {}", e.0, e.1)),
+    };
+
+    let weight = balance_at(token, caller, snapshot_id).await?;
+    if weight < proposal_threshold || weight == 0 {
+        return Err("insufficient balance".to_string());
+    }
+
+    let call_result: CallResult<(TxReceipt,)> =
+        ic_cdk::call(token, "totalSupplyAt", (snapshot_id,)).await;
+    let total_supply = match call_result {
+        Ok((Ok(total_supply),)) => total_supply,
+        Ok((Err(e),)) => return Err(format!("{:?}", e)),
+        Err(e) => return Err(format!("{:?} {}", e.0, e.1)),
+    };
+
+    let id = STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.next_id += 1;
+        stats.next_id
+    });
+    PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        proposals.insert(
+            id,
+            Proposal {
+                id,
+                proposer: caller,
+                description,
+                target,
+                method,
+                args,
+                snapshot_id,
+                total_supply,
+                created_at: now,
+                deadline,
+                votes_yes: Nat::from(0),
+                votes_no: Nat::from(0),
+                status: ProposalStatus::Open,
+                result: "".to_string(),
+            },
+        );
+    });
+
+    Ok(id)
+}
+
+#[update]
+#[candid_method(update)]
+async fn vote(id: u64, in_favor: bool) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let (token, snapshot_id) = _open_proposal(id)?;
+
+    let weight = balance_at(token, caller, snapshot_id).await?;
+    if weight == 0 {
+        return Err("no voting power".to_string());
+    }
+
+    // the proposal may have closed while the balance was being fetched
+    _open_proposal(id)?;
+
+    let voted = BALLOTS.with(|b| {
+        let mut ballots = b.borrow_mut();
+        if ballots.contains_key(&(id, caller)) {
+            return true;
+        }
+        ballots.insert(
+            (id, caller),
+            Ballot {
+                in_favor,
+                weight: weight.clone(),
+            },
+        );
+        false
+    });
+    if voted {
+        return Err("already voted".to_string());
+    }
+
+    PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        if let Some(p) = proposals.get_mut(&id) {
+            if in_favor {
+                p.votes_yes += weight.clone();
+            } else {
+                p.votes_no += weight.clone();
+            }
+        }
+    });
+
+    Ok(weight)
+}
+
+// anyone can execute a proposal once its voting period is over
+#[update]
+#[candid_method(update)]
+async fn execute(id: u64) -> Result<(), String> {
+    let quorum_bps = STATS.with(|s| s.borrow().quorum_bps);
+
+    let call = PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        let p = match proposals.get_mut(&id) {
+            Some(p) => p,
+            None => return Err("proposal not found".to_string()),
+        };
+        if p.status != ProposalStatus::Open {
+            return Err("proposal closed".to_string());
+        }
+        if time() < p.deadline {
+            return Err("voting in progress".to_string());
+        }
+
+        if !_is_accepted(p, quorum_bps) {
+            p.status = ProposalStatus::Rejected;
+            return Ok(None);
+        }
+
+        // marked before the call so that it can not be executed twice
+        p.status = ProposalStatus::Accepted;
+        Ok(Some((p.target, p.method.clone(), p.args.clone())))
+    })?;
+
+    let (target, method, args) = match call {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    let call_result = ic_cdk::api::call::call_raw(target, &method, &args, 0).await;
+    PROPOSALS.with(|p| {
+        let mut proposals = p.borrow_mut();
+        if let Some(p) = proposals.get_mut(&id) {
+            let (status, result) = match &call_result {
+                Ok(reply) => _reply_outcome(reply),
+                Err(e) => (ProposalStatus::Failed, format!("{:?} {}", e.0, e.1)),
+            };
+            p.status = status;
+            p.result = result;
+        }
+    });
+
+    match call_result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{:?} {}", e.0, e.1)),
+    }
+}
+
+#[query(name = "getCyclesInfo")]
+#[candid_method(query, rename = "getCyclesInfo")]
+fn get_cycles_info() -> CyclesInfo {
+    CYCLES.with(|c| c.borrow().info(ic_cdk::api::canister_balance()))
+}
+
+// None turns automatic top-ups off
+#[update(name = "setTopUpConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setTopUpConfig")]
+fn set_top_up_config(config: Option<TopUpConfig>) -> Result<(), String> {
+    CYCLES.with(|c| c.borrow_mut().set_config(config));
+    Ok(())
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    cycles::heartbeat(&CYCLES).await;
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let stats = STATS.with(|s| s.borrow().clone());
+    let proposals = PROPOSALS.with(|a| a.borrow().clone());
+    let ballots = BALLOTS.with(|a| a.borrow().clone());
+    let cycles = CYCLES.with(|c| c.borrow().clone());
+    ic_cdk::storage::stable_save((stats, proposals, ballots, cycles)).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (stats_stored, proposals_stored, ballots_stored, cycles_stored) =
+        ic_cdk::storage::stable_restore().unwrap();
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        *stats = stats_stored;
+    });
+    PROPOSALS.with(|a| {
+        let mut store = a.borrow_mut();
+        *store = proposals_stored;
+    });
+    BALLOTS.with(|a| {
+        let mut store = a.borrow_mut();
+        *store = ballots_stored;
+    });
+    CYCLES.with(|c| {
+        let mut cycles = c.borrow_mut();
+        *cycles = Option::<CyclesMonitor>::unwrap_or_default(cycles_stored);
+    });
+}
+
+async fn balance_at(token: Principal, account: Principal, snapshot_id: u64) -> Result<Nat, String> {
+    let call_result: CallResult<(TxReceipt,)> =
+        ic_cdk::call(token, "balanceOfAt", (account, snapshot_id)).await;
+    match call_result {
+        Ok((Ok(balance),)) => Ok(balance),
+        Ok((Err(e),)) => Err(format!("{:?}", e)),
+        Err(e) => Err(format!("{:?} {}", e.0, e.1)),
+    }
+}
+
+// None if the end of the voting period does not fit into a u64
+fn _deadline(now: u64, voting_period: u64) -> Option<u64> {
+    voting_period
+        .checked_mul(1_000_000_000)
+        .and_then(|period| now.checked_add(period))
+}
+
+// the quorum is a share of the supply at the proposal's snapshot
+fn _is_accepted(p: &Proposal, quorum_bps: u64) -> bool {
+    let votes = p.votes_yes.clone() + p.votes_no.clone();
+    let quorum = p.total_supply.clone() * Nat::from(quorum_bps) / Nat::from(10000);
+    votes >= quorum && p.votes_yes > p.votes_no
+}
+
+// A reply whose first value is a variant tagged `Err`, the way a method
+// reports a failure in its result, fails the proposal. Any other reply
+// executes it.
+fn _reply_outcome(reply: &[u8]) -> (ProposalStatus, String) {
+    let args = match IDLArgs::from_bytes(reply) {
+        Ok(args) => args,
+        Err(e) => return (ProposalStatus::Executed, format!("undecoded reply: {}", e)),
+    };
+    let failed = matches!(
+        args.args.first(),
+        Some(IDLValue::Variant(v)) if v.0.id.get_id() == candid::idl_hash("Err")
+    );
+    let status = if failed {
+        ProposalStatus::Failed
+    } else {
+        ProposalStatus::Executed
+    };
+    (status, args.to_string())
+}
+
+fn _open_proposal(id: u64) -> Result<(Principal, u64), String> {
+    let token = STATS.with(|s| s.borrow().token);
+    PROPOSALS.with(|p| {
+        let proposals = p.borrow();
+        match proposals.get(&id) {
+            Some(p) => {
+                if p.status != ProposalStatus::Open || time() >= p.deadline {
+                    Err("proposal closed".to_string())
+                } else {
+                    Ok((token, p.snapshot_id))
+                }
+            }
+            None => Err("proposal not found".to_string()),
+        }
+    })
+}
+
+// accepted proposals may target this canister to change its own parameters
+fn _is_auth() -> Result<(), String> {
+    STATS.with(|s| {
+        let stats = s.borrow();
+        let caller = ic_cdk::api::caller();
+        if caller == stats.owner || caller == ic_cdk::api::id() {
+            Ok(())
+        } else {
+            Err("invalid caller".to_string())
+        }
+    })
+}
+
+#[cfg(not(any(target_arch = "wasm32", test)))]
+fn main() {
+    // The line below generates did types and service definition from the
+    // methods annotated with `candid_method` above. The definition is then
+    // obtained with `__export_service()`.
+    candid::export_service!();
+    std::print!("{}", __export_service());
+}
+
+#[cfg(any(target_arch = "wasm32", test))]
+fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal(total_supply: u64, votes_yes: u64, votes_no: u64) -> Proposal {
+        Proposal {
+            id: 1,
+            proposer: Principal::anonymous(),
+            description: "".to_string(),
+            target: Principal::anonymous(),
+            method: "setFee".to_string(),
+            args: Vec::new(),
+            snapshot_id: 1,
+            total_supply: Nat::from(total_supply),
+            created_at: 0,
+            deadline: 0,
+            votes_yes: Nat::from(votes_yes),
+            votes_no: Nat::from(votes_no),
+            status: ProposalStatus::Open,
+            result: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn deadline_does_not_overflow() {
+        assert_eq!(_deadline(10, 2), Some(2_000_000_010));
+        assert_eq!(_deadline(0, u64::MAX), None);
+        assert_eq!(_deadline(u64::MAX - 10, 1), None);
+    }
+
+    #[test]
+    fn voting_period_must_fit_in_nanoseconds() {
+        assert!(set_voting_period(u64::MAX / 1000).is_err());
+        assert_eq!(set_voting_period(86400), Ok(()));
+        assert_eq!(STATS.with(|s| s.borrow().voting_period), 86400);
+    }
+
+    #[test]
+    fn proposals_need_quorum_and_majority() {
+        // quorum of 10% of a supply of 1000
+        assert!(_is_accepted(&proposal(1000, 60, 40), 1000));
+        assert!(!_is_accepted(&proposal(1000, 60, 39), 1000));
+        assert!(!_is_accepted(&proposal(1000, 50, 50), 1000));
+        assert!(!_is_accepted(&proposal(1000, 0, 0), 0));
+    }
+
+    #[test]
+    fn error_replies_fail_the_proposal() {
+        let err = candid::encode_one(Result::<(), String>::Err("invalid".to_string())).unwrap();
+        let (status, result) = _reply_outcome(&err);
+        assert_eq!(status, ProposalStatus::Failed);
+        assert!(result.contains("invalid"));
+
+        let ok = candid::encode_one(Result::<(), String>::Ok(())).unwrap();
+        assert_eq!(_reply_outcome(&ok).0, ProposalStatus::Executed);
+        let unit = candid::encode_args(()).unwrap();
+        assert_eq!(_reply_outcome(&unit).0, ProposalStatus::Executed);
+        let nat = candid::encode_one(Nat::from(5)).unwrap();
+        assert_eq!(
+            _reply_outcome(&nat),
+            (ProposalStatus::Executed, "(5 : nat)".to_string())
+        );
+    }
+}
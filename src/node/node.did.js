@@ -3,15 +3,46 @@ export const idlFactory = ({ IDL }) => {
     'closed' : IDL.Bool,
     'token' : IDL.Principal,
     'owner' : IDL.Principal,
+    'team_leader' : IDL.Opt(IDL.Principal),
+    'governance' : IDL.Opt(IDL.Principal),
   });
   const Result = IDL.Variant({ 'Ok' : StatsData, 'Err' : IDL.Text });
   const Result_1 = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
   const Result_2 = IDL.Variant({ 'Ok' : IDL.Nat, 'Err' : IDL.Text });
+  const TopUpSource = IDL.Variant({
+    'CyclesMinting' : IDL.Record({ 'e8s' : IDL.Nat64 }),
+    'Canister' : IDL.Record({ 'cycles' : IDL.Nat64, 'canister' : IDL.Principal }),
+  });
+  const TopUpConfig = IDL.Record({
+    'source' : TopUpSource,
+    'threshold' : IDL.Nat64,
+  });
+  const CyclesSample = IDL.Record({
+    'balance' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const TopUpRecord = IDL.Record({
+    'source' : TopUpSource,
+    'error' : IDL.Opt(IDL.Text),
+    'cycles' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const CyclesInfo = IDL.Record({
+    'balance' : IDL.Nat64,
+    'burn_rate' : IDL.Nat64,
+    'last_sample' : IDL.Opt(CyclesSample),
+    'config' : IDL.Opt(TopUpConfig),
+    'top_ups' : IDL.Vec(TopUpRecord),
+  });
   return IDL.Service({
     'getBackers' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'getCyclesInfo' : IDL.Func([], [CyclesInfo], ['query']),
     'getKeepers' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
     'getStats' : IDL.Func([], [Result], ['query']),
     'setClosed' : IDL.Func([IDL.Bool], [Result_1], []),
+    'setGovernance' : IDL.Func([IDL.Opt(IDL.Principal)], [Result_1], []),
+    'setTeamLeader' : IDL.Func([IDL.Opt(IDL.Principal)], [Result_1], []),
+    'setTopUpConfig' : IDL.Func([IDL.Opt(TopUpConfig)], [Result_1], []),
     'withdraw' : IDL.Func([IDL.Principal, IDL.Nat], [Result_2], []),
   });
 };
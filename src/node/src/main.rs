@@ -1,4 +1,5 @@
 use candid::{candid_method, Nat};
+use common::cycles::{self, CyclesInfo, CyclesMonitor, TopUpConfig};
 use ic_cdk::{
     api::call::CallResult,
     export::{
@@ -21,6 +22,7 @@ pub enum TxError {
     ErrorOperationStyle,
     ErrorTo,
     Other(String),
+    Frozen,
 }
 pub type TxReceipt = Result<Nat, TxError>;
 
@@ -32,6 +34,10 @@ struct StatsData {
     owner: Principal,
     closed: bool,
     token: Principal,
+
+    governance: Option<Principal>,
+    // told about every withdrawal to score the worker's leader
+    team_leader: Option<Principal>,
 }
 
 impl Default for StatsData {
@@ -40,6 +46,9 @@ impl Default for StatsData {
             owner: Principal::anonymous(),
             closed: false,
             token: Principal::anonymous(),
+
+            governance: None,
+            team_leader: None,
         }
     }
 }
@@ -48,6 +57,7 @@ thread_local! {
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     static KEEPERS: RefCell<Keeper> = RefCell::new(Keeper::default());
     static BACKERS: RefCell<Backer> = RefCell::new(Backer::default());
+    static CYCLES: RefCell<CyclesMonitor> = RefCell::new(CyclesMonitor::default());
 }
 
 #[init]
@@ -75,6 +85,26 @@ fn set_closed(closed: bool) -> Result<(), String> {
     })
 }
 
+#[update(name = "setGovernance", guard = "_is_auth")]
+#[candid_method(update, rename = "setGovernance")]
+fn set_governance(governance: Option<Principal>) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.governance = governance;
+        Ok(())
+    })
+}
+
+#[update(name = "setTeamLeader", guard = "_is_auth")]
+#[candid_method(update, rename = "setTeamLeader")]
+fn set_team_leader(team_leader: Option<Principal>) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.team_leader = team_leader;
+        Ok(())
+    })
+}
+
 #[query(name = "getStats")]
 #[candid_method(query, rename = "getStats")]
 fn get_stats() -> Result<StatsData, String> {
@@ -116,16 +146,45 @@ async fn withdraw(worker: Principal, amount: Nat) -> Result<Nat, String> {
         return Err("invalid caller".to_string());
     }
 
-    let token = STATS.with(|s| s.borrow().token);
+    let (token, team_leader) = STATS.with(|s| {
+        let stats = s.borrow();
+        (stats.token, stats.team_leader)
+    });
 
-    let call_result: CallResult<(TxReceipt,)> = ic_cdk::call(token, "mint", (worker, amount)).await;
-    match call_result {
+    let call_result: CallResult<(TxReceipt,)> =
+        ic_cdk::call(token, "mint", (worker, amount.clone())).await;
+    let res = match call_result {
         Ok(tx) => match tx {
             (Ok(tx_id),) => Ok(tx_id),
             (Err(e),) => Err(format!("{:?}", e)),
         },
         Err(e) => Err(format!("{:?} {}", e.0, e.1)),
+    };
+
+    if let (Ok(_), Some(team_leader)) = (&res, team_leader) {
+        // one-way, scoring is best effort and the withdrawal does not wait for it
+        let _ = ic_cdk::api::call::notify(team_leader, "reportPayout", (worker, amount));
     }
+    res
+}
+
+#[query(name = "getCyclesInfo")]
+#[candid_method(query, rename = "getCyclesInfo")]
+fn get_cycles_info() -> CyclesInfo {
+    CYCLES.with(|c| c.borrow().info(ic_cdk::api::canister_balance()))
+}
+
+// None turns automatic top-ups off
+#[update(name = "setTopUpConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setTopUpConfig")]
+fn set_top_up_config(config: Option<TopUpConfig>) -> Result<(), String> {
+    CYCLES.with(|c| c.borrow_mut().set_config(config));
+    Ok(())
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    cycles::heartbeat(&CYCLES).await;
 }
 
 #[pre_upgrade]
@@ -133,12 +192,14 @@ fn pre_upgrade() {
     let stats = STATS.with(|s| s.borrow().clone());
     let keepers = KEEPERS.with(|a| a.borrow().clone());
     let backers = BACKERS.with(|a| a.borrow().clone());
-    ic_cdk::storage::stable_save((stats, keepers, backers)).unwrap();
+    let cycles = CYCLES.with(|c| c.borrow().clone());
+    ic_cdk::storage::stable_save((stats, keepers, backers, cycles)).unwrap();
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (stats_stored, keepers_stored, backers_stored) = ic_cdk::storage::stable_restore().unwrap();
+    let (stats_stored, keepers_stored, backers_stored, cycles_stored) =
+        ic_cdk::storage::stable_restore().unwrap();
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         *stats = stats_stored;
@@ -151,12 +212,17 @@ fn post_upgrade() {
         let mut store = a.borrow_mut();
         *store = backers_stored;
     });
+    CYCLES.with(|c| {
+        let mut cycles = c.borrow_mut();
+        *cycles = Option::<CyclesMonitor>::unwrap_or_default(cycles_stored);
+    });
 }
 
 fn _is_auth() -> Result<(), String> {
     STATS.with(|s| {
         let stats = s.borrow();
-        if ic_cdk::api::caller() == stats.owner {
+        let caller = ic_cdk::api::caller();
+        if caller == stats.owner || Some(caller) == stats.governance {
             Ok(())
         } else {
             Err("invalid caller".to_string())
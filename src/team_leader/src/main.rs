@@ -1,4 +1,6 @@
 use candid::{candid_method, Nat};
+use common::cycles::{self, CyclesInfo, CyclesMonitor, TopUpConfig};
+use common::deposits::{self, PendingSweep, PendingSweeps};
 use ic_cdk::{
     api::call::CallResult,
     export::{
@@ -8,19 +10,61 @@ use ic_cdk::{
 };
 use ic_cdk_macros::*;
 use ic_ledger_types::{
-    query_archived_blocks, query_blocks, AccountIdentifier, Block, BlockIndex, GetBlocksArgs,
-    Operation, DEFAULT_SUBACCOUNT,
+    query_archived_blocks, query_blocks, transfer, AccountIdentifier, Block, BlockIndex,
+    GetBlocksArgs, Memo, Operation, Timestamp, Tokens, TransferArgs, TransferError, DEFAULT_FEE,
+    DEFAULT_SUBACCOUNT,
 };
-use std::{cell::RefCell, collections::BTreeMap, collections::HashSet};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    convert::TryFrom,
+    ops::Bound,
+};
+
+#[derive(CandidType, Debug, PartialEq, Deserialize)]
+pub enum TxError {
+    InsufficientBalance,
+    InsufficientAllowance,
+    Unauthorized,
+    LedgerTrap,
+    AmountTooSmall,
+    BlockUsed,
+    ErrorOperationStyle,
+    ErrorTo,
+    Other(String),
+    Frozen,
+}
+pub type TxReceipt = Result<Nat, TxError>;
 
 type ProfileStore = BTreeMap<Principal, Profile>;
 type ChargeBlock = HashSet<BlockIndex>;
+type RoleStore = BTreeMap<Principal, RoleGrant>;
+// number of each passed profile, rebuilt from the profiles on upgrade
+type NumberIndex = BTreeMap<u16, Principal>;
+type DirectorySettings = BTreeMap<Principal, DirectoryVisibility>;
+// e8s swept from each caller's deposit subaccount and not spent yet
+type DepositStore = BTreeMap<Principal, u64>;
+// accounts of each status, rebuilt from the profiles on upgrade
+type StatusIndex = BTreeMap<ProfileStatus, BTreeSet<Principal>>;
+// (expires_at, account) of each term given, two terms may end on the same ns.
+// Entries of profiles renewed or no longer passed are dropped once due.
+type ExpiryIndex = BTreeSet<(u64, Principal)>;
+// wdn renewal fees paid by leaders who could no longer renew once the fee
+// was pulled, spent by their next renewal or refunded by the owner
+type RenewalCredits = BTreeMap<Principal, Nat>;
 
-#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+// most profiles listProfiles returns at once
+const MAX_PROFILE_PAGE: usize = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 enum ProfileStatus {
     Pending,
     Passed,
     Refused,
+    // a passed leader whose score fell below the threshold
+    Suspended,
+    // a passed leader who did not renew before the end of the grace period
+    Expired,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -31,6 +75,462 @@ struct Profile {
     pub status: ProfileStatus,
     pub comment: String,
     pub number: u16,
+    // the principal that last passed, refused or updated the profile
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    // e8s paid with the application, taken once a refund for it is queued
+    pub paid_fee: Option<u64>,
+    pub refund_block: Option<BlockIndex>,
+    pub passed_at: Option<u64>,
+    // None never expires
+    pub expires_at: Option<u64>,
+}
+
+// what a passed leader shows in the public directory besides the number,
+// community name and wallet
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct DirectoryVisibility {
+    show_discord: bool,
+    show_comment: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct LeaderInfo {
+    number: u16,
+    community_name: String,
+    wallet_address: Principal,
+    discord_account: Option<String>,
+    comment: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+enum MembershipAction {
+    Invited,
+    Requested,
+    Joined,
+    Removed,
+    Left,
+    // the leader turned a join request down
+    Declined,
+    // the leader took an invite back
+    Cancelled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct MembershipEvent {
+    worker: Principal,
+    action: MembershipAction,
+    actor: Principal,
+    timestamp: u64,
+}
+
+// members, invites and join requests of one leader, each with its timestamp
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct Team {
+    members: BTreeMap<Principal, u64>,
+    invites: BTreeMap<Principal, u64>,
+    requests: BTreeMap<Principal, u64>,
+    events: Vec<MembershipEvent>,
+    // joins since the team was formed, rejoins included
+    ever_joined: u64,
+}
+
+impl Team {
+    fn record(&mut self, worker: Principal, action: MembershipAction, actor: Principal, now: u64) {
+        self.events.push(MembershipEvent {
+            worker,
+            action,
+            actor,
+            timestamp: now,
+        });
+    }
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct Teams {
+    teams: BTreeMap<Principal, Team>,
+    // a worker belongs to at most one team
+    member_of: BTreeMap<Principal, Principal>,
+}
+
+impl Teams {
+    fn check_worker(&self, leader: Principal, worker: Principal) -> Result<(), String> {
+        if worker == leader || worker == Principal::anonymous() {
+            return Err("invalid worker".to_string());
+        }
+        if self.member_of.contains_key(&worker) {
+            return Err("already in a team".to_string());
+        }
+        Ok(())
+    }
+
+    // inviting a worker again keeps the first invite
+    fn invite(&mut self, leader: Principal, worker: Principal, now: u64) -> Result<(), String> {
+        self.check_worker(leader, worker)?;
+        let team = self.teams.entry(leader).or_default();
+        if team.invites.contains_key(&worker) {
+            return Ok(());
+        }
+        team.invites.insert(worker, now);
+        team.record(worker, MembershipAction::Invited, leader, now);
+        Ok(())
+    }
+
+    // asking again keeps the first request
+    fn request(&mut self, leader: Principal, worker: Principal, now: u64) -> Result<(), String> {
+        self.check_worker(leader, worker)?;
+        let team = self.teams.entry(leader).or_default();
+        if team.requests.contains_key(&worker) {
+            return Ok(());
+        }
+        team.requests.insert(worker, now);
+        team.record(worker, MembershipAction::Requested, worker, now);
+        Ok(())
+    }
+
+    fn accept(&mut self, leader: Principal, worker: Principal, now: u64) -> Result<(), String> {
+        let invited = self
+            .teams
+            .get(&leader)
+            .is_some_and(|team| team.invites.contains_key(&worker));
+        if !invited {
+            return Err("invite not found".to_string());
+        }
+        self.join(leader, worker, worker, now)
+    }
+
+    fn approve(&mut self, leader: Principal, worker: Principal, now: u64) -> Result<(), String> {
+        let requested = self
+            .teams
+            .get(&leader)
+            .is_some_and(|team| team.requests.contains_key(&worker));
+        if !requested {
+            return Err("request not found".to_string());
+        }
+        self.join(leader, worker, leader, now)
+    }
+
+    fn decline(&mut self, leader: Principal, worker: Principal, now: u64) -> Result<(), String> {
+        let team = self.teams.entry(leader).or_default();
+        if team.requests.remove(&worker).is_none() {
+            return Err("request not found".to_string());
+        }
+        team.record(worker, MembershipAction::Declined, leader, now);
+        Ok(())
+    }
+
+    fn cancel_invite(
+        &mut self,
+        leader: Principal,
+        worker: Principal,
+        now: u64,
+    ) -> Result<(), String> {
+        let team = self.teams.entry(leader).or_default();
+        if team.invites.remove(&worker).is_none() {
+            return Err("invite not found".to_string());
+        }
+        team.record(worker, MembershipAction::Cancelled, leader, now);
+        Ok(())
+    }
+
+    fn join(
+        &mut self,
+        leader: Principal,
+        worker: Principal,
+        actor: Principal,
+        now: u64,
+    ) -> Result<(), String> {
+        if self.member_of.contains_key(&worker) {
+            return Err("already in a team".to_string());
+        }
+        let team = self.teams.entry(leader).or_default();
+        team.invites.remove(&worker);
+        team.requests.remove(&worker);
+        team.members.insert(worker, now);
+        team.ever_joined += 1;
+        team.record(worker, MembershipAction::Joined, actor, now);
+        self.member_of.insert(worker, leader);
+        Ok(())
+    }
+
+    fn leave(
+        &mut self,
+        leader: Principal,
+        worker: Principal,
+        action: MembershipAction,
+        actor: Principal,
+        now: u64,
+    ) -> Result<(), String> {
+        if self.member_of.get(&worker) != Some(&leader) {
+            return Err("member not found".to_string());
+        }
+        self.member_of.remove(&worker);
+        let team = self.teams.entry(leader).or_default();
+        team.members.remove(&worker);
+        team.record(worker, action, actor, now);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TeamInfo {
+    leader: Principal,
+    members: Vec<(Principal, u64)>,
+    invites: Vec<(Principal, u64)>,
+    requests: Vec<(Principal, u64)>,
+}
+
+const DAY: u64 = 86_400_000_000_000;
+
+// score = member_weight * members
+//       + retention_weight * retained members / members that ever joined
+//       + payout_weight * decayed payouts / payout_unit
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ScoreConfig {
+    member_weight: u64,
+    retention_weight: u64,
+    payout_weight: u64,
+    payout_unit: u64,
+    // payouts count half after this long, ns, 0 keeps them whole
+    half_life: u64,
+    // passed leaders scoring below it can be suspended
+    suspend_below: u64,
+    // leaders passed more recently than this are never suspended, ns
+    grace_period: u64,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        ScoreConfig {
+            member_weight: 10,
+            retention_weight: 100,
+            payout_weight: 1,
+            payout_unit: 1,
+            half_life: 30 * DAY,
+            suspend_below: 0,
+            grace_period: 30 * DAY,
+        }
+    }
+}
+
+// payouts reported for the members of one leader, decayed up to updated_at
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct PayoutTotal {
+    amount: u64,
+    updated_at: u64,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct Scoring {
+    config: ScoreConfig,
+    // the node canister allowed to call reportPayout
+    reporter: Option<Principal>,
+    payouts: BTreeMap<Principal, PayoutTotal>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct LeaderScore {
+    leader: Principal,
+    number: u16,
+    score: u64,
+    members: u64,
+    retention_bps: u64,
+    payouts: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct ProfileFilter {
+    status: Option<ProfileStatus>,
+    // inclusive
+    min_number: Option<u16>,
+    max_number: Option<u16>,
+    // case-insensitive substring
+    community_name: Option<String>,
+}
+
+impl ProfileFilter {
+    fn matches(&self, p: &Profile) -> bool {
+        if let Some(status) = &self.status {
+            if p.status != *status {
+                return false;
+            }
+        }
+        if self.min_number.is_some_and(|n| p.number < n)
+            || self.max_number.is_some_and(|n| p.number > n)
+        {
+            return false;
+        }
+        match &self.community_name {
+            Some(name) => p
+                .community_name
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ProfilePage {
+    profiles: Vec<Profile>,
+    // pass as the cursor of the next call, None on the last page
+    next: Option<Principal>,
+    // profiles matching the filter
+    total: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ProfileEvent {
+    index: u64,
+    account: Principal,
+    // None for a first application
+    old_status: Option<ProfileStatus>,
+    new_status: ProfileStatus,
+    actor: Principal,
+    comment: String,
+    timestamp: u64,
+}
+
+// append-only record of every profile change
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct AuditLog {
+    events: Vec<ProfileEvent>,
+    by_account: BTreeMap<Principal, Vec<u64>>,
+}
+
+impl AuditLog {
+    fn record_as(
+        &mut self,
+        actor: Principal,
+        account: Principal,
+        old_status: Option<ProfileStatus>,
+        new_status: ProfileStatus,
+        comment: String,
+        timestamp: u64,
+    ) {
+        let index = self.events.len() as u64;
+        self.events.push(ProfileEvent {
+            index,
+            account,
+            old_status,
+            new_status,
+            actor,
+            comment,
+            timestamp,
+        });
+        self.by_account.entry(account).or_default().push(index);
+    }
+
+    fn history_of(&self, account: Principal) -> Vec<ProfileEvent> {
+        match self.by_account.get(&account) {
+            Some(indexes) => indexes
+                .iter()
+                .map(|i| self.events[*i as usize].clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn page(&self, start: usize, limit: usize) -> Vec<ProfileEvent> {
+        let start = start.min(self.events.len());
+        let end = start.saturating_add(limit).min(self.events.len());
+        self.events[start..end].to_vec()
+    }
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+enum Role {
+    Owner,
+    Admin,
+    Reviewer,
+    Viewer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Permission {
+    ManageRoles,
+    UpdateProfile,
+    Review,
+    View,
+}
+
+impl Role {
+    // Owner: everything. Admin: everything but roles.
+    // Reviewer: pass, refuse and view. Viewer: view only.
+    fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Admin => permission != Permission::ManageRoles,
+            Role::Reviewer => permission == Permission::Review || permission == Permission::View,
+            Role::Viewer => permission == Permission::View,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct RoleGrant {
+    role: Role,
+    granted_by: Principal,
+    granted_at: u64,
+}
+
+// refunds failed transfers again after this long, ns
+const REFUND_RETRY_INTERVAL: u64 = 600_000_000_000;
+const REFUNDS_PER_BEAT: usize = 10;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum RefundPolicy {
+    Full,
+    // basis points of the paid fee
+    Partial(u64),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct PendingRefund {
+    id: u64,
+    account: Principal,
+    // e8s taken from this canister's account, the ledger fee included
+    amount: u64,
+    // sent as created_at_time so the ledger drops a transfer made twice
+    created_at: u64,
+    attempts: u32,
+    last_attempt: u64,
+    last_error: Option<String>,
+    in_flight: bool,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct RefundQueue {
+    next_id: u64,
+    pending: BTreeMap<u64, PendingRefund>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum PaymentAsset {
+    // a transfer of `fee` e8s to fee_to on `ledger`
+    Icp,
+    // pulled with transferFrom once the applicant approved this canister
+    Wdn { token: Principal, fee: Nat },
+}
+
+// expire passed leaders at most this often, ns
+const TERM_SWEEP_INTERVAL: u64 = 600_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TermConfig {
+    // ns
+    duration: u64,
+    // a leader stays passed this long after the term ends, ns
+    grace_period: u64,
+    // in the units of the payment asset
+    renewal_fee: Nat,
+}
+
+// how the fee of an application was paid
+enum Charge {
+    Block(BlockIndex),
+    Deposit(u64),
+    Token,
 }
 
 #[derive(Deserialize, CandidType, Clone, Debug)]
@@ -42,6 +542,14 @@ struct StatsData {
     ledger: Principal,
     fee: Nat,
     fee_to: Principal,
+
+    governance: Option<Principal>,
+    // None keeps the fee of refused applications
+    refund_policy: Option<RefundPolicy>,
+    // None is Icp
+    payment_asset: Option<PaymentAsset>,
+    // None keeps leaders passed for good
+    term: Option<TermConfig>,
 }
 
 impl Default for StatsData {
@@ -54,6 +562,11 @@ impl Default for StatsData {
             ledger: Principal::anonymous(),
             fee: Nat::from(0),
             fee_to: Principal::anonymous(),
+
+            governance: None,
+            refund_policy: None,
+            payment_asset: None,
+            term: None,
         }
     }
 }
@@ -62,7 +575,24 @@ thread_local! {
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     static PROFILE_STORE: RefCell<ProfileStore> = RefCell::new(ProfileStore::default());
     static CHARGE_BLOCK: RefCell<ChargeBlock> = RefCell::new(ChargeBlock::default());
-    static LAST_NUMBER: RefCell<u16> = RefCell::new(0);
+    static LAST_NUMBER: RefCell<u16> = const { RefCell::new(0) };
+    static CYCLES: RefCell<CyclesMonitor> = RefCell::new(CyclesMonitor::default());
+    static ROLES: RefCell<RoleStore> = RefCell::new(RoleStore::default());
+    static AUDIT_LOG: RefCell<AuditLog> = RefCell::new(AuditLog::default());
+    static REFUNDS: RefCell<RefundQueue> = RefCell::new(RefundQueue::default());
+    static DEPOSITS: RefCell<DepositStore> = RefCell::new(DepositStore::default());
+    static SWEEPS: RefCell<PendingSweeps> = RefCell::new(PendingSweeps::default());
+    // accounts whose wdn fee is being pulled, their application or renewal
+    // is made once it is paid
+    static FEE_PULLS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    static NUMBER_INDEX: RefCell<NumberIndex> = RefCell::new(NumberIndex::default());
+    static STATUS_INDEX: RefCell<StatusIndex> = RefCell::new(StatusIndex::default());
+    static DIRECTORY: RefCell<DirectorySettings> = RefCell::new(DirectorySettings::default());
+    static TEAMS: RefCell<Teams> = RefCell::new(Teams::default());
+    static SCORING: RefCell<Scoring> = RefCell::new(Scoring::default());
+    static LAST_TERM_SWEEP: RefCell<u64> = const { RefCell::new(0) };
+    static EXPIRY_INDEX: RefCell<ExpiryIndex> = RefCell::new(ExpiryIndex::default());
+    static RENEWAL_CREDITS: RefCell<RenewalCredits> = RefCell::new(RenewalCredits::default());
 }
 
 #[init]
@@ -110,6 +640,7 @@ fn set_fee(fee: Nat) -> Result<(), String> {
 fn set_fee_to(fee_to: Principal) -> Result<(), String> {
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
+        _check_refund_setup(&stats.refund_policy, fee_to, ic_cdk::api::id())?;
         stats.fee_to = fee_to;
         Ok(())
     })
@@ -125,6 +656,106 @@ fn set_admin(admin: Principal) -> Result<(), String> {
     })
 }
 
+#[update(name = "setGovernance", guard = "_is_auth")]
+#[candid_method(update, rename = "setGovernance")]
+fn set_governance(governance: Option<Principal>) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.governance = governance;
+        Ok(())
+    })
+}
+
+// Refunds are paid from this canister's own ledger account, so fee_to has
+// to be this canister while a policy is set. Only ICP fees are refunded.
+#[update(name = "setRefundPolicy", guard = "_is_auth")]
+#[candid_method(update, rename = "setRefundPolicy")]
+fn set_refund_policy(policy: Option<RefundPolicy>) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        _check_refund_setup(&policy, stats.fee_to, ic_cdk::api::id())?;
+        stats.refund_policy = policy;
+        Ok(())
+    })
+}
+
+#[update(name = "setPaymentAsset", guard = "_is_auth")]
+#[candid_method(update, rename = "setPaymentAsset")]
+fn set_payment_asset(payment_asset: PaymentAsset) -> Result<(), String> {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.payment_asset = Some(payment_asset);
+        Ok(())
+    })
+}
+
+// Passed leaders without a term get one starting now
+#[update(name = "setTermConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setTermConfig")]
+fn set_term_config(term: Option<TermConfig>) -> Result<(), String> {
+    if let Some(term) = &term {
+        let expires_at = ic_cdk::api::time() + term.duration;
+        PROFILE_STORE.with(|profile_store| {
+            let mut store = profile_store.borrow_mut();
+            for (account, p) in store.iter_mut() {
+                if p.status == ProfileStatus::Passed && p.expires_at.is_none() {
+                    p.expires_at = Some(expires_at);
+                    _index_expiry(*account, p.expires_at);
+                }
+            }
+        });
+    }
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.term = term;
+        Ok(())
+    })
+}
+
+#[update(name = "grantRole", guard = "_can_manage_roles")]
+#[candid_method(update, rename = "grantRole")]
+fn grant_role(account: Principal, role: Role) -> Result<(), String> {
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        roles.insert(
+            account,
+            RoleGrant {
+                role,
+                granted_by: ic_cdk::api::caller(),
+                granted_at: ic_cdk::api::time(),
+            },
+        );
+        Ok(())
+    })
+}
+
+#[update(name = "revokeRole", guard = "_can_manage_roles")]
+#[candid_method(update, rename = "revokeRole")]
+fn revoke_role(account: Principal) -> Result<(), String> {
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        match roles.remove(&account) {
+            Some(_) => Ok(()),
+            None => Err("role not found".to_string()),
+        }
+    })
+}
+
+#[query(name = "getRoles", guard = "_can_view")]
+#[candid_method(query, rename = "getRoles")]
+fn get_roles() -> Vec<(Principal, RoleGrant)> {
+    ROLES.with(|r| {
+        let roles = r.borrow();
+        roles.iter().map(|(k, v)| (*k, v.clone())).collect()
+    })
+}
+
+#[query(name = "getRole")]
+#[candid_method(query, rename = "getRole")]
+fn get_role(account: Principal) -> Option<Role> {
+    _role_of(account)
+}
+
 #[query(name = "getStats")]
 #[candid_method(query, rename = "getStats")]
 fn get_stats() -> Result<StatsData, String> {
@@ -148,6 +779,43 @@ fn get() -> Result<Profile, String> {
     })
 }
 
+// The ICP paid into this subaccount of the canister is credited to `account`
+// by confirmDeposit.
+#[query(name = "getDepositAccount")]
+#[candid_method(query, rename = "getDepositAccount")]
+fn get_deposit_account(account: Principal) -> AccountIdentifier {
+    deposits::deposit_account(&ic_cdk::api::id(), &account)
+}
+
+// Sweeps the caller's deposit subaccount to fee_to, returns the caller's credit
+#[update(name = "confirmDeposit")]
+#[candid_method(update, rename = "confirmDeposit")]
+async fn confirm_deposit() -> Result<u64, String> {
+    let caller = ic_cdk::api::caller();
+    let (ledger, fee_to) = STATS.with(|s| {
+        let stats = s.borrow();
+        (stats.ledger, stats.fee_to)
+    });
+    let amount = deposits::sweep_deposit(&SWEEPS, ledger, caller, fee_to).await?;
+    Ok(_credit_deposit(caller, amount))
+}
+
+#[query(name = "getPendingSweeps", guard = "_is_auth")]
+#[candid_method(query, rename = "getPendingSweeps")]
+fn get_pending_sweeps() -> Vec<(Principal, PendingSweep)> {
+    SWEEPS.with(|s| s.borrow().iter().map(|(k, v)| (*k, v.clone())).collect())
+}
+
+// Settles a sweep the ledger no longer deduplicates, once its history shows
+// whether the transfer went through
+#[update(name = "resolveSweep", guard = "_is_auth")]
+#[candid_method(update, rename = "resolveSweep")]
+fn resolve_sweep(account: Principal, went_through: bool) -> Result<u64, String> {
+    let amount = deposits::resolve_sweep(&SWEEPS, account, went_through)?;
+    Ok(_credit_deposit(account, amount))
+}
+
+// Pays the application fee with an ICP transfer to fee_to
 #[update]
 #[candid_method(update)]
 async fn insert(
@@ -157,95 +825,184 @@ async fn insert(
 ) -> Result<(), String> {
     let caller = ic_cdk::api::caller();
 
-    let (ledger, fee) = STATS.with(|s| {
+    let (ledger, fee, payment_asset) = STATS.with(|s| {
         let stats = s.borrow();
-        (stats.ledger, stats.fee.clone())
+        (stats.ledger, stats.fee.clone(), stats.payment_asset.clone())
     });
+    if let Some(PaymentAsset::Wdn { .. }) = payment_asset {
+        return Err("invalid payment".to_string());
+    }
 
     let amount = check_charge_block(ledger, block_index, caller).await?;
     if amount < fee {
         return Err("invalid fee".to_string());
     }
+    _apply(
+        caller,
+        Charge::Block(block_index),
+        Some(amount),
+        community_name,
+        discord_account,
+        ic_cdk::api::time(),
+    )
+}
 
-    let mut res = Ok(());
+// Pays the application fee from the caller's confirmed deposit
+#[update(name = "insertWithDeposit")]
+#[candid_method(update, rename = "insertWithDeposit")]
+fn insert_with_deposit(community_name: String, discord_account: String) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
 
-    STATS.with(|s| {
+    let (fee, payment_asset) = STATS.with(|s| {
         let stats = s.borrow();
-        if stats.closed {
-            res = Err("closed".to_string());
-        }
+        (stats.fee.clone(), stats.payment_asset.clone())
     });
-
-    if res.is_err() {
-        return res;
+    if let Some(PaymentAsset::Wdn { .. }) = payment_asset {
+        return Err("invalid payment".to_string());
     }
 
-    PROFILE_STORE.with(|profile_store| {
-        let mut store = profile_store.borrow_mut();
-        let info = store.get_mut(&caller);
-        match info {
-            Some(info) => {
-                if info.status == ProfileStatus::Refused {
-                    res = save_charge_block(block_index);
-                    if res.is_err() {
-                        return;
-                    }
+    let amount = u64::try_from(&fee.0).map_err(|_| "invalid fee".to_string())?;
+    _apply(
+        caller,
+        Charge::Deposit(amount),
+        Some(amount),
+        community_name,
+        discord_account,
+        ic_cdk::api::time(),
+    )
+}
 
-                    info.community_name = community_name;
-                    info.discord_account = discord_account;
-                    info.wallet_address = caller;
-                    info.status = ProfileStatus::Pending;
-                    info.comment = "".to_string();
-                    info.number = 0;
-                } else {
-                    res = Err("duplicate wallet address".to_string());
-                    return;
-                }
-            }
-            None => {
-                res = save_charge_block(block_index);
-                if res.is_err() {
-                    return;
-                }
+// Pays the application fee in wdn, pulled from the caller's allowance
+#[update(name = "insertWithAllowance")]
+#[candid_method(update, rename = "insertWithAllowance")]
+async fn insert_with_allowance(
+    community_name: String,
+    discord_account: String,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
 
-                store.insert(
-                    caller.clone(),
-                    Profile {
-                        community_name,
-                        discord_account,
-                        wallet_address: caller,
-                        status: ProfileStatus::Pending,
-                        comment: "".to_string(),
-                        number: 0,
-                    },
-                );
-            }
-        }
+    let (fee_to, payment_asset) = STATS.with(|s| {
+        let stats = s.borrow();
+        (stats.fee_to, stats.payment_asset.clone())
     });
+    let (token, fee) = match payment_asset {
+        Some(PaymentAsset::Wdn { token, fee }) => (token, fee),
+        _ => return Err("invalid payment".to_string()),
+    };
 
-    res
+    _can_apply(caller)?;
+    _reserve_fee_pull(caller)?;
+    let res = pull_token_fee(token, caller, fee_to, fee).await;
+    _release_fee_pull(caller);
+    res?;
+    _file_application(
+        caller,
+        Charge::Token,
+        None,
+        community_name,
+        discord_account,
+        ic_cdk::api::time(),
+    )
+}
+
+// Files the application of `caller` once its fee is paid
+fn _apply(
+    caller: Principal,
+    charge: Charge,
+    paid_fee: Option<u64>,
+    community_name: String,
+    discord_account: String,
+    now: u64,
+) -> Result<(), String> {
+    _can_apply(caller)?;
+    _file_application(
+        caller,
+        charge,
+        paid_fee,
+        community_name,
+        discord_account,
+        now,
+    )
+}
+
+// _apply without the checks, made while the slot is reserved
+fn _file_application(
+    caller: Principal,
+    charge: Charge,
+    paid_fee: Option<u64>,
+    community_name: String,
+    discord_account: String,
+    now: u64,
+) -> Result<(), String> {
+    let old_status = PROFILE_STORE.with(|p| p.borrow().get(&caller).map(|p| p.status.clone()));
+    take_charge(caller, &charge)?;
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        store.insert(
+            caller,
+            Profile {
+                community_name,
+                discord_account,
+                wallet_address: caller,
+                status: ProfileStatus::Pending,
+                comment: "".to_string(),
+                number: 0,
+                reviewed_by: None,
+                reviewed_at: None,
+                paid_fee,
+                refund_block: None,
+                passed_at: None,
+                expires_at: None,
+            },
+        );
+    });
+    _record_status(
+        caller,
+        caller,
+        old_status,
+        ProfileStatus::Pending,
+        String::new(),
+        now,
+    );
+    Ok(())
 }
 
-#[update(name = "pass", guard = "_is_admin")]
+#[update(name = "pass", guard = "_can_review")]
 #[candid_method(update)]
 fn pass(account: Principal) -> Result<(), String> {
-    let last_number = LAST_NUMBER.with(|n| n.borrow().clone());
-    let last_number = last_number + 1;
+    let last_number = LAST_NUMBER.with(|n| *n.borrow()) + 1;
+    let term = STATS.with(|s| s.borrow().term.clone());
+    let now = ic_cdk::api::time();
 
-    let res = PROFILE_STORE.with(|profile_store| {
+    PROFILE_STORE.with(|profile_store| {
         let mut store = profile_store.borrow_mut();
 
         if let Some(p) = store.get_mut(&account) {
+            let old_status = p.status.clone();
             p.status = ProfileStatus::Passed;
             p.number = last_number;
+            p.reviewed_by = Some(ic_cdk::api::caller());
+            p.reviewed_at = Some(now);
+            p.passed_at = Some(now);
+            p.expires_at = term.map(|t| now + t.duration);
+            _index_expiry(account, p.expires_at);
+            _record_status(
+                ic_cdk::api::caller(),
+                account,
+                Some(old_status),
+                ProfileStatus::Passed,
+                String::new(),
+                ic_cdk::api::time(),
+            );
             Ok(())
         } else {
             Err("wallet address not found".to_string())
         }
+    })?;
+    NUMBER_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        index.insert(last_number, account);
     });
-    if res.is_err() {
-        return res;
-    }
 
     LAST_NUMBER.with(|n| {
         let mut n = n.borrow_mut();
@@ -255,30 +1012,118 @@ fn pass(account: Principal) -> Result<(), String> {
     Ok(())
 }
 
-#[update(name = "refuse", guard = "_is_admin")]
+// Extends the caller's term, from its end or from now once expired. The
+// renewal fee is paid like the application fee of insert.
+#[update]
 #[candid_method(update)]
-fn refuse(account: Principal, comment: String) -> Result<(), String> {
-    PROFILE_STORE.with(|profile_store| {
-        let mut store = profile_store.borrow_mut();
+async fn renew(block_index: Option<BlockIndex>) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
 
-        if let Some(p) = store.get_mut(&account) {
-            p.status = ProfileStatus::Refused;
-            p.comment = comment;
-            Ok(())
-        } else {
-            Err("wallet address not found".to_string())
+    let (ledger, fee_to, payment_asset, term) = STATS.with(|s| {
+        let stats = s.borrow();
+        (
+            stats.ledger,
+            stats.fee_to,
+            stats.payment_asset.clone(),
+            stats.term.clone(),
+        )
+    });
+    let term = match term {
+        Some(term) => term,
+        None => return Err("no term".to_string()),
+    };
+    _can_renew(caller)?;
+
+    let charge = match (payment_asset, block_index) {
+        (Some(PaymentAsset::Wdn { token, .. }), None) => {
+            if !_spend_renewal_credit(caller, &term.renewal_fee) {
+                _reserve_fee_pull(caller)?;
+                let res = pull_token_fee(token, caller, fee_to, term.renewal_fee.clone()).await;
+                _release_fee_pull(caller);
+                res?;
+            }
+            // the leader may have been refused or suspended meanwhile
+            if !_renew_term(caller, &term, ic_cdk::api::time()) {
+                _credit_renewal(caller, term.renewal_fee.clone());
+                return Err("leader not found, the renewal fee was credited".to_string());
+            }
+            return Ok(());
         }
-    })
+        (Some(PaymentAsset::Wdn { .. }), Some(_)) => return Err("invalid payment".to_string()),
+        (_, Some(block_index)) => {
+            let amount = check_charge_block(ledger, block_index, caller).await?;
+            if amount < term.renewal_fee {
+                return Err("invalid fee".to_string());
+            }
+            Charge::Block(block_index)
+        }
+        (_, None) => Charge::Deposit(
+            u64::try_from(&term.renewal_fee.0).map_err(|_| "invalid fee".to_string())?,
+        ),
+    };
+
+    _can_renew(caller)?;
+    take_charge(caller, &charge)?;
+    _renew_term(caller, &term, ic_cdk::api::time());
+    Ok(())
+}
+
+// A refund owed under the refund policy is queued before the transfer is
+// made, so a failed transfer is retried by the heartbeat.
+#[update(name = "refuse", guard = "_can_review")]
+#[candid_method(update)]
+async fn refuse(account: Principal, comment: String) -> Result<(), String> {
+    let refund = _refuse(account, comment, ic_cdk::api::caller(), ic_cdk::api::time())?;
+    if let Some(amount) = refund {
+        let id = _queue_refund(account, amount);
+        // on failure the refund stays queued
+        let _ = _send_refund(id).await;
+    }
+    Ok(())
 }
 
-#[update(name = "updateProfile", guard = "_is_admin")]
+#[update(name = "updateProfile", guard = "_can_update_profile")]
 #[candid_method(update, rename = "updateProfile")]
 fn update_profile(profile: Profile) -> Result<(), String> {
+    _update_profile(profile, ic_cdk::api::caller(), ic_cdk::api::time())
+}
+
+// Fees and terms are kept, a profile passed here is given a term like pass
+fn _update_profile(profile: Profile, actor: Principal, now: u64) -> Result<(), String> {
+    let term = STATS.with(|s| s.borrow().term.clone());
     PROFILE_STORE.with(|profile_store| {
         let mut store = profile_store.borrow_mut();
 
         if let Some(p) = store.get_mut(&profile.wallet_address) {
-            *p = profile;
+            let old_status = p.status.clone();
+            _record_status(
+                actor,
+                profile.wallet_address,
+                Some(old_status.clone()),
+                profile.status.clone(),
+                profile.comment.clone(),
+                now,
+            );
+            *p = Profile {
+                reviewed_by: Some(actor),
+                reviewed_at: Some(now),
+                paid_fee: p.paid_fee,
+                refund_block: p.refund_block,
+                passed_at: p.passed_at,
+                expires_at: p.expires_at,
+                ..profile
+            };
+            if p.status == ProfileStatus::Passed {
+                if old_status != ProfileStatus::Passed {
+                    p.passed_at = Some(now);
+                    p.expires_at = term.map(|t| now + t.duration);
+                }
+                _index_expiry(p.wallet_address, p.expires_at);
+                NUMBER_INDEX.with(|i| {
+                    let mut index = i.borrow_mut();
+                    index.insert(p.number, p.wallet_address);
+                });
+            }
             Ok(())
         } else {
             Err("wallet address not found".to_string())
@@ -286,7 +1131,7 @@ fn update_profile(profile: Profile) -> Result<(), String> {
     })
 }
 
-#[query(name = "getAll", guard = "_is_admin")]
+#[query(name = "getAll", guard = "_can_view")]
 #[candid_method(query, rename = "getAll")]
 fn get_all(start: usize, limit: usize) -> Vec<Profile> {
     let mut count = 0;
@@ -307,19 +1152,738 @@ fn get_all(start: usize, limit: usize) -> Vec<Profile> {
     })
 }
 
+// `cursor` is the wallet address of the last profile of the previous page,
+// at most MAX_PROFILE_PAGE profiles are returned
+#[query(name = "listProfiles", guard = "_can_view")]
+#[candid_method(query, rename = "listProfiles")]
+fn list_profiles(filter: ProfileFilter, cursor: Option<Principal>, limit: usize) -> ProfilePage {
+    let limit = limit.min(MAX_PROFILE_PAGE);
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let status_only = filter.min_number.is_none()
+        && filter.max_number.is_none()
+        && filter.community_name.is_none();
+    PROFILE_STORE.with(|profile_store| {
+        let store = profile_store.borrow();
+        let status = match &filter.status {
+            Some(status) => status,
+            None => {
+                let total = if status_only {
+                    store.len()
+                } else {
+                    store.values().filter(|p| filter.matches(p)).count()
+                };
+                let matching = store
+                    .range((start, Bound::Unbounded))
+                    .map(|(_, p)| p)
+                    .filter(|p| filter.matches(p));
+                return _profile_page(matching, limit, total);
+            }
+        };
+        STATUS_INDEX.with(|i| {
+            let index = i.borrow();
+            let accounts = index.get(status);
+            let total = match accounts {
+                Some(accounts) if status_only => accounts.len(),
+                Some(accounts) => accounts
+                    .iter()
+                    .filter(|a| filter.matches(&store[*a]))
+                    .count(),
+                None => 0,
+            };
+            let matching = accounts
+                .into_iter()
+                .flat_map(|accounts| accounts.range((start, Bound::Unbounded)))
+                .map(|a| &store[a])
+                .filter(|p| filter.matches(p));
+            _profile_page(matching, limit, total)
+        })
+    })
+}
+
+// Public directory of passed leaders, ordered by number
+#[query(name = "getLeaders")]
+#[candid_method(query, rename = "getLeaders")]
+fn get_leaders(start: usize, limit: usize) -> Vec<LeaderInfo> {
+    NUMBER_INDEX.with(|i| {
+        let index = i.borrow();
+        index
+            .iter()
+            .filter_map(|(number, account)| _leader_info(*number, *account))
+            .skip(start)
+            .take(limit)
+            .collect()
+    })
+}
+
+#[query(name = "getLeaderByNumber")]
+#[candid_method(query, rename = "getLeaderByNumber")]
+fn get_leader_by_number(number: u16) -> Option<LeaderInfo> {
+    let account = NUMBER_INDEX.with(|i| i.borrow().get(&number).copied())?;
+    _leader_info(number, account)
+}
+
+#[update(name = "setDirectoryVisibility")]
+#[candid_method(update, rename = "setDirectoryVisibility")]
+fn set_directory_visibility(visibility: DirectoryVisibility) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    let found = PROFILE_STORE.with(|p| p.borrow().contains_key(&caller));
+    if !found {
+        return Err("not found".to_string());
+    }
+    DIRECTORY.with(|d| {
+        let mut directory = d.borrow_mut();
+        directory.insert(caller, visibility);
+        Ok(())
+    })
+}
+
+// Called by a passed leader
+#[update(name = "inviteMember")]
+#[candid_method(update, rename = "inviteMember")]
+fn invite_member(worker: Principal) -> Result<(), String> {
+    let leader = ic_cdk::api::caller();
+    _is_passed(leader)?;
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.invite(leader, worker, ic_cdk::api::time())
+    })
+}
+
+#[update(name = "cancelInvite")]
+#[candid_method(update, rename = "cancelInvite")]
+fn cancel_invite(worker: Principal) -> Result<(), String> {
+    let leader = ic_cdk::api::caller();
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.cancel_invite(leader, worker, ic_cdk::api::time())
+    })
+}
+
+#[update(name = "acceptInvite")]
+#[candid_method(update, rename = "acceptInvite")]
+fn accept_invite(leader: Principal) -> Result<(), String> {
+    let worker = ic_cdk::api::caller();
+    _is_passed(leader)?;
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.accept(leader, worker, ic_cdk::api::time())
+    })
+}
+
+#[update(name = "requestToJoin")]
+#[candid_method(update, rename = "requestToJoin")]
+fn request_to_join(leader: Principal) -> Result<(), String> {
+    let worker = ic_cdk::api::caller();
+    _is_passed(leader)?;
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.request(leader, worker, ic_cdk::api::time())
+    })
+}
+
+// Called by the leader the request was sent to
+#[update(name = "approveRequest")]
+#[candid_method(update, rename = "approveRequest")]
+fn approve_request(worker: Principal) -> Result<(), String> {
+    let leader = ic_cdk::api::caller();
+    _is_passed(leader)?;
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.approve(leader, worker, ic_cdk::api::time())
+    })
+}
+
+#[update(name = "declineRequest")]
+#[candid_method(update, rename = "declineRequest")]
+fn decline_request(worker: Principal) -> Result<(), String> {
+    let leader = ic_cdk::api::caller();
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.decline(leader, worker, ic_cdk::api::time())
+    })
+}
+
+#[update(name = "removeMember")]
+#[candid_method(update, rename = "removeMember")]
+fn remove_member(worker: Principal) -> Result<(), String> {
+    let leader = ic_cdk::api::caller();
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        teams.leave(
+            leader,
+            worker,
+            MembershipAction::Removed,
+            leader,
+            ic_cdk::api::time(),
+        )
+    })
+}
+
+#[update(name = "leaveTeam")]
+#[candid_method(update, rename = "leaveTeam")]
+fn leave_team() -> Result<(), String> {
+    let worker = ic_cdk::api::caller();
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        let leader = match teams.member_of.get(&worker) {
+            Some(leader) => *leader,
+            None => return Err("team not found".to_string()),
+        };
+        teams.leave(
+            leader,
+            worker,
+            MembershipAction::Left,
+            worker,
+            ic_cdk::api::time(),
+        )
+    })
+}
+
+#[query(name = "getTeam")]
+#[candid_method(query, rename = "getTeam")]
+fn get_team(leader: Principal) -> Option<TeamInfo> {
+    TEAMS.with(|t| {
+        let teams = t.borrow();
+        teams.teams.get(&leader).map(|team| TeamInfo {
+            leader,
+            members: team.members.iter().map(|(k, v)| (*k, *v)).collect(),
+            invites: team.invites.iter().map(|(k, v)| (*k, *v)).collect(),
+            requests: team.requests.iter().map(|(k, v)| (*k, *v)).collect(),
+        })
+    })
+}
+
+#[query(name = "getTeamOf")]
+#[candid_method(query, rename = "getTeamOf")]
+fn get_team_of(worker: Principal) -> Option<Principal> {
+    TEAMS.with(|t| t.borrow().member_of.get(&worker).copied())
+}
+
+#[query(name = "getTeamEvents")]
+#[candid_method(query, rename = "getTeamEvents")]
+fn get_team_events(leader: Principal, start: usize, limit: usize) -> Vec<MembershipEvent> {
+    TEAMS.with(|t| {
+        let teams = t.borrow();
+        match teams.teams.get(&leader) {
+            Some(team) => team
+                .events
+                .iter()
+                .skip(start)
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    })
+}
+
+#[update(name = "setScoreConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setScoreConfig")]
+fn set_score_config(config: ScoreConfig) -> Result<(), String> {
+    if config.payout_unit == 0 {
+        return Err("invalid score config".to_string());
+    }
+    SCORING.with(|s| {
+        let mut scoring = s.borrow_mut();
+        scoring.config = config;
+        Ok(())
+    })
+}
+
+#[update(name = "setPayoutReporter", guard = "_is_auth")]
+#[candid_method(update, rename = "setPayoutReporter")]
+fn set_payout_reporter(reporter: Option<Principal>) -> Result<(), String> {
+    SCORING.with(|s| {
+        let mut scoring = s.borrow_mut();
+        scoring.reporter = reporter;
+        Ok(())
+    })
+}
+
+#[query(name = "getScoreConfig")]
+#[candid_method(query, rename = "getScoreConfig")]
+fn get_score_config() -> ScoreConfig {
+    SCORING.with(|s| s.borrow().config.clone())
+}
+
+// Called by the node canister for every payout, counted for the worker's
+// leader at the time of the payout.
+#[update(name = "reportPayout", guard = "_is_payout_reporter")]
+#[candid_method(update, rename = "reportPayout")]
+fn report_payout(worker: Principal, amount: Nat) -> Result<(), String> {
+    let leader = match TEAMS.with(|t| t.borrow().member_of.get(&worker).copied()) {
+        Some(leader) => leader,
+        None => return Ok(()),
+    };
+    let amount = u64::try_from(&amount.0).unwrap_or(u64::MAX);
+    _record_payout(leader, amount, ic_cdk::api::time());
+    Ok(())
+}
+
+#[query(name = "getScore")]
+#[candid_method(query, rename = "getScore")]
+fn get_score(leader: Principal) -> Option<LeaderScore> {
+    let number = PROFILE_STORE.with(|p| match p.borrow().get(&leader) {
+        Some(profile) if profile.status == ProfileStatus::Passed => Some(profile.number),
+        _ => None,
+    })?;
+    Some(_score_of(leader, number, ic_cdk::api::time()))
+}
+
+// Passed leaders, best score first
+#[query(name = "getLeaderboard")]
+#[candid_method(query, rename = "getLeaderboard")]
+fn get_leaderboard(start: usize, limit: usize) -> Vec<LeaderScore> {
+    let mut scores = _passed_scores(ic_cdk::api::time());
+    scores.sort_by(|a, b| b.score.cmp(&a.score).then(a.number.cmp(&b.number)));
+    scores.into_iter().skip(start).take(limit).collect()
+}
+
+// Suspends the passed leaders scoring below suspend_below that are out of
+// their grace period, returns them.
+#[update(name = "suspendLowScorers", guard = "_can_update_profile")]
+#[candid_method(update, rename = "suspendLowScorers")]
+fn suspend_low_scorers() -> Vec<Principal> {
+    let now = ic_cdk::api::time();
+    let config = SCORING.with(|s| s.borrow().config.clone());
+    let low: Vec<Principal> = _passed_scores(now)
+        .into_iter()
+        .filter(|s| s.score < config.suspend_below)
+        .map(|s| s.leader)
+        .collect();
+    let mut suspended = Vec::new();
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        for leader in low {
+            let p = match store.get_mut(&leader) {
+                Some(p) => p,
+                None => continue,
+            };
+            if _in_grace_period(p, config.grace_period, now) {
+                continue;
+            }
+            p.status = ProfileStatus::Suspended;
+            p.reviewed_by = Some(ic_cdk::api::caller());
+            p.reviewed_at = Some(now);
+            _record_status(
+                ic_cdk::api::caller(),
+                leader,
+                Some(ProfileStatus::Passed),
+                ProfileStatus::Suspended,
+                "score below threshold".to_string(),
+                ic_cdk::api::time(),
+            );
+            suspended.push(leader);
+        }
+    });
+    suspended
+}
+
+// Gives a suspended leader back their number
+#[update(name = "reinstate", guard = "_can_review")]
+#[candid_method(update)]
+fn reinstate(account: Principal) -> Result<(), String> {
+    _reinstate(account, ic_cdk::api::caller(), ic_cdk::api::time())
+}
+
+// The term is kept, a term that ended while suspended expires on the next sweep
+fn _reinstate(account: Principal, actor: Principal, now: u64) -> Result<(), String> {
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        match store.get_mut(&account) {
+            Some(p) if p.status == ProfileStatus::Suspended => {
+                p.status = ProfileStatus::Passed;
+                p.reviewed_by = Some(actor);
+                p.reviewed_at = Some(now);
+                _record_status(
+                    actor,
+                    account,
+                    Some(ProfileStatus::Suspended),
+                    ProfileStatus::Passed,
+                    String::new(),
+                    now,
+                );
+                NUMBER_INDEX.with(|i| {
+                    let mut index = i.borrow_mut();
+                    index.insert(p.number, account);
+                });
+                // its entry was dropped if it fell due while suspended
+                _index_expiry(account, p.expires_at);
+                Ok(())
+            }
+            Some(_) => Err("not suspended".to_string()),
+            None => Err("wallet address not found".to_string()),
+        }
+    })
+}
+
+// the applicant can read their own history
+#[query(name = "getProfileHistory")]
+#[candid_method(query, rename = "getProfileHistory")]
+fn get_profile_history(account: Principal) -> Result<Vec<ProfileEvent>, String> {
+    if ic_cdk::api::caller() != account {
+        _can_view()?;
+    }
+    AUDIT_LOG.with(|a| Ok(a.borrow().history_of(account)))
+}
+
+#[query(name = "getAuditLog", guard = "_can_view")]
+#[candid_method(query, rename = "getAuditLog")]
+fn get_audit_log(start: usize, limit: usize) -> Vec<ProfileEvent> {
+    AUDIT_LOG.with(|a| a.borrow().page(start, limit))
+}
+
+#[query(name = "getRefunds", guard = "_can_view")]
+#[candid_method(query, rename = "getRefunds")]
+fn get_refunds() -> Vec<PendingRefund> {
+    REFUNDS.with(|r| {
+        let refunds = r.borrow();
+        refunds.pending.values().cloned().collect()
+    })
+}
+
+#[query(name = "getRenewalCredits", guard = "_can_view")]
+#[candid_method(query, rename = "getRenewalCredits")]
+fn get_renewal_credits() -> Vec<(Principal, Nat)> {
+    RENEWAL_CREDITS.with(|r| r.borrow().clone().into_iter().collect())
+}
+
+// Drops the credit of `account` once it was refunded, returns it
+#[update(name = "clearRenewalCredit", guard = "_is_auth")]
+#[candid_method(update, rename = "clearRenewalCredit")]
+fn clear_renewal_credit(account: Principal) -> Result<Nat, String> {
+    RENEWAL_CREDITS
+        .with(|r| r.borrow_mut().remove(&account))
+        .ok_or_else(|| "credit not found".to_string())
+}
+
+#[update(name = "retryRefund", guard = "_is_auth")]
+#[candid_method(update, rename = "retryRefund")]
+async fn retry_refund(id: u64) -> Result<BlockIndex, String> {
+    _send_refund(id).await
+}
+
+#[query(name = "getCyclesInfo")]
+#[candid_method(query, rename = "getCyclesInfo")]
+fn get_cycles_info() -> CyclesInfo {
+    CYCLES.with(|c| c.borrow().info(ic_cdk::api::canister_balance()))
+}
+
+// None turns automatic top-ups off
+#[update(name = "setTopUpConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setTopUpConfig")]
+fn set_top_up_config(config: Option<TopUpConfig>) -> Result<(), String> {
+    CYCLES.with(|c| c.borrow_mut().set_config(config));
+    Ok(())
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    _expire_terms();
+    _refund_heartbeat().await;
+    cycles::heartbeat(&CYCLES).await;
+}
+
+// Moves the passed leaders past their term and grace period to Expired and
+// frees their number in the directory.
+fn _expire_terms() {
+    let now = ic_cdk::api::time();
+    let due = LAST_TERM_SWEEP.with(|l| {
+        let mut last = l.borrow_mut();
+        if now < *last + TERM_SWEEP_INTERVAL {
+            return false;
+        }
+        *last = now;
+        true
+    });
+    let grace_period = match STATS.with(|s| s.borrow().term.clone()) {
+        Some(term) if due => term.grace_period,
+        _ => return,
+    };
+    _expire_ended_terms(grace_period, ic_cdk::api::id(), now);
+}
+
+// Expires the passed leaders whose term and grace period are over, only the
+// due entries of the expiry index are looked at
+fn _expire_ended_terms(grace_period: u64, actor: Principal, now: u64) {
+    let cutoff = match now.checked_sub(grace_period) {
+        Some(cutoff) => cutoff,
+        None => return,
+    };
+    let due: Vec<(u64, Principal)> = EXPIRY_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        let due: Vec<(u64, Principal)> = index
+            .iter()
+            .take_while(|(expires_at, _)| *expires_at <= cutoff)
+            .copied()
+            .collect();
+        for entry in &due {
+            index.remove(entry);
+        }
+        due
+    });
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        for (expires_at, account) in due {
+            let p = match store.get_mut(&account) {
+                Some(p)
+                    if p.status == ProfileStatus::Passed && p.expires_at == Some(expires_at) =>
+                {
+                    p
+                }
+                _ => continue,
+            };
+            p.status = ProfileStatus::Expired;
+            NUMBER_INDEX.with(|i| {
+                let mut index = i.borrow_mut();
+                if index.get(&p.number) == Some(&account) {
+                    index.remove(&p.number);
+                }
+            });
+            _record_status(
+                actor,
+                account,
+                Some(ProfileStatus::Passed),
+                ProfileStatus::Expired,
+                "term expired".to_string(),
+                now,
+            );
+        }
+    });
+}
+
+// Retries the queued refunds that are due
+async fn _refund_heartbeat() {
+    let now = ic_cdk::api::time();
+    let due: Vec<u64> = REFUNDS.with(|r| {
+        let refunds = r.borrow();
+        refunds
+            .pending
+            .values()
+            .filter(|p| !p.in_flight && now >= p.last_attempt + REFUND_RETRY_INTERVAL)
+            .take(REFUNDS_PER_BEAT)
+            .map(|p| p.id)
+            .collect()
+    });
+    for id in due {
+        let _ = _send_refund(id).await;
+    }
+}
+
+// refuses the profile and returns the refund owed for it, only the fee of
+// a pending application is refunded
+fn _refuse(
+    account: Principal,
+    comment: String,
+    actor: Principal,
+    now: u64,
+) -> Result<Option<u64>, String> {
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        let p = match store.get_mut(&account) {
+            Some(p) => p,
+            None => return Err("wallet address not found".to_string()),
+        };
+        let old_status = p.status.clone();
+        p.status = ProfileStatus::Refused;
+        p.comment = comment.clone();
+        p.reviewed_by = Some(actor);
+        p.reviewed_at = Some(now);
+        _record_status(
+            actor,
+            account,
+            Some(old_status.clone()),
+            ProfileStatus::Refused,
+            comment,
+            now,
+        );
+        if old_status != ProfileStatus::Pending {
+            return Ok(None);
+        }
+        let refund = p.paid_fee.and_then(_refund_amount);
+        if refund.is_some() {
+            p.paid_fee = None;
+        }
+        Ok(refund)
+    })
+}
+
+fn _check_refund_setup(
+    policy: &Option<RefundPolicy>,
+    fee_to: Principal,
+    canister: Principal,
+) -> Result<(), String> {
+    match policy {
+        Some(RefundPolicy::Partial(bps)) if *bps > 10000 => {
+            Err("invalid refund policy".to_string())
+        }
+        Some(_) if fee_to != canister => Err("fee_to is not this canister".to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn _refund_amount(paid: u64) -> Option<u64> {
+    let policy = STATS.with(|s| s.borrow().refund_policy.clone());
+    let amount = match policy {
+        Some(RefundPolicy::Full) => paid,
+        Some(RefundPolicy::Partial(bps)) => (paid as u128 * bps as u128 / 10000) as u64,
+        None => return None,
+    };
+    // nothing is left once the ledger fee is paid
+    if amount <= DEFAULT_FEE.e8s() {
+        return None;
+    }
+    Some(amount)
+}
+
+fn _queue_refund(account: Principal, amount: u64) -> u64 {
+    REFUNDS.with(|r| {
+        let mut refunds = r.borrow_mut();
+        let id = refunds.next_id;
+        refunds.next_id += 1;
+        refunds.pending.insert(
+            id,
+            PendingRefund {
+                id,
+                account,
+                amount,
+                created_at: ic_cdk::api::time(),
+                attempts: 0,
+                last_attempt: 0,
+                last_error: None,
+                in_flight: false,
+            },
+        );
+        id
+    })
+}
+
+async fn _send_refund(id: u64) -> Result<BlockIndex, String> {
+    let refund = REFUNDS.with(|r| {
+        let mut refunds = r.borrow_mut();
+        match refunds.pending.get_mut(&id) {
+            Some(p) if p.in_flight => Err("refund in progress".to_string()),
+            Some(p) => {
+                p.in_flight = true;
+                p.attempts += 1;
+                p.last_attempt = ic_cdk::api::time();
+                Ok(p.clone())
+            }
+            None => Err("refund not found".to_string()),
+        }
+    })?;
+    let ledger = STATS.with(|s| s.borrow().ledger);
+    let args = TransferArgs {
+        memo: Memo(id),
+        amount: Tokens::from_e8s(refund.amount - DEFAULT_FEE.e8s()),
+        fee: DEFAULT_FEE,
+        from_subaccount: None,
+        to: AccountIdentifier::new(&refund.account, &DEFAULT_SUBACCOUNT),
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: refund.created_at,
+        }),
+    };
+    let mut too_old = false;
+    let res = match transfer(ledger, args).await {
+        Ok(Ok(block_index)) => Ok(block_index),
+        // an earlier attempt went through
+        Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+        Ok(Err(e)) => {
+            too_old = matches!(e, TransferError::TxTooOld { .. });
+            Err(format!("{:?}", e))
+        }
+        Err((code, msg)) => Err(format!("{:?}: {}", code, msg)),
+    };
+
+    REFUNDS.with(|r| {
+        let mut refunds = r.borrow_mut();
+        match &res {
+            Ok(_) => {
+                refunds.pending.remove(&id);
+            }
+            Err(e) => {
+                if let Some(p) = refunds.pending.get_mut(&id) {
+                    p.in_flight = false;
+                    p.last_error = Some(e.clone());
+                    // out of the ledger's deduplication window, no earlier
+                    // attempt went through
+                    if too_old {
+                        p.created_at = ic_cdk::api::time();
+                    }
+                }
+            }
+        }
+    });
+    if let Ok(block_index) = res {
+        PROFILE_STORE.with(|profile_store| {
+            let mut store = profile_store.borrow_mut();
+            if let Some(p) = store.get_mut(&refund.account) {
+                // the applicant may have applied again meanwhile
+                if p.status == ProfileStatus::Refused {
+                    p.refund_block = Some(block_index);
+                }
+            }
+        });
+    }
+    res
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     let stats = STATS.with(|s| s.borrow().clone());
     let profile_store = PROFILE_STORE.with(|a| a.borrow().clone());
     let charge_block = CHARGE_BLOCK.with(|a| a.borrow().clone());
-    let last_number = LAST_NUMBER.with(|a| a.borrow().clone());
-    ic_cdk::storage::stable_save((stats, profile_store, charge_block, last_number)).unwrap();
+    let last_number = LAST_NUMBER.with(|a| *a.borrow());
+    let cycles = CYCLES.with(|c| c.borrow().clone());
+    let roles = ROLES.with(|r| r.borrow().clone());
+    let audit_log = AUDIT_LOG.with(|a| a.borrow().clone());
+    let refunds = REFUNDS.with(|r| r.borrow().clone());
+    let deposits = DEPOSITS.with(|d| d.borrow().clone());
+    let sweeps = SWEEPS.with(|s| s.borrow().clone());
+    let directory = DIRECTORY.with(|d| d.borrow().clone());
+    let teams = TEAMS.with(|t| t.borrow().clone());
+    let scoring = SCORING.with(|s| s.borrow().clone());
+    let renewal_credits = RENEWAL_CREDITS.with(|r| r.borrow().clone());
+    ic_cdk::storage::stable_save((
+        stats,
+        profile_store,
+        charge_block,
+        last_number,
+        cycles,
+        roles,
+        audit_log,
+        refunds,
+        deposits,
+        directory,
+        teams,
+        scoring,
+        sweeps,
+        renewal_credits,
+    ))
+    .unwrap();
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (stats_stored, profile_stored, charge_block_stored, last_number_stored) =
-        ic_cdk::storage::stable_restore().unwrap();
+    let (
+        stats_stored,
+        profile_stored,
+        charge_block_stored,
+        last_number_stored,
+        cycles_stored,
+        roles_stored,
+        audit_log_stored,
+        refunds_stored,
+        deposits_stored,
+        directory_stored,
+        teams_stored,
+        scoring_stored,
+        sweeps_stored,
+        renewal_credits_stored,
+    ) = ic_cdk::storage::stable_restore().unwrap();
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         *stats = stats_stored;
@@ -336,6 +1900,49 @@ fn post_upgrade() {
         let mut last_number = p.borrow_mut();
         *last_number = last_number_stored;
     });
+    CYCLES.with(|c| {
+        let mut cycles = c.borrow_mut();
+        *cycles = Option::<CyclesMonitor>::unwrap_or_default(cycles_stored);
+    });
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        *roles = Option::<RoleStore>::unwrap_or_default(roles_stored);
+    });
+    AUDIT_LOG.with(|a| {
+        let mut audit_log = a.borrow_mut();
+        *audit_log = Option::<AuditLog>::unwrap_or_default(audit_log_stored);
+    });
+    REFUNDS.with(|r| {
+        let mut refunds = r.borrow_mut();
+        *refunds = Option::<RefundQueue>::unwrap_or_default(refunds_stored);
+    });
+    DEPOSITS.with(|d| {
+        let mut deposits = d.borrow_mut();
+        *deposits = Option::<DepositStore>::unwrap_or_default(deposits_stored);
+    });
+    DIRECTORY.with(|d| {
+        let mut directory = d.borrow_mut();
+        *directory = Option::<DirectorySettings>::unwrap_or_default(directory_stored);
+    });
+    TEAMS.with(|t| {
+        let mut teams = t.borrow_mut();
+        *teams = Option::<Teams>::unwrap_or_default(teams_stored);
+    });
+    SCORING.with(|s| {
+        let mut scoring = s.borrow_mut();
+        *scoring = Option::<Scoring>::unwrap_or_default(scoring_stored);
+    });
+    SWEEPS.with(|s| {
+        let mut sweeps = s.borrow_mut();
+        *sweeps = Option::<PendingSweeps>::unwrap_or_default(sweeps_stored);
+    });
+    RENEWAL_CREDITS.with(|r| {
+        let mut renewal_credits = r.borrow_mut();
+        *renewal_credits = Option::<RenewalCredits>::unwrap_or_default(renewal_credits_stored);
+    });
+    _build_number_index();
+    _build_status_index();
+    _build_expiry_index();
 }
 
 async fn query_one_block(ledger: Principal, block_index: BlockIndex) -> CallResult<Option<Block>> {
@@ -346,17 +1953,16 @@ async fn query_one_block(ledger: Principal, block_index: BlockIndex) -> CallResu
 
     let blocks_result = query_blocks(ledger, args.clone()).await?;
 
-    if blocks_result.blocks.len() >= 1 {
+    if !blocks_result.blocks.is_empty() {
         debug_assert_eq!(blocks_result.first_block_index, block_index);
         return Ok(blocks_result.blocks.into_iter().next());
     }
 
     if let Some(func) = blocks_result.archived_blocks.into_iter().find_map(|b| {
-        (b.start <= block_index && (block_index - b.start) < b.length).then(|| b.callback)
+        (b.start <= block_index && (block_index - b.start) < b.length).then_some(b.callback)
     }) {
-        match query_archived_blocks(&func, args).await? {
-            Ok(range) => return Ok(range.blocks.into_iter().next()),
-            _ => (),
+        if let Ok(range) = query_archived_blocks(&func, args).await? {
+            return Ok(range.blocks.into_iter().next());
         }
     }
     Ok(None)
@@ -411,6 +2017,69 @@ async fn check_charge_block(
     Ok(amount.e8s())
 }
 
+// Adds swept e8s to the credit of `account`, returns the credit
+fn _credit_deposit(account: Principal, amount: u64) -> u64 {
+    DEPOSITS.with(|d| {
+        let mut deposits = d.borrow_mut();
+        let credit = deposits.entry(account).or_default();
+        *credit += amount;
+        *credit
+    })
+}
+
+fn take_charge(caller: Principal, charge: &Charge) -> Result<(), String> {
+    match charge {
+        Charge::Block(block_index) => save_charge_block(*block_index),
+        Charge::Deposit(amount) => DEPOSITS.with(|d| {
+            let mut deposits = d.borrow_mut();
+            match deposits.get_mut(&caller) {
+                Some(credit) if *credit >= *amount => {
+                    *credit -= *amount;
+                    Ok(())
+                }
+                _ => Err("insufficient deposit".to_string()),
+            }
+        }),
+        // already pulled
+        Charge::Token => Ok(()),
+    }
+}
+
+async fn pull_token_fee(
+    token: Principal,
+    from: Principal,
+    to: Principal,
+    fee: Nat,
+) -> Result<(), String> {
+    let call_result: CallResult<(TxReceipt,)> =
+        ic_cdk::call(token, "transferFrom", (from, to, fee)).await;
+    match call_result {
+        Ok(tx) => match tx {
+            (Ok(_),) => Ok(()),
+            (Err(e),) => Err(format!("{:?}", e)),
+        },
+        Err(e) => Err(format!("{:?} {}", e.0, e.1)),
+    }
+}
+
+// Checked before a fee is taken, so none is taken for an application that
+// cannot be made.
+fn _can_apply(caller: Principal) -> Result<(), String> {
+    let closed = STATS.with(|s| s.borrow().closed);
+    if closed {
+        return Err("closed".to_string());
+    }
+    if FEE_PULLS.with(|f| f.borrow().contains(&caller)) {
+        return Err("payment in progress".to_string());
+    }
+    PROFILE_STORE.with(|profile_store| match profile_store.borrow().get(&caller) {
+        Some(p) if p.status != ProfileStatus::Refused => {
+            Err("duplicate wallet address".to_string())
+        }
+        _ => Ok(()),
+    })
+}
+
 fn save_charge_block(block_index: BlockIndex) -> Result<(), String> {
     CHARGE_BLOCK.with(|a| {
         let mut block = a.borrow_mut();
@@ -422,21 +2091,294 @@ fn save_charge_block(block_index: BlockIndex) -> Result<(), String> {
     })
 }
 
-fn _is_auth() -> Result<(), String> {
-    STATS.with(|s| {
-        let stats = s.borrow();
-        if ic_cdk::api::caller() == stats.owner {
+// value * 2^(-elapsed / half_life), linear between two halvings
+fn _decay(value: u64, elapsed: u64, half_life: u64) -> u64 {
+    if half_life == 0 {
+        return value;
+    }
+    let halvings = elapsed / half_life;
+    if halvings >= 64 {
+        return 0;
+    }
+    let value = (value >> halvings) as u128;
+    let rest = (elapsed % half_life) as u128;
+    (value - value * rest / (2 * half_life as u128)) as u64
+}
+
+// Adds a payout to the decayed total of `leader`
+fn _record_payout(leader: Principal, amount: u64, now: u64) {
+    SCORING.with(|s| {
+        let mut scoring = s.borrow_mut();
+        let half_life = scoring.config.half_life;
+        let total = scoring.payouts.entry(leader).or_default();
+        let elapsed = now.saturating_sub(total.updated_at);
+        total.amount = _decay(total.amount, elapsed, half_life).saturating_add(amount);
+        total.updated_at = now;
+    });
+}
+
+fn _score_of(leader: Principal, number: u16, now: u64) -> LeaderScore {
+    let (members, joined) = TEAMS.with(|t| match t.borrow().teams.get(&leader) {
+        Some(team) => (team.members.len() as u64, team.ever_joined),
+        None => (0, 0),
+    });
+    let retention_bps = (members * 10000).checked_div(joined).unwrap_or(10000);
+    SCORING.with(|s| {
+        let scoring = s.borrow();
+        let config = &scoring.config;
+        let payouts = match scoring.payouts.get(&leader) {
+            Some(total) => _decay(
+                total.amount,
+                now.saturating_sub(total.updated_at),
+                config.half_life,
+            ),
+            None => 0,
+        };
+        let score = (config.member_weight as u128 * members as u128
+            + config.retention_weight as u128 * retention_bps as u128 / 10000
+            + config.payout_weight as u128 * payouts as u128 / config.payout_unit.max(1) as u128)
+            .min(u64::MAX as u128) as u64;
+        LeaderScore {
+            leader,
+            number,
+            score,
+            members,
+            retention_bps,
+            payouts,
+        }
+    })
+}
+
+// A leader is not suspended for a low score until the grace period after
+// passing is over, profiles from before passed_at count from their review
+fn _in_grace_period(profile: &Profile, grace_period: u64, now: u64) -> bool {
+    let since = profile.passed_at.or(profile.reviewed_at).unwrap_or(0);
+    now < since.saturating_add(grace_period)
+}
+
+fn _passed_scores(now: u64) -> Vec<LeaderScore> {
+    let passed: Vec<(Principal, u16)> = PROFILE_STORE.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, profile)| profile.status == ProfileStatus::Passed)
+            .map(|(account, profile)| (*account, profile.number))
+            .collect()
+    });
+    passed
+        .into_iter()
+        .map(|(leader, number)| _score_of(leader, number, now))
+        .collect()
+}
+
+fn _is_payout_reporter() -> Result<(), String> {
+    let reporter = SCORING.with(|s| s.borrow().reporter);
+    if reporter == Some(ic_cdk::api::caller()) {
+        Ok(())
+    } else {
+        Err("invalid caller".to_string())
+    }
+}
+
+// Extends the term of `account` by one duration, false when it is neither
+// passed nor expired
+fn _renew_term(account: Principal, term: &TermConfig, now: u64) -> bool {
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        let p = match store.get_mut(&account) {
+            Some(p) if p.status == ProfileStatus::Passed || p.status == ProfileStatus::Expired => p,
+            _ => return false,
+        };
+        let old_status = p.status.clone();
+        let start = p.expires_at.map_or(now, |e| e.max(now));
+        p.expires_at = Some(start + term.duration);
+        _index_expiry(account, p.expires_at);
+        if old_status == ProfileStatus::Expired {
+            p.status = ProfileStatus::Passed;
+            NUMBER_INDEX.with(|i| {
+                let mut index = i.borrow_mut();
+                index.insert(p.number, account);
+            });
+        }
+        _record_status(
+            account,
+            account,
+            Some(old_status),
+            ProfileStatus::Passed,
+            "renewed".to_string(),
+            now,
+        );
+        true
+    })
+}
+
+fn _credit_renewal(account: Principal, fee: Nat) {
+    RENEWAL_CREDITS.with(|r| {
+        let mut credits = r.borrow_mut();
+        let credit = credits.entry(account).or_insert_with(|| Nat::from(0));
+        *credit += fee;
+    });
+}
+
+// Pays a renewal from the credit of `account` when it covers `fee`
+fn _spend_renewal_credit(account: Principal, fee: &Nat) -> bool {
+    RENEWAL_CREDITS.with(|r| {
+        let mut credits = r.borrow_mut();
+        match credits.get_mut(&account) {
+            Some(credit) if *credit == *fee => {
+                credits.remove(&account);
+                true
+            }
+            Some(credit) if *credit > *fee => {
+                *credit -= fee.clone();
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+// Holds the slot of `account` while its wdn fee is pulled, a second pull
+// for it is refused meanwhile
+fn _reserve_fee_pull(account: Principal) -> Result<(), String> {
+    FEE_PULLS.with(|f| {
+        if f.borrow_mut().insert(account) {
             Ok(())
         } else {
-            Err("invalid caller".to_string())
+            Err("payment in progress".to_string())
         }
     })
 }
 
-fn _is_admin() -> Result<(), String> {
+fn _release_fee_pull(account: Principal) {
+    FEE_PULLS.with(|f| f.borrow_mut().remove(&account));
+}
+
+fn _can_renew(account: Principal) -> Result<(), String> {
+    PROFILE_STORE.with(|p| match p.borrow().get(&account) {
+        Some(profile)
+            if profile.status == ProfileStatus::Passed
+                || profile.status == ProfileStatus::Expired =>
+        {
+            Ok(())
+        }
+        _ => Err("leader not found".to_string()),
+    })
+}
+
+fn _is_passed(account: Principal) -> Result<(), String> {
+    PROFILE_STORE.with(|p| match p.borrow().get(&account) {
+        Some(profile) if profile.status == ProfileStatus::Passed => Ok(()),
+        _ => Err("leader not found".to_string()),
+    })
+}
+
+// None once the profile is no longer passed under this number, the index
+// keeps such entries until the number is given again
+fn _leader_info(number: u16, account: Principal) -> Option<LeaderInfo> {
+    let profile = PROFILE_STORE.with(|p| p.borrow().get(&account).cloned())?;
+    if profile.status != ProfileStatus::Passed || profile.number != number {
+        return None;
+    }
+    let visibility = DIRECTORY.with(|d| d.borrow().get(&account).cloned().unwrap_or_default());
+    Some(LeaderInfo {
+        number,
+        community_name: profile.community_name,
+        wallet_address: profile.wallet_address,
+        discord_account: visibility.show_discord.then_some(profile.discord_account),
+        comment: visibility.show_comment.then_some(profile.comment),
+    })
+}
+
+fn _profile_page<'a>(
+    mut matching: impl Iterator<Item = &'a Profile>,
+    limit: usize,
+    total: usize,
+) -> ProfilePage {
+    let profiles: Vec<Profile> = matching.by_ref().take(limit).cloned().collect();
+    let next = match (profiles.last(), matching.next()) {
+        (Some(last), Some(_)) => Some(last.wallet_address),
+        _ => None,
+    };
+    ProfilePage {
+        profiles,
+        next,
+        total: total as u64,
+    }
+}
+
+// Every status change goes through here, it is audited and indexed
+fn _record_status(
+    actor: Principal,
+    account: Principal,
+    old_status: Option<ProfileStatus>,
+    new_status: ProfileStatus,
+    comment: String,
+    now: u64,
+) {
+    STATUS_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        if let Some(accounts) = old_status.as_ref().and_then(|s| index.get_mut(s)) {
+            accounts.remove(&account);
+        }
+        index.entry(new_status.clone()).or_default().insert(account);
+    });
+    AUDIT_LOG.with(|a| {
+        let mut log = a.borrow_mut();
+        log.record_as(actor, account, old_status, new_status, comment, now);
+    });
+}
+
+fn _index_expiry(account: Principal, expires_at: Option<u64>) {
+    if let Some(expires_at) = expires_at {
+        EXPIRY_INDEX.with(|i| i.borrow_mut().insert((expires_at, account)));
+    }
+}
+
+fn _build_expiry_index() {
+    EXPIRY_INDEX.with(|i| i.borrow_mut().clear());
+    PROFILE_STORE.with(|p| {
+        for (account, profile) in p.borrow().iter() {
+            if profile.status == ProfileStatus::Passed {
+                _index_expiry(*account, profile.expires_at);
+            }
+        }
+    });
+}
+
+fn _build_status_index() {
+    STATUS_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        index.clear();
+        PROFILE_STORE.with(|p| {
+            for (account, profile) in p.borrow().iter() {
+                index
+                    .entry(profile.status.clone())
+                    .or_default()
+                    .insert(*account);
+            }
+        });
+    });
+}
+
+fn _build_number_index() {
+    NUMBER_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        index.clear();
+        PROFILE_STORE.with(|p| {
+            for (account, profile) in p.borrow().iter() {
+                if profile.status == ProfileStatus::Passed {
+                    index.insert(profile.number, *account);
+                }
+            }
+        });
+    });
+}
+
+fn _is_auth() -> Result<(), String> {
     STATS.with(|s| {
         let stats = s.borrow();
-        if ic_cdk::api::caller() == stats.admin {
+        let caller = ic_cdk::api::caller();
+        if caller == stats.owner || Some(caller) == stats.governance {
             Ok(())
         } else {
             Err("invalid caller".to_string())
@@ -444,6 +2386,47 @@ fn _is_admin() -> Result<(), String> {
     })
 }
 
+// The owner always holds Owner and the `admin` of StatsData always holds
+// Admin, next to the roles granted with grantRole.
+fn _role_of(account: Principal) -> Option<Role> {
+    let (owner, admin) = STATS.with(|s| {
+        let stats = s.borrow();
+        (stats.owner, stats.admin)
+    });
+    if account == owner {
+        return Some(Role::Owner);
+    }
+    let granted = ROLES.with(|r| r.borrow().get(&account).map(|g| g.role));
+    match granted {
+        Some(role) => Some(role),
+        None if account == admin => Some(Role::Admin),
+        None => None,
+    }
+}
+
+fn _has_permission(permission: Permission) -> Result<(), String> {
+    match _role_of(ic_cdk::api::caller()) {
+        Some(role) if role.allows(permission) => Ok(()),
+        _ => Err("invalid caller".to_string()),
+    }
+}
+
+fn _can_manage_roles() -> Result<(), String> {
+    _is_auth().or_else(|_| _has_permission(Permission::ManageRoles))
+}
+
+fn _can_update_profile() -> Result<(), String> {
+    _has_permission(Permission::UpdateProfile)
+}
+
+fn _can_review() -> Result<(), String> {
+    _has_permission(Permission::Review)
+}
+
+fn _can_view() -> Result<(), String> {
+    _has_permission(Permission::View)
+}
+
 #[cfg(not(any(target_arch = "wasm32", test)))]
 fn main() {
     // The line below generates did types and service definition from the
@@ -455,3 +2438,645 @@ fn main() {
 
 #[cfg(any(target_arch = "wasm32", test))]
 fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(i: u8) -> Principal {
+        Principal::from_slice(&[i, 1])
+    }
+
+    fn grant(account: Principal, role: Role) {
+        ROLES.with(|r| {
+            r.borrow_mut().insert(
+                account,
+                RoleGrant {
+                    role,
+                    granted_by: Principal::anonymous(),
+                    granted_at: 0,
+                },
+            )
+        });
+    }
+
+    fn profile(account: Principal, status: ProfileStatus) -> Profile {
+        Profile {
+            community_name: "community".to_string(),
+            discord_account: "discord".to_string(),
+            wallet_address: account,
+            status,
+            comment: String::new(),
+            number: 0,
+            reviewed_by: None,
+            reviewed_at: None,
+            paid_fee: None,
+            refund_block: None,
+            passed_at: None,
+            expires_at: None,
+        }
+    }
+
+    fn add_profile(profile: Profile) {
+        STATUS_INDEX.with(|i| {
+            let mut index = i.borrow_mut();
+            for accounts in index.values_mut() {
+                accounts.remove(&profile.wallet_address);
+            }
+            let accounts = index.entry(profile.status.clone()).or_default();
+            accounts.insert(profile.wallet_address);
+        });
+        PROFILE_STORE.with(|p| p.borrow_mut().insert(profile.wallet_address, profile));
+    }
+
+    fn status_of(account: Principal) -> ProfileStatus {
+        PROFILE_STORE.with(|p| p.borrow()[&account].status.clone())
+    }
+
+    #[test]
+    fn roles_follow_the_permission_matrix() {
+        use Permission::*;
+        for permission in [ManageRoles, UpdateProfile, Review, View] {
+            assert!(Role::Owner.allows(permission));
+        }
+        assert!(!Role::Admin.allows(ManageRoles));
+        assert!(Role::Admin.allows(UpdateProfile));
+        assert!(!Role::Reviewer.allows(UpdateProfile));
+        assert!(Role::Reviewer.allows(Review));
+        assert!(Role::Reviewer.allows(View));
+        assert!(!Role::Viewer.allows(Review));
+        assert!(Role::Viewer.allows(View));
+    }
+
+    #[test]
+    fn owner_and_admin_hold_their_roles_next_to_grants() {
+        STATS.with(|s| {
+            let mut stats = s.borrow_mut();
+            stats.owner = account(1);
+            stats.admin = account(2);
+        });
+        grant(account(3), Role::Reviewer);
+        assert_eq!(_role_of(account(1)), Some(Role::Owner));
+        assert_eq!(_role_of(account(2)), Some(Role::Admin));
+        assert_eq!(_role_of(account(3)), Some(Role::Reviewer));
+        assert_eq!(_role_of(account(4)), None);
+
+        // a grant replaces the implicit Admin, never the owner's role
+        grant(account(1), Role::Viewer);
+        grant(account(2), Role::Viewer);
+        assert_eq!(_role_of(account(1)), Some(Role::Owner));
+        assert_eq!(_role_of(account(2)), Some(Role::Viewer));
+    }
+
+    #[test]
+    fn audit_log_keeps_every_change_per_account() {
+        let mut log = AuditLog::default();
+        log.record_as(
+            account(9),
+            account(1),
+            None,
+            ProfileStatus::Pending,
+            String::new(),
+            1,
+        );
+        log.record_as(
+            account(9),
+            account(2),
+            None,
+            ProfileStatus::Pending,
+            String::new(),
+            2,
+        );
+        log.record_as(
+            account(8),
+            account(1),
+            Some(ProfileStatus::Pending),
+            ProfileStatus::Refused,
+            "incomplete".to_string(),
+            3,
+        );
+
+        let history = log.history_of(account(1));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].index, 2);
+        assert_eq!(history[1].old_status, Some(ProfileStatus::Pending));
+        assert_eq!(history[1].actor, account(8));
+        assert_eq!(history[1].comment, "incomplete");
+        assert!(log.history_of(account(3)).is_empty());
+
+        assert_eq!(log.page(1, 5).len(), 2);
+        assert_eq!(log.page(1, 5)[0].account, account(2));
+        assert!(log.page(3, 5).is_empty());
+        assert!(log.page(usize::MAX, usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn refunds_are_paid_from_the_canister() {
+        let canister = account(100);
+        let policy = Some(RefundPolicy::Full);
+        assert!(_check_refund_setup(&policy, account(1), canister).is_err());
+        assert!(_check_refund_setup(&policy, canister, canister).is_ok());
+        assert!(_check_refund_setup(&None, account(1), canister).is_ok());
+        let partial = Some(RefundPolicy::Partial(10001));
+        assert!(_check_refund_setup(&partial, canister, canister).is_err());
+    }
+
+    #[test]
+    fn only_refusals_of_pending_applications_are_refunded() {
+        STATS.with(|s| s.borrow_mut().refund_policy = Some(RefundPolicy::Partial(5000)));
+        let fee = DEFAULT_FEE.e8s();
+        for (i, status) in [ProfileStatus::Pending, ProfileStatus::Passed]
+            .iter()
+            .enumerate()
+        {
+            add_profile(Profile {
+                paid_fee: Some(100 * fee),
+                ..profile(account(i as u8), status.clone())
+            });
+        }
+
+        assert_eq!(
+            _refuse(account(0), "no".to_string(), account(9), 5),
+            Ok(Some(50 * fee))
+        );
+        // the fee is refunded once
+        assert_eq!(
+            _refuse(account(0), "no".to_string(), account(9), 6),
+            Ok(None)
+        );
+        assert_eq!(
+            _refuse(account(1), "no".to_string(), account(9), 7),
+            Ok(None)
+        );
+        assert_eq!(status_of(account(1)), ProfileStatus::Refused);
+        assert!(_refuse(account(2), String::new(), account(9), 8).is_err());
+
+        let history = AUDIT_LOG.with(|a| a.borrow().history_of(account(0)));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].actor, account(9));
+        assert_eq!(history[0].timestamp, 5);
+    }
+
+    #[test]
+    fn refunds_leave_the_ledger_fee_out() {
+        STATS.with(|s| s.borrow_mut().refund_policy = Some(RefundPolicy::Partial(100)));
+        let fee = DEFAULT_FEE.e8s();
+        assert_eq!(_refund_amount(200 * fee), Some(2 * fee));
+        assert_eq!(_refund_amount(100 * fee), None);
+        STATS.with(|s| s.borrow_mut().refund_policy = None);
+        assert_eq!(_refund_amount(200 * fee), None);
+    }
+
+    fn apply(account: Principal, charge: Charge) -> Result<(), String> {
+        _apply(
+            account,
+            charge,
+            Some(100),
+            "community".to_string(),
+            "discord".to_string(),
+            5,
+        )
+    }
+
+    #[test]
+    fn deposit_applications_spend_the_confirmed_credit() {
+        DEPOSITS.with(|d| d.borrow_mut().insert(account(1), 150));
+
+        assert_eq!(
+            apply(account(2), Charge::Deposit(100)),
+            Err("insufficient deposit".to_string())
+        );
+        assert!(PROFILE_STORE.with(|p| p.borrow().get(&account(2)).is_none()));
+
+        assert_eq!(apply(account(1), Charge::Deposit(100)), Ok(()));
+        assert_eq!(status_of(account(1)), ProfileStatus::Pending);
+        assert_eq!(DEPOSITS.with(|d| d.borrow()[&account(1)]), 50);
+
+        // a pending application keeps the rest of the credit
+        assert_eq!(
+            apply(account(1), Charge::Deposit(50)),
+            Err("duplicate wallet address".to_string())
+        );
+        assert_eq!(DEPOSITS.with(|d| d.borrow()[&account(1)]), 50);
+    }
+
+    #[test]
+    fn refused_applicants_apply_again() {
+        add_profile(profile(account(1), ProfileStatus::Refused));
+        assert_eq!(apply(account(1), Charge::Block(7)), Ok(()));
+        assert_eq!(status_of(account(1)), ProfileStatus::Pending);
+        assert_eq!(
+            PROFILE_STORE.with(|p| p.borrow()[&account(1)].paid_fee),
+            Some(100)
+        );
+
+        // the block pays once
+        add_profile(profile(account(2), ProfileStatus::Refused));
+        assert_eq!(
+            apply(account(2), Charge::Block(7)),
+            Err("block_index already used".to_string())
+        );
+        assert_eq!(status_of(account(2)), ProfileStatus::Refused);
+
+        let events = AUDIT_LOG.with(|a| a.borrow().history_of(account(1)));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].old_status, Some(ProfileStatus::Refused));
+    }
+
+    #[test]
+    fn a_reserved_slot_is_filed_once_paid() {
+        _reserve_fee_pull(account(1)).unwrap();
+        assert_eq!(
+            _reserve_fee_pull(account(1)),
+            Err("payment in progress".to_string())
+        );
+        assert_eq!(
+            apply(account(1), Charge::Deposit(0)),
+            Err("payment in progress".to_string())
+        );
+
+        // closing while the fee is pulled does not lose the paid application
+        STATS.with(|s| s.borrow_mut().closed = true);
+        _release_fee_pull(account(1));
+        _file_application(
+            account(1),
+            Charge::Token,
+            None,
+            "community".to_string(),
+            "discord".to_string(),
+            5,
+        )
+        .unwrap();
+        assert_eq!(status_of(account(1)), ProfileStatus::Pending);
+        assert_eq!(_reserve_fee_pull(account(1)), Ok(()));
+    }
+
+    #[test]
+    fn renewals_extend_the_term() {
+        let term = TermConfig {
+            duration: 100,
+            grace_period: 10,
+            renewal_fee: Nat::from(1),
+        };
+        let mut leader = profile(account(1), ProfileStatus::Passed);
+        leader.expires_at = Some(500);
+        add_profile(leader);
+        _renew_term(account(1), &term, 450);
+        assert_eq!(
+            PROFILE_STORE.with(|p| p.borrow()[&account(1)].expires_at),
+            Some(600)
+        );
+
+        // an expired leader is passed again from now
+        let mut leader = profile(account(2), ProfileStatus::Expired);
+        leader.number = 3;
+        leader.expires_at = Some(500);
+        add_profile(leader);
+        _renew_term(account(2), &term, 700);
+        assert_eq!(status_of(account(2)), ProfileStatus::Passed);
+        assert_eq!(
+            PROFILE_STORE.with(|p| p.borrow()[&account(2)].expires_at),
+            Some(800)
+        );
+        assert_eq!(
+            NUMBER_INDEX.with(|i| i.borrow().get(&3).cloned()),
+            Some(account(2))
+        );
+    }
+
+    #[test]
+    fn profile_pages_follow_the_cursor() {
+        for i in 1..=5 {
+            let status = if i % 2 == 0 {
+                ProfileStatus::Refused
+            } else {
+                ProfileStatus::Pending
+            };
+            add_profile(profile(account(i), status));
+        }
+        let pending = ProfileFilter {
+            status: Some(ProfileStatus::Pending),
+            ..Default::default()
+        };
+
+        let page = list_profiles(pending.clone(), None, 2);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.profiles.len(), 2);
+        let page = list_profiles(pending.clone(), page.next, 2);
+        assert_eq!(page.profiles.len(), 1);
+        assert_eq!(page.next, None);
+
+        let page = list_profiles(ProfileFilter::default(), None, 1000);
+        assert_eq!(page.total, 5);
+        assert_eq!(page.next, None);
+
+        let filter = ProfileFilter {
+            community_name: Some("COMM".to_string()),
+            ..pending
+        };
+        assert_eq!(list_profiles(filter, None, 10).total, 3);
+    }
+
+    #[test]
+    fn status_changes_move_accounts_between_statuses() {
+        add_profile(profile(account(1), ProfileStatus::Pending));
+        _refuse(account(1), String::new(), account(9), 5).unwrap();
+        let refused = ProfileFilter {
+            status: Some(ProfileStatus::Refused),
+            ..Default::default()
+        };
+        assert_eq!(list_profiles(refused.clone(), None, 10).total, 1);
+
+        for i in 0..MAX_PROFILE_PAGE as u8 {
+            let p = profile(Principal::from_slice(&[i, 2]), ProfileStatus::Refused);
+            PROFILE_STORE.with(|s| s.borrow_mut().insert(p.wallet_address, p));
+        }
+        _build_status_index();
+        let page = list_profiles(refused, None, usize::MAX);
+        assert_eq!(page.total as usize, MAX_PROFILE_PAGE + 1);
+        assert_eq!(page.profiles.len(), MAX_PROFILE_PAGE);
+        assert!(page.next.is_some());
+    }
+
+    fn add_leader(account: Principal, number: u16) {
+        let mut leader = profile(account, ProfileStatus::Passed);
+        leader.number = number;
+        leader.comment = "welcome".to_string();
+        add_profile(leader);
+    }
+
+    #[test]
+    fn the_directory_lists_passed_leaders_by_number() {
+        add_leader(account(1), 2);
+        add_leader(account(2), 1);
+        // refused after the number was given
+        let mut refused = profile(account(3), ProfileStatus::Refused);
+        refused.number = 3;
+        add_profile(refused);
+        _build_number_index();
+        NUMBER_INDEX.with(|i| i.borrow_mut().insert(3, account(3)));
+
+        let leaders = get_leaders(0, 10);
+        let numbers: Vec<u16> = leaders.iter().map(|l| l.number).collect();
+        assert_eq!(numbers, vec![1, 2]);
+        assert_eq!(leaders[0].wallet_address, account(2));
+        assert!(get_leader_by_number(3).is_none());
+        assert_eq!(get_leaders(1, 10).len(), 1);
+    }
+
+    #[test]
+    fn leaders_choose_what_the_directory_shows() {
+        add_leader(account(1), 1);
+        let info = _leader_info(1, account(1)).unwrap();
+        assert_eq!(info.discord_account, None);
+        assert_eq!(info.comment, None);
+
+        DIRECTORY.with(|d| {
+            d.borrow_mut().insert(
+                account(1),
+                DirectoryVisibility {
+                    show_discord: true,
+                    show_comment: false,
+                },
+            )
+        });
+        let info = _leader_info(1, account(1)).unwrap();
+        assert_eq!(info.discord_account, Some("discord".to_string()));
+        assert_eq!(info.comment, None);
+        // a number given to someone else
+        assert!(_leader_info(2, account(1)).is_none());
+    }
+
+    fn actions(teams: &Teams, leader: Principal) -> Vec<MembershipAction> {
+        teams.teams[&leader]
+            .events
+            .iter()
+            .map(|e| e.action.clone())
+            .collect()
+    }
+
+    #[test]
+    fn membership_moves_through_invites_and_requests() {
+        use MembershipAction::*;
+        let (leader, other) = (account(1), account(2));
+        let mut teams = Teams::default();
+
+        teams.invite(leader, account(3), 1).unwrap();
+        // asked twice, the first invite stands
+        teams.invite(leader, account(3), 2).unwrap();
+        assert_eq!(teams.teams[&leader].invites[&account(3)], 1);
+        teams.accept(leader, account(3), 3).unwrap();
+        assert_eq!(teams.member_of[&account(3)], leader);
+        assert_eq!(
+            teams.request(other, account(3), 4),
+            Err("already in a team".to_string())
+        );
+
+        teams.request(leader, account(4), 5).unwrap();
+        teams.request(leader, account(4), 6).unwrap();
+        teams.decline(leader, account(4), 7).unwrap();
+        assert_eq!(
+            teams.approve(leader, account(4), 8),
+            Err("request not found".to_string())
+        );
+
+        teams.invite(leader, account(5), 9).unwrap();
+        teams.cancel_invite(leader, account(5), 10).unwrap();
+        assert_eq!(
+            teams.accept(leader, account(5), 11),
+            Err("invite not found".to_string())
+        );
+
+        teams
+            .leave(leader, account(3), Left, account(3), 12)
+            .unwrap();
+        assert_eq!(
+            actions(&teams, leader),
+            vec![Invited, Joined, Requested, Declined, Invited, Cancelled, Left]
+        );
+        assert!(teams.member_of.is_empty());
+    }
+
+    #[test]
+    fn anonymous_workers_cannot_join() {
+        let mut teams = Teams::default();
+        let anonymous = Principal::anonymous();
+        assert_eq!(
+            teams.request(account(1), anonymous, 1),
+            Err("invalid worker".to_string())
+        );
+        assert_eq!(
+            teams.invite(account(1), anonymous, 1),
+            Err("invalid worker".to_string())
+        );
+        assert_eq!(
+            teams.invite(account(1), account(1), 1),
+            Err("invalid worker".to_string())
+        );
+        assert!(teams.teams.is_empty());
+    }
+
+    #[test]
+    fn payouts_halve_every_half_life() {
+        assert_eq!(_decay(1000, 0, 100), 1000);
+        assert_eq!(_decay(1000, 100, 100), 500);
+        assert_eq!(_decay(1000, 150, 100), 375);
+        assert_eq!(_decay(1000, 64 * 100, 100), 0);
+        assert_eq!(_decay(1000, 500, 0), 1000);
+    }
+
+    #[test]
+    fn scores_count_members_retention_and_payouts() {
+        let leader = account(1);
+        TEAMS.with(|t| {
+            let mut teams = t.borrow_mut();
+            teams.join(leader, account(2), leader, 1).unwrap();
+            teams.join(leader, account(3), leader, 1).unwrap();
+            teams
+                .leave(leader, account(3), MembershipAction::Left, account(3), 2)
+                .unwrap();
+            // a rejoin counts as a join, the events are not needed for it
+            teams.join(leader, account(3), leader, 3).unwrap();
+            teams.teams.get_mut(&leader).unwrap().events.clear();
+        });
+        _record_payout(leader, 1000, 0);
+        _record_payout(leader, 1000, 30 * DAY);
+
+        let score = _score_of(leader, 1, 30 * DAY);
+        assert_eq!(score.members, 2);
+        assert_eq!(score.retention_bps, 6666);
+        assert_eq!(score.payouts, 1500);
+        assert_eq!(score.score, 10 * 2 + 66 + 1500);
+
+        let score = _score_of(account(9), 2, 0);
+        assert_eq!(score.retention_bps, 10000);
+        assert_eq!(score.score, 100);
+    }
+
+    fn set_term(duration: u64, grace_period: u64) -> TermConfig {
+        let term = TermConfig {
+            duration,
+            grace_period,
+            renewal_fee: Nat::from(1),
+        };
+        STATS.with(|s| s.borrow_mut().term = Some(term.clone()));
+        term
+    }
+
+    #[test]
+    fn profile_updates_keep_terms_and_grant_them_on_passing() {
+        set_term(100, 10);
+        let mut leader = profile(account(1), ProfileStatus::Passed);
+        leader.passed_at = Some(1);
+        leader.expires_at = Some(101);
+        add_profile(leader);
+
+        let mut update = profile(account(1), ProfileStatus::Passed);
+        update.comment = "edited".to_string();
+        _update_profile(update, account(9), 50).unwrap();
+        let p = PROFILE_STORE.with(|p| p.borrow()[&account(1)].clone());
+        assert_eq!(p.comment, "edited");
+        assert_eq!((p.passed_at, p.expires_at), (Some(1), Some(101)));
+
+        add_profile(profile(account(2), ProfileStatus::Pending));
+        _update_profile(profile(account(2), ProfileStatus::Passed), account(9), 50).unwrap();
+        let p = PROFILE_STORE.with(|p| p.borrow()[&account(2)].clone());
+        assert_eq!((p.passed_at, p.expires_at), (Some(50), Some(150)));
+        assert!(EXPIRY_INDEX.with(|i| i.borrow().contains(&(150, account(2)))));
+    }
+
+    #[test]
+    fn terms_expire_once_the_grace_period_is_over() {
+        let term = set_term(100, 10);
+        for i in 1..=3 {
+            let mut leader = profile(account(i), ProfileStatus::Passed);
+            leader.number = i as u16;
+            leader.expires_at = Some(100);
+            add_profile(leader);
+        }
+        _build_number_index();
+        _build_expiry_index();
+        // renewed, its first entry is stale
+        _renew_term(account(2), &term, 50);
+        // suspended meanwhile
+        add_profile(profile(account(3), ProfileStatus::Suspended));
+
+        _expire_ended_terms(10, account(9), 109);
+        assert_eq!(status_of(account(1)), ProfileStatus::Passed);
+
+        _expire_ended_terms(10, account(9), 110);
+        assert_eq!(status_of(account(1)), ProfileStatus::Expired);
+        assert_eq!(status_of(account(2)), ProfileStatus::Passed);
+        assert_eq!(status_of(account(3)), ProfileStatus::Suspended);
+        assert!(get_leader_by_number(1).is_none());
+        assert_eq!(
+            EXPIRY_INDEX.with(|i| i.borrow().iter().copied().collect::<Vec<_>>()),
+            vec![(200, account(2))]
+        );
+
+        _expire_ended_terms(10, account(9), 210);
+        assert_eq!(status_of(account(2)), ProfileStatus::Expired);
+        assert!(EXPIRY_INDEX.with(|i| i.borrow().is_empty()));
+    }
+
+    #[test]
+    fn grace_period_counts_from_passing() {
+        let mut p = profile(account(1), ProfileStatus::Passed);
+        p.passed_at = Some(100);
+        // a later review, e.g. a profile update, does not extend it
+        p.reviewed_at = Some(500);
+        assert!(_in_grace_period(&p, 50, 149));
+        assert!(!_in_grace_period(&p, 50, 150));
+
+        // legacy profiles count from their review
+        p.passed_at = None;
+        assert!(_in_grace_period(&p, 50, 549));
+        assert!(!_in_grace_period(&p, 50, 550));
+    }
+
+    #[test]
+    fn reinstated_leaders_expire_when_their_term_ended_meanwhile() {
+        set_term(100, 10);
+        let mut leader = profile(account(1), ProfileStatus::Passed);
+        leader.number = 1;
+        leader.expires_at = Some(100);
+        add_profile(leader);
+        _build_number_index();
+        _build_expiry_index();
+
+        let mut suspended = profile(account(1), ProfileStatus::Suspended);
+        suspended.number = 1;
+        _update_profile(suspended, account(9), 50).unwrap();
+        // due while suspended, its entry is dropped
+        _expire_ended_terms(10, account(9), 150);
+        assert_eq!(status_of(account(1)), ProfileStatus::Suspended);
+
+        _reinstate(account(1), account(9), 160).unwrap();
+        assert_eq!(status_of(account(1)), ProfileStatus::Passed);
+        _expire_ended_terms(10, account(9), 170);
+        assert_eq!(status_of(account(1)), ProfileStatus::Expired);
+        assert!(get_leader_by_number(1).is_none());
+    }
+
+    #[test]
+    fn renewals_of_leaders_no_longer_passed_are_credited() {
+        let term = set_term(100, 10);
+        let mut leader = profile(account(1), ProfileStatus::Suspended);
+        leader.expires_at = Some(100);
+        add_profile(leader);
+
+        assert!(!_renew_term(account(1), &term, 50));
+        let p = PROFILE_STORE.with(|p| p.borrow()[&account(1)].clone());
+        assert_eq!(
+            (p.status, p.expires_at),
+            (ProfileStatus::Suspended, Some(100))
+        );
+        assert!(STATUS_INDEX.with(|i| i.borrow()[&ProfileStatus::Suspended].contains(&account(1))));
+        assert!(EXPIRY_INDEX.with(|i| i.borrow().is_empty()));
+
+        _credit_renewal(account(1), term.renewal_fee.clone());
+        assert!(!_spend_renewal_credit(account(1), &Nat::from(2)));
+        assert!(_spend_renewal_credit(account(1), &term.renewal_fee));
+        assert!(!_spend_renewal_credit(account(1), &term.renewal_fee));
+    }
+}
@@ -1,41 +1,284 @@
 export const idlFactory = ({ IDL }) => {
+  const Result = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : IDL.Text });
   const ProfileStatus = IDL.Variant({
     'Passed' : IDL.Null,
+    'Suspended' : IDL.Null,
     'Refused' : IDL.Null,
+    'Expired' : IDL.Null,
     'Pending' : IDL.Null,
   });
   const Profile = IDL.Record({
     'status' : ProfileStatus,
+    'passed_at' : IDL.Opt(IDL.Nat64),
     'wallet_address' : IDL.Principal,
+    'reviewed_at' : IDL.Opt(IDL.Nat64),
+    'reviewed_by' : IDL.Opt(IDL.Principal),
     'comment' : IDL.Text,
     'discord_account' : IDL.Text,
     'community_name' : IDL.Text,
+    'paid_fee' : IDL.Opt(IDL.Nat64),
     'number' : IDL.Nat16,
+    'refund_block' : IDL.Opt(IDL.Nat64),
+    'expires_at' : IDL.Opt(IDL.Nat64),
+  });
+  const Result_1 = IDL.Variant({ 'Ok' : Profile, 'Err' : IDL.Text });
+  const ProfileEvent = IDL.Record({
+    'actor' : IDL.Principal,
+    'old_status' : IDL.Opt(ProfileStatus),
+    'comment' : IDL.Text,
+    'account' : IDL.Principal,
+    'timestamp' : IDL.Nat64,
+    'index' : IDL.Nat64,
+    'new_status' : ProfileStatus,
+  });
+  const Result_2 = IDL.Variant({
+    'Ok' : IDL.Vec(ProfileEvent),
+    'Err' : IDL.Text,
+  });
+  const PendingRefund = IDL.Record({
+    'id' : IDL.Nat64,
+    'last_error' : IDL.Opt(IDL.Text),
+    'attempts' : IDL.Nat32,
+    'created_at' : IDL.Nat64,
+    'account' : IDL.Principal,
+    'in_flight' : IDL.Bool,
+    'amount' : IDL.Nat64,
+    'last_attempt' : IDL.Nat64,
+  });
+  const PendingSweep = IDL.Record({
+    'created_at' : IDL.Nat64,
+    'reconcile' : IDL.Bool,
+    'in_flight' : IDL.Bool,
+    'amount' : IDL.Nat64,
+  });
+  const RefundPolicy = IDL.Variant({ 'Full' : IDL.Null, 'Partial' : IDL.Nat64 });
+  const PaymentAsset = IDL.Variant({
+    'Icp' : IDL.Null,
+    'Wdn' : IDL.Record({ 'fee' : IDL.Nat, 'token' : IDL.Principal }),
+  });
+  const TermConfig = IDL.Record({
+    'renewal_fee' : IDL.Nat,
+    'duration' : IDL.Nat64,
+    'grace_period' : IDL.Nat64,
   });
-  const Result = IDL.Variant({ 'Ok' : Profile, 'Err' : IDL.Text });
   const StatsData = IDL.Record({
     'fee' : IDL.Nat,
     'closed' : IDL.Bool,
     'admin' : IDL.Principal,
     'fee_to' : IDL.Principal,
     'owner' : IDL.Principal,
+    'term' : IDL.Opt(TermConfig),
     'ledger' : IDL.Principal,
+    'refund_policy' : IDL.Opt(RefundPolicy),
+    'governance' : IDL.Opt(IDL.Principal),
+    'payment_asset' : IDL.Opt(PaymentAsset),
+  });
+  const Result_3 = IDL.Variant({ 'Ok' : StatsData, 'Err' : IDL.Text });
+  const Result_4 = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
+  const Result_5 = IDL.Variant({ 'Ok' : IDL.Nat, 'Err' : IDL.Text });
+  const TopUpSource = IDL.Variant({
+    'CyclesMinting' : IDL.Record({ 'e8s' : IDL.Nat64 }),
+    'Canister' : IDL.Record({ 'cycles' : IDL.Nat64, 'canister' : IDL.Principal }),
+  });
+  const TopUpConfig = IDL.Record({
+    'source' : TopUpSource,
+    'threshold' : IDL.Nat64,
+  });
+  const CyclesSample = IDL.Record({
+    'balance' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const TopUpRecord = IDL.Record({
+    'source' : TopUpSource,
+    'error' : IDL.Opt(IDL.Text),
+    'cycles' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const CyclesInfo = IDL.Record({
+    'balance' : IDL.Nat64,
+    'burn_rate' : IDL.Nat64,
+    'last_sample' : IDL.Opt(CyclesSample),
+    'config' : IDL.Opt(TopUpConfig),
+    'top_ups' : IDL.Vec(TopUpRecord),
+  });
+  const Role = IDL.Variant({
+    'Viewer' : IDL.Null,
+    'Reviewer' : IDL.Null,
+    'Admin' : IDL.Null,
+    'Owner' : IDL.Null,
+  });
+  const RoleGrant = IDL.Record({
+    'role' : Role,
+    'granted_at' : IDL.Nat64,
+    'granted_by' : IDL.Principal,
+  });
+  const ProfileFilter = IDL.Record({
+    'status' : IDL.Opt(ProfileStatus),
+    'min_number' : IDL.Opt(IDL.Nat16),
+    'community_name' : IDL.Opt(IDL.Text),
+    'max_number' : IDL.Opt(IDL.Nat16),
+  });
+  const ProfilePage = IDL.Record({
+    'total' : IDL.Nat64,
+    'next' : IDL.Opt(IDL.Principal),
+    'profiles' : IDL.Vec(Profile),
+  });
+  const LeaderInfo = IDL.Record({
+    'wallet_address' : IDL.Principal,
+    'comment' : IDL.Opt(IDL.Text),
+    'discord_account' : IDL.Opt(IDL.Text),
+    'community_name' : IDL.Text,
+    'number' : IDL.Nat16,
+  });
+  const DirectoryVisibility = IDL.Record({
+    'show_comment' : IDL.Bool,
+    'show_discord' : IDL.Bool,
+  });
+  const TeamInfo = IDL.Record({
+    'members' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat64)),
+    'invites' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat64)),
+    'leader' : IDL.Principal,
+    'requests' : IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat64)),
+  });
+  const MembershipAction = IDL.Variant({
+    'Invited' : IDL.Null,
+    'Left' : IDL.Null,
+    'Declined' : IDL.Null,
+    'Requested' : IDL.Null,
+    'Removed' : IDL.Null,
+    'Cancelled' : IDL.Null,
+    'Joined' : IDL.Null,
+  });
+  const MembershipEvent = IDL.Record({
+    'action' : MembershipAction,
+    'actor' : IDL.Principal,
+    'timestamp' : IDL.Nat64,
+    'worker' : IDL.Principal,
+  });
+  const LeaderScore = IDL.Record({
+    'members' : IDL.Nat64,
+    'score' : IDL.Nat64,
+    'leader' : IDL.Principal,
+    'number' : IDL.Nat16,
+    'retention_bps' : IDL.Nat64,
+    'payouts' : IDL.Nat64,
+  });
+  const ScoreConfig = IDL.Record({
+    'suspend_below' : IDL.Nat64,
+    'payout_unit' : IDL.Nat64,
+    'retention_weight' : IDL.Nat64,
+    'member_weight' : IDL.Nat64,
+    'payout_weight' : IDL.Nat64,
+    'grace_period' : IDL.Nat64,
+    'half_life' : IDL.Nat64,
   });
-  const Result_1 = IDL.Variant({ 'Ok' : StatsData, 'Err' : IDL.Text });
-  const Result_2 = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
   return IDL.Service({
-    'get' : IDL.Func([], [Result], ['query']),
+    'acceptInvite' : IDL.Func([IDL.Principal], [Result_4], []),
+    'approveRequest' : IDL.Func([IDL.Principal], [Result_4], []),
+    'cancelInvite' : IDL.Func([IDL.Principal], [Result_4], []),
+    'clearRenewalCredit' : IDL.Func([IDL.Principal], [Result_5], []),
+    'confirmDeposit' : IDL.Func([], [Result], []),
+    'declineRequest' : IDL.Func([IDL.Principal], [Result_4], []),
+    'get' : IDL.Func([], [Result_1], ['query']),
     'getAll' : IDL.Func([IDL.Nat64, IDL.Nat64], [IDL.Vec(Profile)], ['query']),
-    'getStats' : IDL.Func([], [Result_1], ['query']),
-    'insert' : IDL.Func([IDL.Nat64, IDL.Text, IDL.Text], [Result_2], []),
-    'pass' : IDL.Func([IDL.Principal], [Result_2], []),
-    'refuse' : IDL.Func([IDL.Principal, IDL.Text], [Result_2], []),
-    'setAdmin' : IDL.Func([IDL.Principal], [Result_2], []),
-    'setClosed' : IDL.Func([IDL.Bool], [Result_2], []),
-    'setFee' : IDL.Func([IDL.Nat], [Result_2], []),
-    'setFeeTo' : IDL.Func([IDL.Principal], [Result_2], []),
-    'setLedger' : IDL.Func([IDL.Principal], [Result_2], []),
-    'updateProfile' : IDL.Func([Profile], [Result_2], []),
+    'getAuditLog' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(ProfileEvent)],
+        ['query'],
+      ),
+    'getCyclesInfo' : IDL.Func([], [CyclesInfo], ['query']),
+    'getDepositAccount' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Vec(IDL.Nat8)],
+        ['query'],
+      ),
+    'getLeaderByNumber' : IDL.Func(
+        [IDL.Nat16],
+        [IDL.Opt(LeaderInfo)],
+        ['query'],
+      ),
+    'getLeaderboard' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(LeaderScore)],
+        ['query'],
+      ),
+    'getLeaders' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(LeaderInfo)],
+        ['query'],
+      ),
+    'getPendingSweeps' : IDL.Func(
+        [],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, PendingSweep))],
+        ['query'],
+      ),
+    'getProfileHistory' : IDL.Func([IDL.Principal], [Result_2], ['query']),
+    'getRefunds' : IDL.Func([], [IDL.Vec(PendingRefund)], ['query']),
+    'getRenewalCredits' : IDL.Func(
+        [],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat))],
+        ['query'],
+      ),
+    'getRole' : IDL.Func([IDL.Principal], [IDL.Opt(Role)], ['query']),
+    'getRoles' : IDL.Func(
+        [],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, RoleGrant))],
+        ['query'],
+      ),
+    'getScore' : IDL.Func([IDL.Principal], [IDL.Opt(LeaderScore)], ['query']),
+    'getScoreConfig' : IDL.Func([], [ScoreConfig], ['query']),
+    'getStats' : IDL.Func([], [Result_3], ['query']),
+    'getTeam' : IDL.Func([IDL.Principal], [IDL.Opt(TeamInfo)], ['query']),
+    'getTeamEvents' : IDL.Func(
+        [IDL.Principal, IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(MembershipEvent)],
+        ['query'],
+      ),
+    'getTeamOf' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Opt(IDL.Principal)],
+        ['query'],
+      ),
+    'grantRole' : IDL.Func([IDL.Principal, Role], [Result_4], []),
+    'insert' : IDL.Func([IDL.Nat64, IDL.Text, IDL.Text], [Result_4], []),
+    'insertWithAllowance' : IDL.Func([IDL.Text, IDL.Text], [Result_4], []),
+    'insertWithDeposit' : IDL.Func([IDL.Text, IDL.Text], [Result_4], []),
+    'inviteMember' : IDL.Func([IDL.Principal], [Result_4], []),
+    'leaveTeam' : IDL.Func([], [Result_4], []),
+    'listProfiles' : IDL.Func(
+        [ProfileFilter, IDL.Opt(IDL.Principal), IDL.Nat64],
+        [ProfilePage],
+        ['query'],
+      ),
+    'pass' : IDL.Func([IDL.Principal], [Result_4], []),
+    'refuse' : IDL.Func([IDL.Principal, IDL.Text], [Result_4], []),
+    'reinstate' : IDL.Func([IDL.Principal], [Result_4], []),
+    'removeMember' : IDL.Func([IDL.Principal], [Result_4], []),
+    'renew' : IDL.Func([IDL.Opt(IDL.Nat64)], [Result_4], []),
+    'reportPayout' : IDL.Func([IDL.Principal, IDL.Nat], [Result_4], []),
+    'requestToJoin' : IDL.Func([IDL.Principal], [Result_4], []),
+    'resolveSweep' : IDL.Func([IDL.Principal, IDL.Bool], [Result], []),
+    'retryRefund' : IDL.Func([IDL.Nat64], [Result], []),
+    'revokeRole' : IDL.Func([IDL.Principal], [Result_4], []),
+    'setAdmin' : IDL.Func([IDL.Principal], [Result_4], []),
+    'setClosed' : IDL.Func([IDL.Bool], [Result_4], []),
+    'setDirectoryVisibility' : IDL.Func(
+        [DirectoryVisibility],
+        [Result_4],
+        [],
+      ),
+    'setFee' : IDL.Func([IDL.Nat], [Result_4], []),
+    'setFeeTo' : IDL.Func([IDL.Principal], [Result_4], []),
+    'setGovernance' : IDL.Func([IDL.Opt(IDL.Principal)], [Result_4], []),
+    'setLedger' : IDL.Func([IDL.Principal], [Result_4], []),
+    'setPaymentAsset' : IDL.Func([PaymentAsset], [Result_4], []),
+    'setPayoutReporter' : IDL.Func([IDL.Opt(IDL.Principal)], [Result_4], []),
+    'setRefundPolicy' : IDL.Func([IDL.Opt(RefundPolicy)], [Result_4], []),
+    'setScoreConfig' : IDL.Func([ScoreConfig], [Result_4], []),
+    'setTermConfig' : IDL.Func([IDL.Opt(TermConfig)], [Result_4], []),
+    'setTopUpConfig' : IDL.Func([IDL.Opt(TopUpConfig)], [Result_4], []),
+    'suspendLowScorers' : IDL.Func([], [IDL.Vec(IDL.Principal)], []),
+    'updateProfile' : IDL.Func([Profile], [Result_4], []),
   });
 };
 export const init = ({ IDL }) => { return []; };
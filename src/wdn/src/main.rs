@@ -1,15 +1,26 @@
 use candid::{candid_method, CandidType, Deserialize, Int, Nat};
-use cap_sdk::{handshake, insert, Event, IndefiniteEvent, TypedEvent};
+use cap_sdk::{handshake, insert, DetailValue, Event, IndefiniteEvent, TypedEvent};
 use cap_std::dip20::cap::DIP20Details;
 use cap_std::dip20::{Operation, TransactionStatus, TxRecord};
+use common::cycles::{self, CyclesInfo, CyclesMonitor, TopUpConfig};
 use ic_cdk_macros::*;
+use ic_certified_map::{AsHashTree, HashTree, RbTree};
 use ic_kit::{ic, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{
+    BoundedStorable, DefaultMemoryImpl, Memory, StableBTreeMap, StableLog, Storable,
+};
+use serde::Serialize;
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::convert::Into;
-use std::iter::FromIterator;
+use std::io::Write;
+use std::ops::Bound;
 use std::string::String;
+use std::thread::LocalKey;
 
 #[derive(CandidType, Default, Deserialize, Clone)]
 pub struct TxLog {
@@ -42,6 +53,7 @@ struct StatsData {
     deploy_time: u64,
 
     minter: Principal,
+    governance: Option<Principal>,
 }
 
 #[allow(non_snake_case)]
@@ -71,13 +83,802 @@ impl Default for StatsData {
             deploy_time: 0,
 
             minter: Principal::anonymous(),
+            governance: None,
         }
     }
 }
 
+// heap layout used before balances moved to stable memory, only read while migrating
 type Balances = HashMap<Principal, Nat>;
 type Allowances = HashMap<Principal, HashMap<Principal, Nat>>;
 
+type StableMemory = VirtualMemory<DefaultMemoryImpl>;
+
+// records of an account, keyed by (account, u64::MAX - record id) so that
+// the newest come first
+type AccountIndex = RefCell<StableBTreeMap<AccountKey, (), StableMemory>>;
+
+const UPGRADES_MEMORY: MemoryId = MemoryId::new(0);
+const BALANCES_MEMORY: MemoryId = MemoryId::new(1);
+const ALLOWS_MEMORY: MemoryId = MemoryId::new(2);
+const HISTORY_INDEX_MEMORY: MemoryId = MemoryId::new(3);
+const HISTORY_DATA_MEMORY: MemoryId = MemoryId::new(4);
+const HOLDERS_MEMORY: MemoryId = MemoryId::new(5);
+const HOLDER_RANKS_MEMORY: MemoryId = MemoryId::new(13);
+const NOTIFICATION_ITEMS_MEMORY: MemoryId = MemoryId::new(14);
+const NOTIFICATION_PAYLOADS_MEMORY: MemoryId = MemoryId::new(15);
+const NOTIFICATION_ACCOUNTS_MEMORY: MemoryId = MemoryId::new(16);
+const ESCROW_ITEMS_MEMORY: MemoryId = MemoryId::new(17);
+const ESCROW_DEADLINES_MEMORY: MemoryId = MemoryId::new(18);
+const ESCROW_ACCOUNTS_MEMORY: MemoryId = MemoryId::new(19);
+const ESCROWED_MEMORY: MemoryId = MemoryId::new(20);
+const STREAM_ITEMS_MEMORY: MemoryId = MemoryId::new(21);
+const STREAM_SENDERS_MEMORY: MemoryId = MemoryId::new(22);
+const STREAM_RECIPIENTS_MEMORY: MemoryId = MemoryId::new(23);
+const FROZEN_MEMORY: MemoryId = MemoryId::new(24);
+const FREEZE_LOG_INDEX_MEMORY: MemoryId = MemoryId::new(25);
+const FREEZE_LOG_DATA_MEMORY: MemoryId = MemoryId::new(26);
+const SNAPSHOT_BALANCES_MEMORY: MemoryId = MemoryId::new(6);
+const SNAPSHOT_SUPPLY_MEMORY: MemoryId = MemoryId::new(7);
+const VESTING_SCHEDULES_MEMORY: MemoryId = MemoryId::new(8);
+const VESTING_BENEFICIARIES_MEMORY: MemoryId = MemoryId::new(9);
+const STAKE_POSITIONS_MEMORY: MemoryId = MemoryId::new(10);
+const STAKE_OWNERS_MEMORY: MemoryId = MemoryId::new(11);
+const FEE_EXEMPT_MEMORY: MemoryId = MemoryId::new(12);
+
+// written by the memory manager at the start of stable memory
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+const MAX_PRINCIPAL_SIZE: u32 = 29;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalKey(Principal);
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = MAX_PRINCIPAL_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct AllowanceKey {
+    owner: Principal,
+    spender: Principal,
+}
+
+impl Storable for AllowanceKey {
+    // [owner length][owner][spender]
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let owner = self.owner.as_slice();
+        let mut bytes = Vec::with_capacity(1 + owner.len() + self.spender.as_slice().len());
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(self.spender.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner_len = bytes[0] as usize;
+        AllowanceKey {
+            owner: Principal::from_slice(&bytes[1..1 + owner_len]),
+            spender: Principal::from_slice(&bytes[1 + owner_len..]),
+        }
+    }
+}
+
+impl BoundedStorable for AllowanceKey {
+    const MAX_SIZE: u32 = 1 + 2 * MAX_PRINCIPAL_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Records kept in stable maps are candid encoded, `$max_size` bounds the
+// encoding of any value of the type.
+macro_rules! candid_storable {
+    ($t:ty, $max_size:expr) => {
+        impl Storable for $t {
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(candid::encode_one(self).unwrap())
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                candid::decode_one(&bytes).unwrap()
+            }
+        }
+
+        impl BoundedStorable for $t {
+            const MAX_SIZE: u32 = $max_size;
+            const IS_FIXED_SIZE: bool = false;
+        }
+    };
+}
+
+// Key of records kept per account, ordered by account and then by id
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct AccountKey {
+    account: Principal,
+    id: u64,
+}
+
+impl Storable for AccountKey {
+    // [account length][account][big-endian id]
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let account = self.account.as_slice();
+        let mut bytes = Vec::with_capacity(1 + account.len() + 8);
+        bytes.push(account.len() as u8);
+        bytes.extend_from_slice(account);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let account_len = bytes[0] as usize;
+        let mut id = [0; 8];
+        id.copy_from_slice(&bytes[1 + account_len..]);
+        AccountKey {
+            account: Principal::from_slice(&bytes[1..1 + account_len]),
+            id: u64::from_be_bytes(id),
+        }
+    }
+}
+
+impl BoundedStorable for AccountKey {
+    const MAX_SIZE: u32 = 1 + MAX_PRINCIPAL_SIZE + 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// LEB128 encoded, 64 bytes hold any value below 2^448
+#[derive(Clone)]
+struct StableNat(Nat);
+
+impl Storable for StableNat {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        self.0.encode(&mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut reader: &[u8] = &bytes;
+        StableNat(Nat::decode(&mut reader).unwrap())
+    }
+}
+
+impl BoundedStorable for StableNat {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Holder index entry, ordered by balance descending and then by principal.
+// Encoded as [0xff - balance length][complemented big-endian balance]
+// [holder length][holder] so that the byte order matches the index order and
+// no entry is a prefix of another.
+#[derive(Clone, PartialEq, Eq)]
+struct HolderKey {
+    balance: Nat,
+    holder: Principal,
+}
+
+impl Storable for HolderKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let balance = self.balance.0.to_bytes_be();
+        let holder = self.holder.as_slice();
+        let mut bytes = Vec::with_capacity(2 + balance.len() + holder.len());
+        bytes.push(0xff - balance.len() as u8);
+        bytes.extend(balance.iter().map(|b| !b));
+        bytes.push(holder.len() as u8);
+        bytes.extend_from_slice(holder);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let balance_len = (0xff - bytes[0]) as usize;
+        let mut balance = Nat::from(0);
+        for b in &bytes[1..1 + balance_len] {
+            balance = balance * Nat::from(256) + Nat::from(!b);
+        }
+        HolderKey {
+            balance,
+            holder: Principal::from_slice(&bytes[2 + balance_len..]),
+        }
+    }
+}
+
+impl BoundedStorable for HolderKey {
+    const MAX_SIZE: u32 = 2 + <StableNat as BoundedStorable>::MAX_SIZE + MAX_PRINCIPAL_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Ord for HolderKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.to_bytes().cmp(&other.to_bytes())
+    }
+}
+
+impl PartialOrd for HolderKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// holder bytes covered by the holder rank counts
+const HOLDER_RANK_HOLDER_BYTES: usize = 1;
+
+// Number of holder index entries whose encoding starts with a prefix, keyed by
+// [prefix length][prefix]. Prefixes go down to the holder length and the first
+// HOLDER_RANK_HOLDER_BYTES of the holder, so that ranks and offsets are found
+// by summing counts instead of walking the index.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RankPrefix(Vec<u8>);
+
+impl RankPrefix {
+    fn new(len: usize, prefix: &[u8]) -> Self {
+        let mut bytes = Vec::with_capacity(1 + prefix.len());
+        bytes.push(len as u8);
+        bytes.extend_from_slice(prefix);
+        RankPrefix(bytes)
+    }
+}
+
+impl Storable for RankPrefix {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RankPrefix(bytes.to_vec())
+    }
+}
+
+impl BoundedStorable for RankPrefix {
+    const MAX_SIZE: u32 =
+        3 + <StableNat as BoundedStorable>::MAX_SIZE + HOLDER_RANK_HOLDER_BYTES as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub event: IndefiniteEvent,
+}
+
+impl Storable for HistoryEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+// Copy-on-write balance snapshots: an account's value is only recorded, in
+// SNAPSHOT_BALANCES, the first time it changes after a snapshot has been
+// taken. The total supply is recorded the same way in SNAPSHOT_SUPPLY.
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct Snapshots {
+    current_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VestingSchedule {
+    id: u64,
+    beneficiary: Principal,
+    total: Nat,
+    released: Nat,
+
+    // nanoseconds
+    start: u64,
+    // seconds after start
+    cliff: u64,
+    duration: u64,
+    interval: u64,
+
+    revocable: bool,
+    revoked: bool,
+}
+
+candid_storable!(VestingSchedule, 512);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VestingStatus {
+    schedule: VestingSchedule,
+    vested: Nat,
+    releasable: Nat,
+}
+
+// Vested tokens are credited to the beneficiary up front, the part that
+// has not been released yet stays locked in their balance. The schedules
+// are kept in VESTING_SCHEDULES and indexed by beneficiary.
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct Vestings {
+    next_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FeeSplit {
+    // basis points of each fee, whatever is left goes to fee_to
+    burn_bps: u64,
+    staking_bps: u64,
+    treasury_bps: u64,
+    treasury: Principal,
+}
+
+#[derive(CandidType, Default, Deserialize, Clone, Debug)]
+enum FeePolicy {
+    #[default]
+    FeeTo,
+    Burn,
+    Split(FeeSplit),
+}
+
+impl FeePolicy {
+    fn validate(&self) -> Result<(), TxError> {
+        if let FeePolicy::Split(split) = self {
+            let total = split
+                .burn_bps
+                .checked_add(split.staking_bps)
+                .and_then(|bps| bps.checked_add(split.treasury_bps));
+            if !matches!(total, Some(bps) if bps <= 10000) {
+                return Err(TxError::Other("fee split above 10000 bps".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // the same policy with `staking_bps` of each fee going to the stakers,
+    // taken from what would have gone to fee_to or been burned
+    fn with_staking_share(self, staking_bps: u64, fee_to: Principal) -> FeePolicy {
+        match self {
+            FeePolicy::FeeTo | FeePolicy::Burn if staking_bps == 0 => self,
+            FeePolicy::FeeTo => FeePolicy::Split(FeeSplit {
+                burn_bps: 0,
+                staking_bps,
+                treasury_bps: 0,
+                treasury: fee_to,
+            }),
+            FeePolicy::Burn => FeePolicy::Split(FeeSplit {
+                burn_bps: 10000u64.saturating_sub(staking_bps),
+                staking_bps,
+                treasury_bps: 0,
+                treasury: fee_to,
+            }),
+            FeePolicy::Split(split) => FeePolicy::Split(FeeSplit {
+                staking_bps,
+                ..split
+            }),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FeeTier {
+    // basis points of the transferred amount
+    rate_bps: u64,
+    min: Nat,
+    max: Nat,
+}
+
+// principals that pay no fee, as sender or as recipient, are kept in FEE_EXEMPT
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct FeeRules {
+    // replaces the flat fee on transfers when set
+    tier: Option<FeeTier>,
+}
+
+// where a charged fee went, for the history
+enum FeePart {
+    Burn(Nat),
+    Transfer(Principal, Nat),
+    Staking(Nat),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum NotificationStatus {
+    // not sent yet
+    Pending,
+    // sent one-way, the recipient's reply is not awaited
+    Delivered,
+    // the callback could not be sent
+    Failed(String),
+}
+
+// the payload is kept in NOTIFICATION_PAYLOADS until the notification is
+// delivered, the stored record has it empty
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Notification {
+    id: u64,
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+    payload: Vec<u8>,
+    status: NotificationStatus,
+    attempts: u32,
+    created_at: u64,
+    last_attempt_at: u64,
+}
+
+candid_storable!(Notification, 512 + MAX_NOTIFICATION_ERROR_SIZE);
+
+#[derive(Clone)]
+struct NotificationPayload(Vec<u8>);
+
+impl Storable for NotificationPayload {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        NotificationPayload(bytes.to_vec())
+    }
+}
+
+impl BoundedStorable for NotificationPayload {
+    const MAX_SIZE: u32 = MAX_NOTIFICATION_PAYLOAD_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// argument of the recipient's callback. The id stays the same across retries,
+// recipients use it to ignore a notification they have already processed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferNotification {
+    id: u64,
+    from: Principal,
+    amount: Nat,
+    payload: Vec<u8>,
+}
+
+// the notifications themselves are in NOTIFICATION_ITEMS
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct Notifications {
+    next_id: u64,
+}
+
+const MAX_NOTIFICATION_PAYLOAD_SIZE: u32 = 2048;
+// failure messages are cut to this many bytes
+const MAX_NOTIFICATION_ERROR_SIZE: u32 = 256;
+// the only method transferAndCall calls on a recipient
+const NOTIFICATION_METHOD: &str = "onTokenTransfer";
+
+// expired escrows refunded per heartbeat
+const ESCROW_REFUNDS_PER_BEAT: usize = 20;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum EscrowStatus {
+    Open,
+    Released,
+    Refunded,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Escrow {
+    id: u64,
+    payer: Principal,
+    payee: Principal,
+    arbiter: Principal,
+    amount: Nat,
+    // ns, the payer is refunded once it has passed
+    deadline: u64,
+    created_at: u64,
+    status: EscrowStatus,
+}
+
+candid_storable!(Escrow, 512);
+
+// Escrowed amounts are held outside of BALANCES, in ESCROW_ITEMS, until they
+// are released or refunded.
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct Escrows {
+    next_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+enum StreamStatus {
+    Active,
+    // the whole deposit has been withdrawn
+    Completed,
+    Canceled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Stream {
+    id: u64,
+    sender: Principal,
+    recipient: Principal,
+    rate_per_second: Nat,
+    deposit: Nat,
+    withdrawn: Nat,
+    // ns
+    start: u64,
+    // ns, set when canceled, accrual stops there
+    canceled_at: Option<u64>,
+    status: StreamStatus,
+}
+
+candid_storable!(Stream, 512);
+
+impl Stream {
+    // streamed to the recipient so far, capped at the deposit
+    fn accrued_at(&self, now: u64) -> Nat {
+        let end = self.canceled_at.unwrap_or(now);
+        let elapsed = end.saturating_sub(self.start) / 1_000_000_000;
+        let accrued = self.rate_per_second.clone() * Nat::from(elapsed);
+        if accrued > self.deposit {
+            self.deposit.clone()
+        } else {
+            accrued
+        }
+    }
+
+    fn withdrawable_at(&self, now: u64) -> Nat {
+        self.accrued_at(now) - self.withdrawn.clone()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StreamInfo {
+    stream: Stream,
+    withdrawable: Nat,
+}
+
+// Deposits are held outside of BALANCES, in STREAM_ITEMS, until they are
+// withdrawn by the recipient or returned to the sender.
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct Streams {
+    next_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FreezeRecord {
+    reason: String,
+    frozen_at: u64,
+    frozen_by: Principal,
+}
+
+candid_storable!(FreezeRecord, 128 + MAX_FREEZE_REASON_SIZE);
+
+const MAX_FREEZE_REASON_SIZE: u32 = 256;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum FreezeAction {
+    Freeze,
+    Unfreeze,
+    ForceTransfer,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct FreezeLogEntry {
+    account: Principal,
+    action: FreezeAction,
+    reason: String,
+    by: Principal,
+    timestamp: u64,
+}
+
+impl Storable for FreezeLogEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// leaves of the certified tree, values are candid encoded
+const CERTIFIED_BALANCE_PREFIX: &[u8] = b"balance:";
+const CERTIFIED_TOTAL_SUPPLY: &[u8] = b"total_supply";
+const CERTIFIED_METADATA: &[u8] = b"metadata";
+// balances put back into the certified tree per heartbeat after an upgrade
+const CERTIFY_BATCH: usize = 500;
+
+// The certified tree lives on the heap and is refilled from BALANCES in
+// batches after an upgrade. Balances past `after` are not in it yet.
+#[derive(Default)]
+struct CertifyBackfill {
+    running: bool,
+    after: Option<Principal>,
+}
+
+// A certified value: `witness` is the CBOR encoded hash tree revealing the
+// value's leaf and `certificate` certifies the tree's root hash. The
+// certificate is only available in query calls.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CertifiedNat {
+    value: Nat,
+    witness: Vec<u8>,
+    certificate: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct CertifiedMetadata {
+    value: Metadata,
+    witness: Vec<u8>,
+    certificate: Option<Vec<u8>>,
+}
+
+// scale of the reward-per-weight accumulators
+const REWARD_PRECISION: u64 = 1_000_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StakingTerm {
+    days: u64,
+    // reward weight of the staked amount, in basis points
+    weight_bps: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StakePosition {
+    id: u64,
+    owner: Principal,
+    amount: Nat,
+    weight: Nat,
+    term_days: u64,
+    staked_at: u64,
+    unlock_at: u64,
+
+    fee_reward_debt: Nat,
+    emission_reward_debt: Nat,
+}
+
+candid_storable!(StakePosition, 512);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StakePositionInfo {
+    position: StakePosition,
+    pending_reward: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct StakingInfo {
+    terms: Vec<StakingTerm>,
+    emission_per_second: Nat,
+    fee_share_bps: u64,
+    early_unlock_penalty_bps: u64,
+    total_staked: Nat,
+    total_weight: Nat,
+    // estimated from the emission only, per term in days
+    apr_bps: Vec<(u64, Nat)>,
+}
+
+// staked tokens and the fee rewards waiting to be claimed are held here,
+// outside of BALANCES. Emission rewards are minted when they are claimed.
+#[derive(CandidType, Deserialize, Clone)]
+struct Staking {
+    terms: Vec<StakingTerm>,
+    emission_per_second: Nat,
+    early_unlock_penalty_bps: u64,
+
+    total_staked: Nat,
+    total_weight: Nat,
+    fee_reserve: Nat,
+    acc_fee_per_weight: Nat,
+    acc_emission_per_weight: Nat,
+    last_update: u64,
+
+    next_id: u64,
+}
+
+impl Default for Staking {
+    fn default() -> Self {
+        Staking {
+            terms: vec![
+                StakingTerm {
+                    days: 30,
+                    weight_bps: 10000,
+                },
+                StakingTerm {
+                    days: 90,
+                    weight_bps: 15000,
+                },
+                StakingTerm {
+                    days: 180,
+                    weight_bps: 20000,
+                },
+            ],
+            emission_per_second: Nat::from(0),
+            early_unlock_penalty_bps: 0,
+
+            total_staked: Nat::from(0),
+            total_weight: Nat::from(0),
+            fee_reserve: Nat::from(0),
+            acc_fee_per_weight: Nat::from(0),
+            acc_emission_per_weight: Nat::from(0),
+            last_update: 0,
+
+            next_id: 0,
+        }
+    }
+}
+
+impl Staking {
+    fn emission_acc_at(&self, now: u64) -> Nat {
+        let elapsed = now.saturating_sub(self.last_update) / 1000000000;
+        if elapsed == 0 || self.total_weight == 0 || self.emission_per_second == 0 {
+            return self.acc_emission_per_weight.clone();
+        }
+        let emission = self.emission_per_second.clone() * Nat::from(elapsed);
+        self.acc_emission_per_weight.clone()
+            + emission * Nat::from(REWARD_PRECISION) / self.total_weight.clone()
+    }
+
+    fn update_pool(&mut self, now: u64) {
+        if self.total_weight == 0 {
+            self.last_update = now;
+            return;
+        }
+        let elapsed = now.saturating_sub(self.last_update) / 1000000000;
+        if elapsed == 0 {
+            return;
+        }
+        self.acc_emission_per_weight = self.emission_acc_at(now);
+        self.last_update += elapsed * 1000000000;
+    }
+
+    fn distribute_fee(&mut self, amount: Nat) {
+        self.acc_fee_per_weight +=
+            amount.clone() * Nat::from(REWARD_PRECISION) / self.total_weight.clone();
+        self.fee_reserve += amount;
+    }
+
+    // rounding can leave the reserve a few units short of what is owed
+    fn take_fee_reward(&mut self, amount: Nat) -> Nat {
+        let amount = if amount > self.fee_reserve {
+            self.fee_reserve.clone()
+        } else {
+            amount
+        };
+        self.fee_reserve -= amount.clone();
+        amount
+    }
+
+    fn accrued(&self, weight: &Nat) -> (Nat, Nat) {
+        (
+            weight.clone() * self.acc_fee_per_weight.clone() / Nat::from(REWARD_PRECISION),
+            weight.clone() * self.acc_emission_per_weight.clone() / Nat::from(REWARD_PRECISION),
+        )
+    }
+
+    // (fee reward, emission reward) not claimed yet, the pool must be up to date
+    fn pending(&self, position: &StakePosition) -> (Nat, Nat) {
+        let (fee, emission) = self.accrued(&position.weight);
+        (
+            fee - position.fee_reward_debt.clone(),
+            emission - position.emission_reward_debt.clone(),
+        )
+    }
+
+    fn pending_at(&self, position: &StakePosition, now: u64) -> Nat {
+        let fee =
+            position.weight.clone() * self.acc_fee_per_weight.clone() / Nat::from(REWARD_PRECISION);
+        let emission =
+            position.weight.clone() * self.emission_acc_at(now) / Nat::from(REWARD_PRECISION);
+        fee - position.fee_reward_debt.clone() + emission - position.emission_reward_debt.clone()
+    }
+}
+
 #[derive(CandidType, Debug, PartialEq)]
 pub enum TxError {
     InsufficientBalance,
@@ -89,18 +890,94 @@ pub enum TxError {
     ErrorOperationStyle,
     ErrorTo,
     Other(String),
+    Frozen,
 }
 pub type TxReceipt = Result<Nat, TxError>;
 
 thread_local! {
-    static BALANCES: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::default());
-    static ALLOWS: RefCell<HashMap<Principal, HashMap<Principal, Nat>>> = RefCell::new(HashMap::default());
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static BALANCES: RefCell<StableBTreeMap<PrincipalKey, StableNat, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(BALANCES_MEMORY)));
+    static ALLOWS: RefCell<StableBTreeMap<AllowanceKey, StableNat, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(ALLOWS_MEMORY)));
+    static HOLDERS: RefCell<StableBTreeMap<HolderKey, (), StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(HOLDERS_MEMORY)));
+    static HOLDER_RANKS: RefCell<StableBTreeMap<RankPrefix, u64, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(HOLDER_RANKS_MEMORY)));
+    // (account, snapshot id) -> balance before its first change after the snapshot
+    static SNAPSHOT_BALANCES: RefCell<StableBTreeMap<AccountKey, StableNat, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(SNAPSHOT_BALANCES_MEMORY)));
+    static SNAPSHOT_SUPPLY: RefCell<StableBTreeMap<u64, StableNat, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(SNAPSHOT_SUPPLY_MEMORY)));
+    static HISTORY: RefCell<StableLog<HistoryEntry, StableMemory, StableMemory>> = RefCell::new(
+        StableLog::init(_memory(HISTORY_INDEX_MEMORY), _memory(HISTORY_DATA_MEMORY))
+            .expect("failed to initialize the history log"),
+    );
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     static TXLOG: RefCell<TxLog> = RefCell::new(TxLog::default());
+    static SNAPSHOTS: RefCell<Snapshots> = RefCell::new(Snapshots::default());
+    static VESTINGS: RefCell<Vestings> = RefCell::new(Vestings::default());
+    static VESTING_SCHEDULES: RefCell<StableBTreeMap<u64, VestingSchedule, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(VESTING_SCHEDULES_MEMORY)));
+    // (beneficiary, schedule id)
+    static VESTING_BENEFICIARIES: RefCell<StableBTreeMap<AccountKey, (), StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(VESTING_BENEFICIARIES_MEMORY)));
+    static STAKING: RefCell<Staking> = RefCell::new(Staking::default());
+    static STAKE_POSITIONS: RefCell<StableBTreeMap<u64, StakePosition, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(STAKE_POSITIONS_MEMORY)));
+    // (owner, position id)
+    static STAKE_OWNERS: RefCell<StableBTreeMap<AccountKey, (), StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(STAKE_OWNERS_MEMORY)));
+    static FEE_POLICY: RefCell<FeePolicy> = RefCell::new(FeePolicy::default());
+    static FEE_RULES: RefCell<FeeRules> = RefCell::new(FeeRules::default());
+    static FEE_EXEMPT: RefCell<StableBTreeMap<PrincipalKey, (), StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(FEE_EXEMPT_MEMORY)));
+    static NOTIFICATIONS: RefCell<Notifications> = RefCell::new(Notifications::default());
+    static NOTIFICATION_ITEMS: RefCell<StableBTreeMap<u64, Notification, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(NOTIFICATION_ITEMS_MEMORY)));
+    static NOTIFICATION_PAYLOADS: RefCell<StableBTreeMap<u64, NotificationPayload, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(NOTIFICATION_PAYLOADS_MEMORY)));
+    // sender and recipient
+    static NOTIFICATION_ACCOUNTS: AccountIndex =
+        RefCell::new(StableBTreeMap::init(_memory(NOTIFICATION_ACCOUNTS_MEMORY)));
+    static ESCROWS: RefCell<Escrows> = RefCell::new(Escrows::default());
+    static ESCROW_ITEMS: RefCell<StableBTreeMap<u64, Escrow, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(ESCROW_ITEMS_MEMORY)));
+    // (deadline, id) of the open escrows
+    static ESCROW_DEADLINES: RefCell<StableBTreeMap<(u64, u64), (), StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(ESCROW_DEADLINES_MEMORY)));
+    // payer, payee and arbiter
+    static ESCROW_ACCOUNTS: AccountIndex =
+        RefCell::new(StableBTreeMap::init(_memory(ESCROW_ACCOUNTS_MEMORY)));
+    // payer -> total of their open escrows
+    static ESCROWED: RefCell<StableBTreeMap<PrincipalKey, StableNat, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(ESCROWED_MEMORY)));
+    static STREAMS: RefCell<Streams> = RefCell::new(Streams::default());
+    static STREAM_ITEMS: RefCell<StableBTreeMap<u64, Stream, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(STREAM_ITEMS_MEMORY)));
+    static STREAM_SENDERS: AccountIndex =
+        RefCell::new(StableBTreeMap::init(_memory(STREAM_SENDERS_MEMORY)));
+    static STREAM_RECIPIENTS: AccountIndex =
+        RefCell::new(StableBTreeMap::init(_memory(STREAM_RECIPIENTS_MEMORY)));
+    // Frozen accounts can receive but nothing leaves them except through
+    // forceTransfer. Every change is kept in FREEZE_LOG.
+    static FROZEN: RefCell<StableBTreeMap<PrincipalKey, FreezeRecord, StableMemory>> =
+        RefCell::new(StableBTreeMap::init(_memory(FROZEN_MEMORY)));
+    static FREEZE_LOG: RefCell<StableLog<FreezeLogEntry, StableMemory, StableMemory>> =
+        RefCell::new(
+            StableLog::init(_memory(FREEZE_LOG_INDEX_MEMORY), _memory(FREEZE_LOG_DATA_MEMORY))
+                .expect("failed to initialize the freeze log"),
+        );
+    // refilled from BALANCES and STATS after an upgrade
+    static CERTIFIED: RefCell<RbTree<Vec<u8>, Vec<u8>>> = const { RefCell::new(RbTree::new()) };
+    static CERTIFY_BACKFILL: RefCell<CertifyBackfill> = RefCell::new(CertifyBackfill::default());
+    static CYCLES: RefCell<CyclesMonitor> = RefCell::new(CyclesMonitor::default());
 }
 
 #[init]
 #[candid_method(init)]
+#[allow(clippy::too_many_arguments)]
 fn init(
     logo: String,
     name: String,
@@ -125,20 +1002,21 @@ fn init(
         stats.history_size = 1;
         stats.deploy_time = ic::time();
     });
+    _certify_metadata();
     handshake(5_000_000_000_000, Some(cap));
-    BALANCES.with(|b| {
-        b.borrow_mut().insert(owner, total_supply.clone());
-    });
-    let _ = add_record(
-        owner,
-        Operation::Mint,
-        owner,
-        owner,
-        total_supply,
-        Nat::from(0),
-        ic::time(),
-        TransactionStatus::Succeeded,
-    );
+    _balance_ins(owner, total_supply.clone());
+    // init cannot wait for cap, the mint is sent along with the next record
+    _queue_event(_tx_record_event(TxRecord {
+        caller: Some(owner),
+        index: Nat::from(0),
+        from: owner,
+        to: owner,
+        amount: total_supply,
+        fee: Nat::from(0),
+        timestamp: Int::from(ic::time()),
+        status: TransactionStatus::Succeeded,
+        operation: Operation::Mint,
+    }));
 }
 
 /* UPDATE FNS */
@@ -147,14 +1025,17 @@ fn init(
 #[candid_method(update)]
 async fn transfer(to: Principal, value: Nat) -> TxReceipt {
     let from = ic::caller();
-    let fee = _get_fee();
-    if balance_of(from) < value.clone() + fee.clone() {
+    if _is_frozen(from) {
+        return Err(TxError::Frozen);
+    }
+    let fee = _get_fee_for(from, to, Some(&value));
+    if _spendable_of(from) < value.clone() + fee.clone() {
         return Err(TxError::InsufficientBalance);
     }
-    _charge_fee(from, fee.clone());
+    let fee_parts = _charge_fee(from, fee.clone());
     _transfer(from, to, value.clone());
     _history_inc();
-    add_record(
+    let res = add_record(
         from,
         Operation::Transfer,
         from,
@@ -164,49 +1045,31 @@ async fn transfer(to: Principal, value: Nat) -> TxReceipt {
         ic::time(),
         TransactionStatus::Succeeded,
     )
-    .await
+    .await;
+    _record_fee_parts(from, fee_parts).await;
+    res
 }
 
 #[update(name = "transferFrom")]
 #[candid_method(update, rename = "transferFrom")]
 async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
     let owner = ic::caller();
+    if _is_frozen(from) || _is_frozen(owner) {
+        return Err(TxError::Frozen);
+    }
     let from_allowance = allowance(from, owner);
-    let fee = _get_fee();
+    let fee = _get_fee_for(from, to, Some(&value));
     if from_allowance < value.clone() + fee.clone() {
         return Err(TxError::InsufficientAllowance);
     }
-    let from_balance = balance_of(from);
-    if from_balance < value.clone() + fee.clone() {
+    if _spendable_of(from) < value.clone() + fee.clone() {
         return Err(TxError::InsufficientBalance);
     }
-    _charge_fee(from, fee.clone());
+    let fee_parts = _charge_fee(from, fee.clone());
     _transfer(from, to, value.clone());
-    ALLOWS.with(|a| {
-        let mut allowances = a.borrow_mut();
-        match allowances.get(&from) {
-            Some(inner) => {
-                let result = inner.get(&owner).unwrap().clone();
-                let mut temp = inner.clone();
-                if result.clone() - value.clone() - fee.clone() != 0 {
-                    temp.insert(owner, result.clone() - value.clone() - fee.clone());
-                    allowances.insert(from, temp);
-                } else {
-                    temp.remove(&owner);
-                    if temp.len() == 0 {
-                        allowances.remove(&from);
-                    } else {
-                        allowances.insert(from, temp);
-                    }
-                }
-            }
-            None => {
-                assert!(false);
-            }
-        }
-    });
+    _allowance_set(from, owner, from_allowance - value.clone() - fee.clone());
     _history_inc();
-    add_record(
+    let res = add_record(
         owner,
         Operation::TransferFrom,
         from,
@@ -216,48 +1079,28 @@ async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt
         ic::time(),
         TransactionStatus::Succeeded,
     )
-    .await
+    .await;
+    _record_fee_parts(from, fee_parts).await;
+    res
 }
 
 #[update]
 #[candid_method(update)]
 async fn approve(spender: Principal, value: Nat) -> TxReceipt {
     let owner = ic::caller();
-    let fee = _get_fee();
-    if balance_of(owner) < fee.clone() {
+    if _is_frozen(owner) {
+        return Err(TxError::Frozen);
+    }
+    let fee = _get_fee_for(owner, spender, None);
+    if _spendable_of(owner) < fee.clone() {
         return Err(TxError::InsufficientBalance);
     }
-    _charge_fee(owner, fee.clone());
+    let fee_parts = _charge_fee(owner, fee.clone());
     let v = value.clone() + fee.clone();
-    ALLOWS.with(|a| {
-        let mut allowances = a.borrow_mut();
-        match allowances.get(&owner) {
-            Some(inner) => {
-                let mut temp = inner.clone();
-                if v.clone() != 0 {
-                    temp.insert(spender, v.clone());
-                    allowances.insert(owner, temp);
-                } else {
-                    temp.remove(&spender);
-                    if temp.len() == 0 {
-                        allowances.remove(&owner);
-                    } else {
-                        allowances.insert(owner, temp);
-                    }
-                }
-            }
-            None => {
-                if v.clone() != 0 {
-                    let mut inner = HashMap::new();
-                    inner.insert(spender, v.clone());
-                    allowances.insert(owner, inner);
-                }
-            }
-        }
-    });
+    _allowance_set(owner, spender, v.clone());
 
     _history_inc();
-    add_record(
+    let res = add_record(
         owner,
         Operation::Approve,
         owner,
@@ -267,25 +1110,22 @@ async fn approve(spender: Principal, value: Nat) -> TxReceipt {
         ic::time(),
         TransactionStatus::Succeeded,
     )
-    .await
+    .await;
+    _record_fee_parts(owner, fee_parts).await;
+    res
 }
 
 #[update]
 #[candid_method(update)]
 async fn burn(amount: Nat) -> TxReceipt {
     let caller = ic::caller();
-    let caller_balance = balance_of(caller);
-    if caller_balance.clone() < amount.clone() {
+    if _is_frozen(caller) {
+        return Err(TxError::Frozen);
+    }
+    if _spendable_of(caller) < amount.clone() {
         return Err(TxError::InsufficientBalance);
     }
-    BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        balances.insert(caller, caller_balance - amount.clone());
-    });
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        stats.total_supply -= amount.clone();
-    });
+    _burn_from(caller, amount.clone());
     _history_inc();
     add_record(
         caller,
@@ -300,6 +1140,365 @@ async fn burn(amount: Nat) -> TxReceipt {
     .await
 }
 
+#[update(name = "releaseVested")]
+#[candid_method(update, rename = "releaseVested")]
+fn release_vested() -> TxReceipt {
+    let released = _release_vested(ic::caller(), ic::time());
+    if released == 0 {
+        return Err(TxError::AmountTooSmall);
+    }
+    Ok(released)
+}
+
+#[update]
+#[candid_method(update)]
+async fn stake(amount: Nat, term_days: u64) -> TxReceipt {
+    let caller = ic::caller();
+    if _is_frozen(caller) {
+        return Err(TxError::Frozen);
+    }
+    if amount == 0 {
+        return Err(TxError::AmountTooSmall);
+    }
+    if _spendable_of(caller) < amount.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
+
+    let id = _open_stake_position(caller, amount.clone(), term_days, ic::time())?;
+    _balance_sub(caller, amount.clone());
+
+    _history_inc();
+    add_event(
+        caller,
+        "stake",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("from", DetailValue::Principal(caller)),
+            ("amount", amount.into()),
+            ("term", DetailValue::U64(term_days)),
+        ],
+    )
+    .await
+}
+
+// unlocking before the end of the term costs the early unlock penalty,
+// which is shared among the remaining stakers
+#[update]
+#[candid_method(update)]
+async fn unstake(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    let fee_to = STATS.with(|s| s.borrow().fee_to);
+    let (returned, emission_reward, penalty, penalty_to_fee_to) =
+        _close_stake_position(caller, id, ic::time())?;
+
+    _balance_add(caller, returned.clone());
+    if penalty_to_fee_to > 0u64 {
+        _balance_add(fee_to, penalty_to_fee_to);
+    }
+    if emission_reward > 0u64 {
+        _mint_reward(caller, emission_reward).await;
+    }
+
+    _history_inc();
+    add_event(
+        caller,
+        "unstake",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("to", DetailValue::Principal(caller)),
+            ("amount", returned.into()),
+            ("penalty", penalty.into()),
+        ],
+    )
+    .await
+}
+
+#[update(name = "claimStakeRewards")]
+#[candid_method(update, rename = "claimStakeRewards")]
+async fn claim_stake_rewards(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    let (fee_reward, emission_reward) = _claim_stake_rewards(caller, id, ic::time())?;
+    if fee_reward == 0 && emission_reward == 0 {
+        return Err(TxError::AmountTooSmall);
+    }
+
+    // the emission part is recorded as a mint
+    if emission_reward > 0u64 {
+        _mint_reward(caller, emission_reward.clone()).await;
+    }
+    if fee_reward > 0u64 {
+        _balance_add(caller, fee_reward.clone());
+        _history_inc();
+        let _ = add_event(
+            caller,
+            "stakeReward",
+            vec![
+                ("id", DetailValue::U64(id)),
+                ("to", DetailValue::Principal(caller)),
+                ("amount", fee_reward.clone().into()),
+            ],
+        )
+        .await;
+    }
+    Ok(fee_reward + emission_reward)
+}
+
+// Transfers `value` to `to` and then calls its onTokenTransfer with a
+// TransferNotification. The call is one-way, so a recipient that never
+// replies cannot hold wdn up. The transfer stands even if the callback cannot
+// be sent; the failure is kept on the notification and can be retried with
+// retryNotification. Returns the notification id.
+#[update(name = "transferAndCall")]
+#[candid_method(update, rename = "transferAndCall")]
+async fn transfer_and_call(to: Principal, value: Nat, payload: Vec<u8>) -> Result<u64, TxError> {
+    let from = ic::caller();
+    if _is_frozen(from) {
+        return Err(TxError::Frozen);
+    }
+    if payload.len() > MAX_NOTIFICATION_PAYLOAD_SIZE as usize {
+        return Err(TxError::Other("payload too large".to_string()));
+    }
+    _check_notification_recipient(to, ic::id())?;
+    let fee = _get_fee_for(from, to, Some(&value));
+    if _spendable_of(from) < value.clone() + fee.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
+    let fee_parts = _charge_fee(from, fee.clone());
+    _transfer(from, to, value.clone());
+    let id = _add_notification(Notification {
+        id: 0,
+        from,
+        to,
+        amount: value.clone(),
+        payload,
+        status: NotificationStatus::Pending,
+        attempts: 0,
+        created_at: ic::time(),
+        last_attempt_at: 0,
+    });
+    _history_inc();
+    let _ = add_record(
+        from,
+        Operation::Transfer,
+        from,
+        to,
+        value,
+        fee,
+        ic::time(),
+        TransactionStatus::Succeeded,
+    )
+    .await;
+    _record_fee_parts(from, fee_parts).await;
+    _deliver_notification(id);
+    Ok(id)
+}
+
+// Sends a failed notification again. Delivered notifications are left
+// alone, so retrying is safe to repeat.
+#[update(name = "retryNotification")]
+#[candid_method(update, rename = "retryNotification")]
+fn retry_notification(id: u64) -> Result<NotificationStatus, TxError> {
+    if _can_retry_notification(id, ic::caller())? {
+        _deliver_notification(id);
+    }
+    Ok(_notification_status(id))
+}
+
+// Locks `value` of the caller's balance for `payee`. The arbiter releases it
+// to the payee or refunds the payer; after `deadline` the payer is refunded.
+#[update(name = "createEscrow")]
+#[candid_method(update, rename = "createEscrow")]
+async fn create_escrow(
+    payee: Principal,
+    arbiter: Principal,
+    value: Nat,
+    deadline: u64,
+) -> Result<u64, TxError> {
+    let payer = ic::caller();
+    if _is_frozen(payer) {
+        return Err(TxError::Frozen);
+    }
+    let now = ic::time();
+    if value == 0 {
+        return Err(TxError::AmountTooSmall);
+    }
+    if deadline <= now {
+        return Err(TxError::Other("deadline has passed".to_string()));
+    }
+    let fee = _get_fee_for(payer, payee, Some(&value));
+    if _spendable_of(payer) < value.clone() + fee.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
+    let fee_parts = _charge_fee(payer, fee.clone());
+    _balance_sub(payer, value.clone());
+    let id = _add_escrow(Escrow {
+        id: 0,
+        payer,
+        payee,
+        arbiter,
+        amount: value.clone(),
+        deadline,
+        created_at: now,
+        status: EscrowStatus::Open,
+    });
+    _history_inc();
+    let _ = add_event(
+        payer,
+        "escrowCreate",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("from", DetailValue::Principal(payer)),
+            ("to", DetailValue::Principal(payee)),
+            ("arbiter", DetailValue::Principal(arbiter)),
+            ("amount", value.into()),
+            ("fee", fee.into()),
+            ("deadline", DetailValue::U64(deadline)),
+        ],
+    )
+    .await;
+    _record_fee_parts(payer, fee_parts).await;
+    Ok(id)
+}
+
+#[update(name = "releaseEscrow")]
+#[candid_method(update, rename = "releaseEscrow")]
+async fn release_escrow(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    match get_escrow(id) {
+        Some(escrow) if escrow.arbiter == caller => {}
+        _ => return Err(TxError::Unauthorized),
+    }
+    let escrow = _settle_escrow(id, EscrowStatus::Released)
+        .ok_or_else(|| TxError::Other("escrow is closed".to_string()))?;
+    _balance_add(escrow.payee, escrow.amount.clone());
+    _history_inc();
+    add_event(
+        caller,
+        "escrowRelease",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("to", DetailValue::Principal(escrow.payee)),
+            ("amount", escrow.amount.into()),
+        ],
+    )
+    .await
+}
+
+// by the arbiter at any time, or by the payer once the deadline has passed
+#[update(name = "refundEscrow")]
+#[candid_method(update, rename = "refundEscrow")]
+async fn refund_escrow(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    let now = ic::time();
+    match get_escrow(id) {
+        Some(escrow)
+            if escrow.arbiter == caller || (escrow.payer == caller && escrow.deadline <= now) => {}
+        _ => return Err(TxError::Unauthorized),
+    }
+    let escrow = _settle_escrow(id, EscrowStatus::Refunded)
+        .ok_or_else(|| TxError::Other("escrow is closed".to_string()))?;
+    _refund_escrow(caller, escrow).await
+}
+
+// Locks `deposit` of the caller's balance and streams it to `recipient` at
+// `rate_per_second` from now on.
+#[update(name = "createStream")]
+#[candid_method(update, rename = "createStream")]
+async fn create_stream(
+    recipient: Principal,
+    rate_per_second: Nat,
+    deposit: Nat,
+) -> Result<u64, TxError> {
+    let sender = ic::caller();
+    if _is_frozen(sender) {
+        return Err(TxError::Frozen);
+    }
+    if rate_per_second == 0 || deposit < rate_per_second {
+        return Err(TxError::AmountTooSmall);
+    }
+    let fee = _get_fee_for(sender, recipient, Some(&deposit));
+    if _spendable_of(sender) < deposit.clone() + fee.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
+    let fee_parts = _charge_fee(sender, fee.clone());
+    _balance_sub(sender, deposit.clone());
+    let id = _add_stream(Stream {
+        id: 0,
+        sender,
+        recipient,
+        rate_per_second: rate_per_second.clone(),
+        deposit: deposit.clone(),
+        withdrawn: Nat::from(0),
+        start: ic::time(),
+        canceled_at: None,
+        status: StreamStatus::Active,
+    });
+    _history_inc();
+    let _ = add_event(
+        sender,
+        "streamCreate",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("from", DetailValue::Principal(sender)),
+            ("to", DetailValue::Principal(recipient)),
+            ("rate", rate_per_second.into()),
+            ("amount", deposit.into()),
+            ("fee", fee.into()),
+        ],
+    )
+    .await;
+    _record_fee_parts(sender, fee_parts).await;
+    Ok(id)
+}
+
+// pays the recipient what has accrued since the last withdrawal
+#[update(name = "withdrawFromStream")]
+#[candid_method(update, rename = "withdrawFromStream")]
+async fn withdraw_from_stream(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    let amount = _withdraw_from_stream(caller, id, ic::time())?;
+    _balance_add(caller, amount.clone());
+    _history_inc();
+    add_event(
+        caller,
+        "streamWithdraw",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("to", DetailValue::Principal(caller)),
+            ("amount", amount.into()),
+        ],
+    )
+    .await
+}
+
+// Stops the stream, by the sender or the recipient. The recipient gets what
+// has accrued up to now and the sender gets back the rest of the deposit.
+#[update(name = "cancelStream")]
+#[candid_method(update, rename = "cancelStream")]
+async fn cancel_stream(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    let (stream, to_recipient, to_sender) = _cancel_stream(caller, id, ic::time())?;
+    if to_recipient > 0u64 {
+        _balance_add(stream.recipient, to_recipient.clone());
+    }
+    if to_sender > 0u64 {
+        _balance_add(stream.sender, to_sender.clone());
+    }
+    _history_inc();
+    add_event(
+        caller,
+        "streamCancel",
+        vec![
+            ("id", DetailValue::U64(id)),
+            ("from", DetailValue::Principal(stream.sender)),
+            ("to", DetailValue::Principal(stream.recipient)),
+            ("recipientAmount", to_recipient.into()),
+            ("senderAmount", to_sender.into()),
+        ],
+    )
+    .await
+}
+
 /* QUERY FNS */
 
 #[query(name = "balanceOf")]
@@ -307,8 +1506,8 @@ async fn burn(amount: Nat) -> TxReceipt {
 fn balance_of(id: Principal) -> Nat {
     BALANCES.with(|b| {
         let balances = b.borrow();
-        match balances.get(&id) {
-            Some(balance) => balance.clone(),
+        match balances.get(&PrincipalKey(id)) {
+            Some(balance) => balance.0,
             None => Nat::from(0),
         }
     })
@@ -319,11 +1518,8 @@ fn balance_of(id: Principal) -> Nat {
 fn allowance(owner: Principal, spender: Principal) -> Nat {
     ALLOWS.with(|a| {
         let allowances = a.borrow();
-        match allowances.get(&owner) {
-            Some(inner) => match inner.get(&spender) {
-                Some(value) => value.clone(),
-                None => Nat::from(0),
-            },
+        match allowances.get(&AllowanceKey { owner, spender }) {
+            Some(value) => value.0,
             None => Nat::from(0),
         }
     })
@@ -392,6 +1588,136 @@ fn minter() -> Principal {
     })
 }
 
+#[query]
+#[candid_method(query)]
+fn governance() -> Option<Principal> {
+    STATS.with(|s| {
+        let stats = s.borrow();
+        stats.governance
+    })
+}
+
+#[query(name = "getNotification")]
+#[candid_method(query, rename = "getNotification")]
+fn get_notification(id: u64) -> Option<Notification> {
+    _get_notification(id)
+}
+
+// newest first
+#[query(name = "getNotifications")]
+#[candid_method(query, rename = "getNotifications")]
+fn get_notifications(who: Principal, start: usize, limit: usize) -> Vec<Notification> {
+    _account_index_page(&NOTIFICATION_ACCOUNTS, who, start, limit)
+        .into_iter()
+        .filter_map(_get_notification)
+        .collect()
+}
+
+#[query(name = "getEscrow")]
+#[candid_method(query, rename = "getEscrow")]
+fn get_escrow(id: u64) -> Option<Escrow> {
+    ESCROW_ITEMS.with(|e| e.borrow().get(&id))
+}
+
+// escrows where `who` is payer, payee or arbiter, newest first
+#[query(name = "getEscrows")]
+#[candid_method(query, rename = "getEscrows")]
+fn get_escrows(who: Principal, start: usize, limit: usize) -> Vec<Escrow> {
+    _account_index_page(&ESCROW_ACCOUNTS, who, start, limit)
+        .into_iter()
+        .filter_map(get_escrow)
+        .collect()
+}
+
+// open escrows paid by `who`
+#[query(name = "escrowedBalanceOf")]
+#[candid_method(query, rename = "escrowedBalanceOf")]
+fn escrowed_balance_of(who: Principal) -> Nat {
+    ESCROWED.with(|e| {
+        e.borrow()
+            .get(&PrincipalKey(who))
+            .map_or_else(|| Nat::from(0), |v| v.0)
+    })
+}
+
+#[query(name = "getStream")]
+#[candid_method(query, rename = "getStream")]
+fn get_stream(id: u64) -> Option<StreamInfo> {
+    let now = ic::time();
+    STREAM_ITEMS
+        .with(|s| s.borrow().get(&id))
+        .map(|stream| StreamInfo {
+            withdrawable: stream.withdrawable_at(now),
+            stream,
+        })
+}
+
+// newest first
+#[query(name = "getStreamsBySender")]
+#[candid_method(query, rename = "getStreamsBySender")]
+fn get_streams_by_sender(sender: Principal, start: usize, limit: usize) -> Vec<StreamInfo> {
+    _streams_in(&STREAM_SENDERS, sender, start, limit)
+}
+
+// newest first
+#[query(name = "getStreamsByRecipient")]
+#[candid_method(query, rename = "getStreamsByRecipient")]
+fn get_streams_by_recipient(recipient: Principal, start: usize, limit: usize) -> Vec<StreamInfo> {
+    _streams_in(&STREAM_RECIPIENTS, recipient, start, limit)
+}
+
+#[query(name = "getCyclesInfo")]
+#[candid_method(query, rename = "getCyclesInfo")]
+fn get_cycles_info() -> CyclesInfo {
+    CYCLES.with(|c| c.borrow().info(ic::balance()))
+}
+
+#[query(name = "getFeePolicy")]
+#[candid_method(query, rename = "getFeePolicy")]
+fn get_fee_policy() -> FeePolicy {
+    FEE_POLICY.with(|f| f.borrow().clone())
+}
+
+#[query(name = "getFeeFor")]
+#[candid_method(query, rename = "getFeeFor")]
+fn get_fee_for(from: Principal, to: Principal, amount: Nat) -> Nat {
+    _get_fee_for(from, to, Some(&amount))
+}
+
+#[query(name = "getFeeExempt")]
+#[candid_method(query, rename = "getFeeExempt")]
+fn get_fee_exempt() -> Vec<Principal> {
+    FEE_EXEMPT.with(|f| f.borrow().iter().map(|(k, _)| k.0).collect())
+}
+
+#[query(name = "getFreeze")]
+#[candid_method(query, rename = "getFreeze")]
+fn get_freeze(who: Principal) -> Option<FreezeRecord> {
+    FROZEN.with(|f| f.borrow().get(&PrincipalKey(who)))
+}
+
+#[query(name = "getFrozenAccounts")]
+#[candid_method(query, rename = "getFrozenAccounts")]
+fn get_frozen_accounts() -> Vec<(Principal, FreezeRecord)> {
+    FROZEN.with(|f| f.borrow().iter().map(|(k, v)| (k.0, v)).collect())
+}
+
+#[query(name = "getFreezeLog")]
+#[candid_method(query, rename = "getFreezeLog")]
+fn get_freeze_log(start: usize, limit: usize) -> Vec<FreezeLogEntry> {
+    FREEZE_LOG.with(|f| {
+        let log = f.borrow();
+        let end = std::cmp::min(start.saturating_add(limit) as u64, log.len());
+        (start as u64..end).filter_map(|i| log.get(i)).collect()
+    })
+}
+
+#[query(name = "getFeeTier")]
+#[candid_method(query, rename = "getFeeTier")]
+fn get_fee_tier() -> Option<FeeTier> {
+    FEE_RULES.with(|f| f.borrow().tier.clone())
+}
+
 #[query(name = "getMetadata")]
 #[candid_method(query, rename = "getMetadata")]
 fn get_metadata() -> Metadata {
@@ -409,6 +1735,42 @@ fn get_metadata() -> Metadata {
     })
 }
 
+#[query(name = "certifiedBalanceOf")]
+#[candid_method(query, rename = "certifiedBalanceOf")]
+fn certified_balance_of(id: Principal) -> CertifiedNat {
+    // right after an upgrade the witness may not cover the balance yet
+    let certificate = if _is_balance_certified(id) {
+        ic::data_certificate()
+    } else {
+        None
+    };
+    CertifiedNat {
+        value: balance_of(id),
+        witness: _certified_witness(&_certified_balance_key(id)),
+        certificate,
+    }
+}
+
+#[query(name = "certifiedTotalSupply")]
+#[candid_method(query, rename = "certifiedTotalSupply")]
+fn certified_total_supply() -> CertifiedNat {
+    CertifiedNat {
+        value: total_supply(),
+        witness: _certified_witness(CERTIFIED_TOTAL_SUPPLY),
+        certificate: ic::data_certificate(),
+    }
+}
+
+#[query(name = "certifiedMetadata")]
+#[candid_method(query, rename = "certifiedMetadata")]
+fn certified_metadata() -> CertifiedMetadata {
+    CertifiedMetadata {
+        value: get_metadata(),
+        witness: _certified_witness(CERTIFIED_METADATA),
+        certificate: ic::data_certificate(),
+    }
+}
+
 #[query(name = "historySize")]
 #[candid_method(query, rename = "historySize")]
 fn history_size() -> usize {
@@ -430,7 +1792,7 @@ fn get_token_info() -> TokenInfo {
                 feeTo: stats.fee_to,
                 historySize: stats.history_size,
                 deployTime: stats.deploy_time,
-                holderNumber: balances.len(),
+                holderNumber: balances.len() as usize,
                 cycles: ic::balance(),
             }
         })
@@ -440,84 +1802,444 @@ fn get_token_info() -> TokenInfo {
 #[query(name = "getHolders")]
 #[candid_method(query, rename = "getHolders")]
 fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
-    BALANCES.with(|b| {
-        let balances = b.borrow();
-        let mut balance = Vec::new();
-        for (k, v) in balances.iter() {
-            balance.push((k.clone(), v.clone()));
+    match _holder_at(start as u64) {
+        Some(first) => _holders_from(Bound::Included(first), limit),
+        None => Vec::new(),
+    }
+}
+
+// pages through the holders in getHolders order, `after` is the last entry
+// of the previous page
+#[query(name = "getHoldersAfter")]
+#[candid_method(query, rename = "getHoldersAfter")]
+fn get_holders_after(after: Option<(Principal, Nat)>, limit: usize) -> Vec<(Principal, Nat)> {
+    match after {
+        Some((holder, balance)) => {
+            _holders_from(Bound::Excluded(HolderKey { balance, holder }), limit)
         }
-        balance.sort_by(|a, b| b.1.cmp(&a.1));
-        let limit: usize = if start + limit > balance.len() {
-            balance.len() - start
-        } else {
-            limit
-        };
-        balance[start..start + limit].to_vec()
-    })
+        None => _holders_from(Bound::Unbounded, limit),
+    }
+}
+
+// 1-based position in getHolders, None if the account holds nothing
+#[query(name = "getHolderRank")]
+#[candid_method(query, rename = "getHolderRank")]
+fn get_holder_rank(who: Principal) -> Option<u64> {
+    let balance = balance_of(who);
+    if balance == 0 {
+        return None;
+    }
+    Some(
+        _holders_before(&HolderKey {
+            balance,
+            holder: who,
+        }) + 1,
+    )
 }
 
 #[query(name = "getAllowanceSize")]
 #[candid_method(query, rename = "getAllowanceSize")]
 fn get_allowance_size() -> usize {
-    ALLOWS.with(|a| {
-        let allowances = a.borrow();
-        let mut size = 0;
-        for (_, v) in allowances.iter() {
-            size += v.len();
-        }
-        size
-    })
+    ALLOWS.with(|a| a.borrow().len() as usize)
 }
 
 #[query(name = "getUserApprovals")]
 #[candid_method(query, rename = "getUserApprovals")]
 fn get_user_approvals(who: Principal) -> Vec<(Principal, Nat)> {
+    let from = AllowanceKey {
+        owner: who,
+        spender: Principal::management_canister(),
+    };
     ALLOWS.with(|a| {
-        let allowances = a.borrow();
-        match allowances.get(&who) {
-            Some(allow) => Vec::from_iter(allow.clone().into_iter()),
-            None => Vec::new(),
-        }
+        a.borrow()
+            .range(from..)
+            .take_while(|(k, _)| k.owner == who)
+            .map(|(k, v)| (k.spender, v.0))
+            .collect()
     })
 }
 
-/* CONTROLLER FNS */
+#[query(name = "getTransaction")]
+#[candid_method(query, rename = "getTransaction")]
+fn get_transaction(index: u64) -> Option<HistoryEntry> {
+    HISTORY.with(|h| h.borrow().get(index))
+}
 
-#[update(guard = "_is_minter")]
-#[candid_method(update, rename = "mint")]
-async fn mint(to: Principal, amount: Nat) -> TxReceipt {
-    let caller = ic::caller();
-    let to_balance = balance_of(to);
+#[query(name = "getTransactions")]
+#[candid_method(query, rename = "getTransactions")]
+fn get_transactions(start: u64, limit: u64) -> Vec<HistoryEntry> {
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        let end = std::cmp::min(start.saturating_add(limit), history.len());
+        (start..end).filter_map(|i| history.get(i)).collect()
+    })
+}
 
-    BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        balances.insert(to, to_balance + amount.clone());
-    });
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        stats.total_supply += amount.clone();
-    });
-    _history_inc();
-    add_record(
-        caller,
-        Operation::Mint,
-        caller,
-        to,
-        amount,
-        Nat::from(0),
-        ic::time(),
-        TransactionStatus::Succeeded,
-    )
-    .await
+// Read-only HTTP interface for monitoring and listings. Amounts are JSON
+// strings since they do not fit into a JSON number.
+#[query]
+#[candid_method(query)]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if req.method != "GET" {
+        return _http_response(405, "text/plain", "method not allowed".to_string());
+    }
+    let (path, query) = match req.url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (req.url.as_str(), ""),
+    };
+    match path {
+        "/metadata" => {
+            let info = get_token_info();
+            let m = info.metadata;
+            let body = serde_json::json!({
+                "name": m.name,
+                "symbol": m.symbol,
+                "decimals": m.decimals,
+                "logo": m.logo,
+                "owner": m.owner.to_text(),
+                "fee": m.fee.0.to_string(),
+                "feeTo": info.feeTo.to_text(),
+                "totalSupply": m.totalSupply.0.to_string(),
+                "historySize": info.historySize,
+                "deployTime": info.deployTime,
+                "holderNumber": info.holderNumber,
+            });
+            _http_response(200, "application/json", body.to_string())
+        }
+        "/supply" => {
+            let body = serde_json::json!({ "totalSupply": total_supply().0.to_string() });
+            _http_response(200, "application/json", body.to_string())
+        }
+        "/holders" => {
+            let start = _http_param(query, "start").unwrap_or(0);
+            let limit = std::cmp::min(_http_param(query, "limit").unwrap_or(100), 1000);
+            let holders: Vec<serde_json::Value> = get_holders(start, limit)
+                .into_iter()
+                .map(|(p, b)| {
+                    serde_json::json!({ "principal": p.to_text(), "balance": b.0.to_string() })
+                })
+                .collect();
+            _http_response(
+                200,
+                "application/json",
+                serde_json::Value::from(holders).to_string(),
+            )
+        }
+        "/metrics" => _http_response(200, "text/plain; version=0.0.4", _http_metrics()),
+        _ => match path.strip_prefix("/tx/").map(|i| i.parse::<u64>()) {
+            Some(Ok(index)) => match get_transaction(index) {
+                Some(entry) => _http_response(
+                    200,
+                    "application/json",
+                    _history_entry_json(index, entry).to_string(),
+                ),
+                None => _http_response(404, "text/plain", "not found".to_string()),
+            },
+            _ => _http_response(404, "text/plain", "not found".to_string()),
+        },
+    }
 }
 
-#[update(name = "setMinter", guard = "_is_auth")]
-#[candid_method(update, rename = "setMinter")]
-fn set_minter(minter: Principal) {
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        stats.minter = minter;
+#[query(name = "balanceOfAt")]
+#[candid_method(query, rename = "balanceOfAt")]
+fn balance_of_at(id: Principal, snapshot_id: u64) -> TxReceipt {
+    if !_is_valid_snapshot(snapshot_id) {
+        return Err(TxError::Other("invalid snapshot id".to_string()));
+    }
+    Ok(_balance_of_at(id, snapshot_id))
+}
+
+#[query(name = "totalSupplyAt")]
+#[candid_method(query, rename = "totalSupplyAt")]
+fn total_supply_at(snapshot_id: u64) -> TxReceipt {
+    if !_is_valid_snapshot(snapshot_id) {
+        return Err(TxError::Other("invalid snapshot id".to_string()));
+    }
+    let recorded = SNAPSHOT_SUPPLY.with(|s| {
+        s.borrow()
+            .range(snapshot_id..)
+            .next()
+            .map(|(_, supply)| supply.0)
     });
+    Ok(recorded.unwrap_or_else(total_supply))
+}
+
+// holders at the snapshot, in principal order
+#[query(name = "getSnapshotHolders")]
+#[candid_method(query, rename = "getSnapshotHolders")]
+fn get_snapshot_holders(snapshot_id: u64, start: usize, limit: usize) -> Vec<(Principal, Nat)> {
+    if !_is_valid_snapshot(snapshot_id) {
+        return Vec::new();
+    }
+    _snapshot_holders_after(snapshot_id, None)
+        .skip(start)
+        .take(limit)
+        .collect()
+}
+
+// cursor based paging: returns the holders at the snapshot that come after
+// `after` in principal order, from the first one if it is null
+#[query(name = "getSnapshotHoldersAfter")]
+#[candid_method(query, rename = "getSnapshotHoldersAfter")]
+fn get_snapshot_holders_after(
+    snapshot_id: u64,
+    after: Option<Principal>,
+    limit: usize,
+) -> Vec<(Principal, Nat)> {
+    if !_is_valid_snapshot(snapshot_id) {
+        return Vec::new();
+    }
+    _snapshot_holders_after(snapshot_id, after)
+        .take(limit)
+        .collect()
+}
+
+#[query(name = "currentSnapshotId")]
+#[candid_method(query, rename = "currentSnapshotId")]
+fn current_snapshot_id() -> u64 {
+    SNAPSHOTS.with(|s| s.borrow().current_id)
+}
+
+#[query(name = "getVestingSchedules")]
+#[candid_method(query, rename = "getVestingSchedules")]
+fn get_vesting_schedules(beneficiary: Principal) -> Vec<VestingStatus> {
+    let now = ic::time();
+    _vesting_schedules_of(beneficiary)
+        .into_iter()
+        .map(|s| {
+            let vested = _vested_amount(&s, now);
+            VestingStatus {
+                releasable: vested.clone() - s.released.clone(),
+                vested,
+                schedule: s,
+            }
+        })
+        .collect()
+}
+
+#[query(name = "lockedBalanceOf")]
+#[candid_method(query, rename = "lockedBalanceOf")]
+fn locked_balance_of(id: Principal) -> Nat {
+    let mut locked = Nat::from(0);
+    for s in _vesting_schedules_of(id) {
+        locked += s.total - s.released;
+    }
+    locked
+}
+
+#[query(name = "getStakePositions")]
+#[candid_method(query, rename = "getStakePositions")]
+fn get_stake_positions(owner: Principal) -> Vec<StakePositionInfo> {
+    let now = ic::time();
+    let positions = _stake_positions_of(owner);
+    STAKING.with(|s| {
+        let staking = s.borrow();
+        positions
+            .into_iter()
+            .map(|p| StakePositionInfo {
+                pending_reward: staking.pending_at(&p, now),
+                position: p,
+            })
+            .collect()
+    })
+}
+
+#[query(name = "getStakingInfo")]
+#[candid_method(query, rename = "getStakingInfo")]
+fn get_staking_info() -> StakingInfo {
+    STAKING.with(|s| {
+        let staking = s.borrow();
+        let emission_per_year = staking.emission_per_second.clone() * Nat::from(365 * 86400);
+        let apr_bps = staking
+            .terms
+            .iter()
+            .map(|t| {
+                let apr = if staking.total_weight > 0u64 {
+                    emission_per_year.clone() * Nat::from(t.weight_bps)
+                        / staking.total_weight.clone()
+                } else {
+                    Nat::from(0)
+                };
+                (t.days, apr)
+            })
+            .collect();
+        StakingInfo {
+            terms: staking.terms.clone(),
+            emission_per_second: staking.emission_per_second.clone(),
+            fee_share_bps: match FEE_POLICY.with(|f| f.borrow().clone()) {
+                FeePolicy::Split(split) => split.staking_bps,
+                _ => 0,
+            },
+            early_unlock_penalty_bps: staking.early_unlock_penalty_bps,
+            total_staked: staking.total_staked.clone(),
+            total_weight: staking.total_weight.clone(),
+            apr_bps,
+        }
+    })
+}
+
+/* CONTROLLER FNS */
+
+#[update(name = "takeSnapshot", guard = "_is_auth")]
+#[candid_method(update, rename = "takeSnapshot")]
+fn take_snapshot() -> u64 {
+    SNAPSHOTS.with(|s| {
+        let mut snapshots = s.borrow_mut();
+        snapshots.current_id += 1;
+        snapshots.current_id
+    })
+}
+
+#[update(name = "createVesting", guard = "_is_auth")]
+#[candid_method(update, rename = "createVesting")]
+async fn create_vesting(
+    beneficiary: Principal,
+    amount: Nat,
+    start: u64,
+    cliff: u64,
+    duration: u64,
+    interval: u64,
+    revocable: bool,
+) -> TxReceipt {
+    let caller = ic::caller();
+    if amount == 0 {
+        return Err(TxError::AmountTooSmall);
+    }
+    if duration == 0 || cliff > duration || interval > duration {
+        return Err(TxError::Other("invalid vesting schedule".to_string()));
+    }
+    if _spendable_of(caller) < amount.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
+    let start = if start == 0 { ic::time() } else { start };
+
+    _transfer(caller, beneficiary, amount.clone());
+    _add_vesting_schedule(VestingSchedule {
+        id: 0,
+        beneficiary,
+        total: amount.clone(),
+        released: Nat::from(0),
+        start,
+        cliff,
+        duration,
+        interval,
+        revocable,
+        revoked: false,
+    });
+    _history_inc();
+    add_record(
+        caller,
+        Operation::Transfer,
+        caller,
+        beneficiary,
+        amount,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+    )
+    .await
+}
+
+// the vested part stays with the beneficiary, the rest returns to the caller
+#[update(name = "revokeVesting", guard = "_is_auth")]
+#[candid_method(update, rename = "revokeVesting")]
+async fn revoke_vesting(id: u64) -> TxReceipt {
+    let caller = ic::caller();
+    let (beneficiary, unvested) = _revoke_vesting(id, ic::time())?;
+    if unvested == 0 {
+        return Ok(Nat::from(0));
+    }
+
+    _transfer(beneficiary, caller, unvested.clone());
+    _history_inc();
+    add_record(
+        caller,
+        Operation::Transfer,
+        beneficiary,
+        caller,
+        unvested,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+    )
+    .await
+}
+
+#[update(guard = "_is_minter")]
+#[candid_method(update, rename = "mint")]
+async fn mint(to: Principal, amount: Nat) -> TxReceipt {
+    let caller = ic::caller();
+    let to_balance = balance_of(to);
+
+    _balance_ins(to, to_balance + amount.clone());
+    _snapshot_supply();
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.total_supply += amount.clone();
+    });
+    _certify_metadata();
+    _history_inc();
+    add_record(
+        caller,
+        Operation::Mint,
+        caller,
+        to,
+        amount,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+    )
+    .await
+}
+
+#[update(name = "setMinter", guard = "_is_auth")]
+#[candid_method(update, rename = "setMinter")]
+fn set_minter(minter: Principal) {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.minter = minter;
+    });
+}
+
+#[update(name = "setGovernance", guard = "_is_auth")]
+#[candid_method(update, rename = "setGovernance")]
+fn set_governance(governance: Option<Principal>) {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.governance = governance;
+    });
+}
+
+#[update(name = "setStakingTerms", guard = "_is_auth")]
+#[candid_method(update, rename = "setStakingTerms")]
+fn set_staking_terms(terms: Vec<StakingTerm>) {
+    STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        staking.terms = terms;
+    });
+}
+
+#[update(name = "setStakingEmission", guard = "_is_auth")]
+#[candid_method(update, rename = "setStakingEmission")]
+fn set_staking_emission(emission_per_second: Nat) {
+    STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        staking.update_pool(ic::time());
+        staking.emission_per_second = emission_per_second;
+    });
+}
+
+#[update(name = "setEarlyUnlockPenalty", guard = "_is_auth")]
+#[candid_method(update, rename = "setEarlyUnlockPenalty")]
+fn set_early_unlock_penalty(penalty_bps: u64) -> Result<(), TxError> {
+    if penalty_bps > 10000 {
+        return Err(TxError::Other("penalty above 10000 bps".to_string()));
+    }
+    STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        staking.early_unlock_penalty_bps = penalty_bps;
+    });
+    Ok(())
 }
 
 #[update(name = "setName", guard = "_is_auth")]
@@ -527,6 +2249,7 @@ fn set_name(name: String) {
         let mut stats = s.borrow_mut();
         stats.name = name;
     });
+    _certify_metadata();
 }
 
 #[update(name = "setLogo", guard = "_is_auth")]
@@ -536,6 +2259,7 @@ fn set_logo(logo: String) {
         let mut stats = s.borrow_mut();
         stats.logo = logo;
     });
+    _certify_metadata();
 }
 
 #[update(name = "setFee", guard = "_is_auth")]
@@ -545,6 +2269,118 @@ fn set_fee(fee: Nat) {
         let mut stats = s.borrow_mut();
         stats.fee = fee;
     });
+    _certify_metadata();
+}
+
+#[update(name = "setFeePolicy", guard = "_is_auth")]
+#[candid_method(update, rename = "setFeePolicy")]
+fn set_fee_policy(policy: FeePolicy) -> Result<(), TxError> {
+    policy.validate()?;
+    FEE_POLICY.with(|f| {
+        let mut fee_policy = f.borrow_mut();
+        *fee_policy = policy;
+    });
+    Ok(())
+}
+
+// kept from before the fee policy, only changes the stakers' part of it
+#[update(name = "setStakingFeeShare", guard = "_is_auth")]
+#[candid_method(update, rename = "setStakingFeeShare")]
+fn set_staking_fee_share(fee_share_bps: u64) -> Result<(), TxError> {
+    let fee_to = STATS.with(|s| s.borrow().fee_to);
+    let policy = FEE_POLICY
+        .with(|f| f.borrow().clone())
+        .with_staking_share(fee_share_bps, fee_to);
+    set_fee_policy(policy)
+}
+
+#[update(name = "addFeeExempt", guard = "_is_auth")]
+#[candid_method(update, rename = "addFeeExempt")]
+fn add_fee_exempt(id: Principal) {
+    FEE_EXEMPT.with(|f| f.borrow_mut().insert(PrincipalKey(id), ()));
+}
+
+#[update(name = "removeFeeExempt", guard = "_is_auth")]
+#[candid_method(update, rename = "removeFeeExempt")]
+fn remove_fee_exempt(id: Principal) {
+    FEE_EXEMPT.with(|f| f.borrow_mut().remove(&PrincipalKey(id)));
+}
+
+#[update(name = "freezeAccount", guard = "_is_auth")]
+#[candid_method(update, rename = "freezeAccount")]
+fn freeze_account(who: Principal, reason: String) -> Result<(), TxError> {
+    _check_freeze_reason(&reason)?;
+    FROZEN.with(|f| {
+        f.borrow_mut().insert(
+            PrincipalKey(who),
+            FreezeRecord {
+                reason: reason.clone(),
+                frozen_at: ic::time(),
+                frozen_by: ic::caller(),
+            },
+        )
+    });
+    _freeze_audit(who, FreezeAction::Freeze, reason);
+    Ok(())
+}
+
+#[update(name = "unfreezeAccount", guard = "_is_auth")]
+#[candid_method(update, rename = "unfreezeAccount")]
+fn unfreeze_account(who: Principal, reason: String) -> Result<(), TxError> {
+    _check_freeze_reason(&reason)?;
+    if FROZEN
+        .with(|f| f.borrow_mut().remove(&PrincipalKey(who)))
+        .is_some()
+    {
+        _freeze_audit(who, FreezeAction::Unfreeze, reason);
+    }
+    Ok(())
+}
+
+// Recovery of funds from a frozen account, e.g. to a new key of a holder
+// whose key was stolen. No fee is charged. Tokens still locked by vesting
+// stay where they are, so that revoking the schedule can take them back.
+#[update(name = "forceTransfer", guard = "_is_auth")]
+#[candid_method(update, rename = "forceTransfer")]
+async fn force_transfer(from: Principal, to: Principal, value: Nat, reason: String) -> TxReceipt {
+    _force_transfer(from, to, value.clone(), reason.clone())?;
+    _history_inc();
+    add_event(
+        ic::caller(),
+        "forceTransfer",
+        vec![
+            ("from", DetailValue::Principal(from)),
+            ("to", DetailValue::Principal(to)),
+            ("amount", value.into()),
+            ("reason", DetailValue::Text(reason)),
+        ],
+    )
+    .await
+}
+
+// None turns automatic top-ups off
+#[update(name = "setTopUpConfig", guard = "_is_auth")]
+#[candid_method(update, rename = "setTopUpConfig")]
+fn set_top_up_config(config: Option<TopUpConfig>) {
+    CYCLES.with(|c| c.borrow_mut().set_config(config));
+}
+
+#[update(name = "setFeeTier", guard = "_is_auth")]
+#[candid_method(update, rename = "setFeeTier")]
+fn set_fee_tier(tier: Option<FeeTier>) -> Result<(), TxError> {
+    if let Some(t) = &tier {
+        if t.rate_bps > 10000 {
+            return Err(TxError::Other("fee rate above 10000 bps".to_string()));
+        }
+        if t.min > t.max {
+            return Err(TxError::Other("minimum fee above the maximum".to_string()));
+        }
+    }
+    FEE_RULES.with(|f| {
+        let mut rules = f.borrow_mut();
+        rules.tier = tier;
+    });
+    Ok(())
 }
 
 #[update(name = "setFeeTo", guard = "_is_auth")]
@@ -563,6 +2399,7 @@ fn set_owner(owner: Principal) {
         let mut stats = s.borrow_mut();
         stats.owner = owner;
     });
+    _certify_metadata();
 }
 
 /* INTERNAL FNS */
@@ -572,7 +2409,8 @@ fn set_owner(owner: Principal) {
 fn _is_auth() -> Result<(), String> {
     STATS.with(|s| {
         let stats = s.borrow();
-        if ic::caller() == stats.owner {
+        let caller = ic::caller();
+        if caller == stats.owner || Some(caller) == stats.governance {
             Ok(())
         } else {
             Err("Error: Unauthorized principal ID".to_string())
@@ -592,156 +2430,1450 @@ fn _is_minter() -> Result<(), String> {
 }
 
 fn _balance_ins(from: Principal, value: Nat) {
-    BALANCES.with(|b| {
+    _snapshot_account(from);
+    let previous = BALANCES.with(|b| {
         let mut balances = b.borrow_mut();
-        balances.insert(from, value);
+        balances.insert(PrincipalKey(from), StableNat(value.clone()))
     });
+    _certify_balance(from, Some(&value));
+    _holder_index_update(from, previous.map(|p| p.0), Some(value));
 }
 
 fn _balance_rem(from: Principal) {
-    BALANCES.with(|b| {
+    _snapshot_account(from);
+    let previous = BALANCES.with(|b| {
         let mut balances = b.borrow_mut();
-        balances.remove(&from);
+        balances.remove(&PrincipalKey(from))
     });
+    _certify_balance(from, None);
+    _holder_index_update(from, previous.map(|p| p.0), None);
 }
 
-fn _transfer(from: Principal, to: Principal, value: Nat) {
-    let from_balance = balance_of(from);
-    let from_balance_new = from_balance - value.clone();
-
-    // TODO: check this logic ↴
-    if from_balance_new != 0 {
-        _balance_ins(from, from_balance_new);
-    } else {
-        _balance_rem(from)
+fn _holder_index_update(holder: Principal, previous: Option<Nat>, current: Option<Nat>) {
+    if let Some(balance) = previous {
+        let key = HolderKey { balance, holder };
+        if HOLDERS.with(|h| h.borrow_mut().remove(&key)).is_some() {
+            _holder_ranks_update(&key, false);
+        }
     }
-    let to_balance = balance_of(to);
-    let to_balance_new = to_balance + value;
-    if to_balance_new != 0 {
-        _balance_ins(to, to_balance_new);
+    if let Some(balance) = current {
+        let key = HolderKey { balance, holder };
+        if HOLDERS
+            .with(|h| h.borrow_mut().insert(key.clone(), ()))
+            .is_none()
+        {
+            _holder_ranks_update(&key, true);
+        }
     }
 }
 
-fn _charge_fee(user: Principal, fee: Nat) {
-    STATS.with(|s| {
-        let stats = s.borrow();
-        if stats.fee > Nat::from(0) {
-            _transfer(user, stats.fee_to, fee);
+// length of the prefixes of `key` that are counted in HOLDER_RANKS
+fn _holder_rank_depth(key: &[u8]) -> usize {
+    let holder_start = 2 + (0xff - key[0]) as usize;
+    (holder_start + HOLDER_RANK_HOLDER_BYTES).min(key.len())
+}
+
+fn _holder_ranks_update(key: &HolderKey, added: bool) {
+    let bytes = key.to_bytes();
+    HOLDER_RANKS.with(|r| {
+        let mut ranks = r.borrow_mut();
+        for len in 1..=_holder_rank_depth(&bytes) {
+            let prefix = RankPrefix::new(len, &bytes[..len]);
+            let count = ranks.get(&prefix).unwrap_or(0);
+            if added {
+                ranks.insert(prefix, count + 1);
+            } else if count > 1 {
+                ranks.insert(prefix, count - 1);
+            } else {
+                ranks.remove(&prefix);
+            }
         }
     });
 }
 
-fn _get_fee() -> Nat {
-    STATS.with(|s| {
-        let stats = s.borrow();
-        stats.fee.clone()
-    })
+// first index entry whose encoding starts with `prefix`, which goes at least
+// down to the holder length
+fn _holder_bucket_start(prefix: &[u8]) -> HolderKey {
+    let holder_start = 2 + (0xff - prefix[0]) as usize;
+    let mut bytes = prefix.to_vec();
+    bytes.resize(holder_start + prefix[holder_start - 1] as usize, 0);
+    HolderKey::from_bytes(Cow::Owned(bytes))
 }
 
-fn _get_owner() -> Principal {
-    STATS.with(|s| {
-        let stats = s.borrow();
-        stats.owner
-    })
+// number of index entries ordered before `key`
+fn _holders_before(key: &HolderKey) -> u64 {
+    let bytes = key.to_bytes();
+    let depth = _holder_rank_depth(&bytes);
+    let mut before: u64 = HOLDER_RANKS.with(|r| {
+        let ranks = r.borrow();
+        (1..=depth)
+            .map(|len| {
+                ranks
+                    .range(
+                        RankPrefix::new(len, &bytes[..len - 1])
+                            ..RankPrefix::new(len, &bytes[..len]),
+                    )
+                    .map(|(_, count)| count)
+                    .sum::<u64>()
+            })
+            .sum()
+    });
+    if depth < bytes.len() {
+        let start = _holder_bucket_start(&bytes[..depth]);
+        before += HOLDERS.with(|h| h.borrow().range(start..key.clone()).count() as u64);
+    }
+    before
 }
 
-fn _history_inc() {
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        stats.history_size += 1;
-    })
+// whether `prefix` is as long as the counted prefixes of the entries it starts
+fn _is_full_rank_prefix(prefix: &[u8]) -> bool {
+    if prefix.is_empty() {
+        return false;
+    }
+    let holder_start = 2 + (0xff - prefix[0]) as usize;
+    prefix.len() >= holder_start
+        && prefix.len()
+            == (holder_start + HOLDER_RANK_HOLDER_BYTES)
+                .min(holder_start + prefix[holder_start - 1] as usize)
 }
 
-#[cfg(any(target_arch = "wasm32", test))]
-fn main() {}
+// the index entry at 0-based position `index`
+fn _holder_at(index: u64) -> Option<HolderKey> {
+    let mut prefix = Vec::new();
+    let mut remaining = index;
+    while !_is_full_rank_prefix(&prefix) {
+        let len = prefix.len() + 1;
+        let lower = RankPrefix::new(len, &prefix);
+        let next = HOLDER_RANKS.with(|r| {
+            for (k, count) in r
+                .borrow()
+                .range(lower.clone()..)
+                .take_while(|(k, _)| k.0.len() == len + 1 && k.0.starts_with(&lower.0))
+            {
+                if remaining < count {
+                    return Some(k.0[1..].to_vec());
+                }
+                remaining -= count;
+            }
+            None
+        });
+        prefix = next?;
+    }
+    let start = _holder_bucket_start(&prefix);
+    HOLDERS.with(|h| {
+        h.borrow()
+            .range(start..)
+            .nth(remaining as usize)
+            .map(|(k, _)| k)
+    })
+}
 
-#[cfg(not(any(target_arch = "wasm32", test)))]
-fn main() {
-    candid::export_service!();
-    std::print!("{}", __export_service());
+fn _holders_from(from: Bound<HolderKey>, limit: usize) -> Vec<(Principal, Nat)> {
+    HOLDERS.with(|h| {
+        h.borrow()
+            .range((from, Bound::Unbounded))
+            .take(limit)
+            .map(|(k, _)| (k.holder, k.balance))
+            .collect()
+    })
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
-    let stats = STATS.with(|s| s.borrow().clone());
-    let balances = BALANCES.with(|b| b.borrow().clone());
-    let allows = ALLOWS.with(|a| a.borrow().clone());
-    let tx_log = TXLOG.with(|t| t.borrow().clone());
-    ic::stable_store((stats, balances, allows, tx_log)).unwrap();
+fn _holder_count() -> u64 {
+    HOLDER_RANKS.with(|r| {
+        r.borrow()
+            .range(RankPrefix::new(1, &[])..RankPrefix::new(2, &[]))
+            .map(|(_, count)| count)
+            .sum()
+    })
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    let (metadata_stored, balances_stored, allowances_stored, tx_log_stored): (
-        StatsData,
-        Balances,
-        Allowances,
-        TxLog,
-    ) = ic::stable_restore().unwrap();
-    STATS.with(|s| {
-        let mut stats = s.borrow_mut();
-        *stats = metadata_stored;
-    });
+// refills the holder index when it does not cover every balance, as after
+// upgrading from a version without it or with an older layout of it
+fn _build_holder_index() {
+    let holders = BALANCES.with(|b| b.borrow().len());
+    if _holder_count() == holders && HOLDERS.with(|h| h.borrow().len()) == holders {
+        return;
+    }
+    HOLDERS.with(|h| *h.borrow_mut() = StableBTreeMap::new(_memory(HOLDERS_MEMORY)));
+    HOLDER_RANKS.with(|r| *r.borrow_mut() = StableBTreeMap::new(_memory(HOLDER_RANKS_MEMORY)));
     BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        *balances = balances_stored;
+        for (k, v) in b.borrow().iter() {
+            _holder_index_update(k.0, None, Some(v.0));
+        }
     });
+}
+
+fn _allowance_set(owner: Principal, spender: Principal, value: Nat) {
     ALLOWS.with(|a| {
         let mut allowances = a.borrow_mut();
-        *allowances = allowances_stored;
-    });
-    TXLOG.with(|t| {
-        let mut tx_log = t.borrow_mut();
-        *tx_log = tx_log_stored;
+        let key = AllowanceKey { owner, spender };
+        if value != 0 {
+            allowances.insert(key, StableNat(value));
+        } else {
+            allowances.remove(&key);
+        }
     });
 }
 
-async fn add_record(
-    caller: Principal,
-    op: Operation,
-    from: Principal,
-    to: Principal,
-    amount: Nat,
-    fee: Nat,
-    timestamp: u64,
-    status: TransactionStatus,
-) -> TxReceipt {
-    insert_into_cap(Into::<IndefiniteEvent>::into(Into::<Event>::into(Into::<
-        TypedEvent<DIP20Details>,
-    >::into(
-        TxRecord {
-            caller: Some(caller),
-            index: Nat::from(0),
-            from,
-            to,
-            amount: Nat::from(amount),
-            fee: Nat::from(fee),
-            timestamp: Int::from(timestamp),
-            status,
-            operation: op,
-        },
-    ))))
-    .await
+fn _balance_sub(from: Principal, value: Nat) {
+    let from_balance = balance_of(from);
+    let from_balance_new = from_balance - value;
+
+    // TODO: check this logic ↴
+    if from_balance_new != 0 {
+        _balance_ins(from, from_balance_new);
+    } else {
+        _balance_rem(from)
+    }
 }
 
-pub async fn insert_into_cap(ie: IndefiniteEvent) -> TxReceipt {
-    let mut tx_log = TXLOG.with(|t| t.take());
-    if let Some(failed_ie) = tx_log.ie_records.pop_front() {
-        let _ = insert_into_cap_priv(failed_ie).await;
+fn _balance_add(to: Principal, value: Nat) {
+    let to_balance = balance_of(to);
+    let to_balance_new = to_balance + value;
+    if to_balance_new != 0 {
+        _balance_ins(to, to_balance_new);
     }
-    insert_into_cap_priv(ie).await
 }
 
-async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
-    let insert_res = insert(ie.clone())
-        .await
-        .map(|tx_id| Nat::from(tx_id))
-        .map_err(|error| {
-            TxError::Other(format!("Inserting into cap failed with error: {:?}", error))
-        });
+fn _transfer(from: Principal, to: Principal, value: Nat) {
+    _balance_sub(from, value.clone());
+    _balance_add(to, value);
+}
 
-    if insert_res.is_err() {
-        TXLOG.with(|t| {
+fn _is_frozen(id: Principal) -> bool {
+    FROZEN.with(|f| f.borrow().contains_key(&PrincipalKey(id)))
+}
+
+fn _force_transfer(
+    from: Principal,
+    to: Principal,
+    value: Nat,
+    reason: String,
+) -> Result<(), TxError> {
+    _check_freeze_reason(&reason)?;
+    if !_is_frozen(from) {
+        return Err(TxError::Other("account is not frozen".to_string()));
+    }
+    if _spendable_of(from) < value {
+        return Err(TxError::InsufficientBalance);
+    }
+    _transfer(from, to, value);
+    _freeze_audit(from, FreezeAction::ForceTransfer, reason);
+    Ok(())
+}
+
+fn _check_freeze_reason(reason: &str) -> Result<(), TxError> {
+    if reason.len() > MAX_FREEZE_REASON_SIZE as usize {
+        return Err(TxError::Other("reason too long".to_string()));
+    }
+    Ok(())
+}
+
+fn _freeze_audit(account: Principal, action: FreezeAction, reason: String) {
+    FREEZE_LOG.with(|f| {
+        f.borrow()
+            .append(&FreezeLogEntry {
+                account,
+                action,
+                reason,
+                by: ic::caller(),
+                timestamp: ic::time(),
+            })
+            .expect("failed to append to the freeze log");
+    });
+}
+
+fn _spendable_of(id: Principal) -> Nat {
+    let balance = balance_of(id);
+    let locked = locked_balance_of(id);
+    if balance > locked {
+        balance - locked
+    } else {
+        Nat::from(0)
+    }
+}
+
+fn _vesting_schedules_of(beneficiary: Principal) -> Vec<VestingSchedule> {
+    let ids: Vec<u64> = VESTING_BENEFICIARIES.with(|v| {
+        v.borrow()
+            .range(
+                AccountKey {
+                    account: beneficiary,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(k, _)| k.account == beneficiary)
+            .map(|(k, _)| k.id)
+            .collect()
+    });
+    VESTING_SCHEDULES.with(|v| {
+        let schedules = v.borrow();
+        ids.into_iter()
+            .filter_map(|id| schedules.get(&id))
+            .collect()
+    })
+}
+
+// assigns the schedule its id
+fn _add_vesting_schedule(mut schedule: VestingSchedule) -> u64 {
+    let id = VESTINGS.with(|v| {
+        let mut vestings = v.borrow_mut();
+        vestings.next_id += 1;
+        vestings.next_id
+    });
+    schedule.id = id;
+    VESTING_BENEFICIARIES.with(|v| {
+        v.borrow_mut().insert(
+            AccountKey {
+                account: schedule.beneficiary,
+                id,
+            },
+            (),
+        )
+    });
+    VESTING_SCHEDULES.with(|v| v.borrow_mut().insert(id, schedule));
+    id
+}
+
+// marks everything vested by `now` as released, returns the newly released amount
+fn _release_vested(beneficiary: Principal, now: u64) -> Nat {
+    let mut released = Nat::from(0);
+    for mut schedule in _vesting_schedules_of(beneficiary) {
+        let vested = _vested_amount(&schedule, now);
+        if vested == schedule.released {
+            continue;
+        }
+        released += vested.clone() - schedule.released.clone();
+        schedule.released = vested;
+        VESTING_SCHEDULES.with(|v| v.borrow_mut().insert(schedule.id, schedule));
+    }
+    released
+}
+
+// stops the schedule at what has vested by `now`,
+// returns the beneficiary and the unvested amount
+fn _revoke_vesting(id: u64, now: u64) -> Result<(Principal, Nat), TxError> {
+    let mut schedule = match VESTING_SCHEDULES.with(|v| v.borrow().get(&id)) {
+        Some(s) => s,
+        None => return Err(TxError::Other("vesting not found".to_string())),
+    };
+    if !schedule.revocable || schedule.revoked {
+        return Err(TxError::Other("vesting not revocable".to_string()));
+    }
+    let vested = _vested_amount(&schedule, now);
+    let unvested = schedule.total.clone() - vested.clone();
+    schedule.total = vested;
+    schedule.revoked = true;
+    let beneficiary = schedule.beneficiary;
+    VESTING_SCHEDULES.with(|v| v.borrow_mut().insert(id, schedule));
+    Ok((beneficiary, unvested))
+}
+
+fn _vested_amount(schedule: &VestingSchedule, now: u64) -> Nat {
+    if schedule.revoked {
+        return schedule.total.clone();
+    }
+    let elapsed = now.saturating_sub(schedule.start) / 1000000000;
+    if elapsed < schedule.cliff {
+        return Nat::from(0);
+    }
+    if elapsed >= schedule.duration {
+        return schedule.total.clone();
+    }
+    let interval = schedule.interval.max(1);
+    let vested_time = elapsed / interval * interval;
+    schedule.total.clone() * Nat::from(vested_time) / Nat::from(schedule.duration)
+}
+
+fn _charge_fee(user: Principal, fee: Nat) -> Vec<FeePart> {
+    if fee == 0 {
+        return Vec::new();
+    }
+    let fee_to = STATS.with(|s| s.borrow().fee_to);
+    let policy = FEE_POLICY.with(|f| f.borrow().clone());
+    match policy {
+        // recorded as the fee of the operation itself
+        FeePolicy::FeeTo => {
+            _transfer(user, fee_to, fee);
+            Vec::new()
+        }
+        FeePolicy::Burn => {
+            _burn_from(user, fee.clone());
+            vec![FeePart::Burn(fee)]
+        }
+        FeePolicy::Split(split) => {
+            let burn = fee.clone() * Nat::from(split.burn_bps) / Nat::from(10000);
+            let treasury = fee.clone() * Nat::from(split.treasury_bps) / Nat::from(10000);
+            let staking = _charge_staking_share(user, &fee, split.staking_bps);
+            let rest = fee - burn.clone() - treasury.clone() - staking.clone();
+
+            let mut parts = Vec::new();
+            if burn > 0u64 {
+                _burn_from(user, burn.clone());
+                parts.push(FeePart::Burn(burn));
+            }
+            if treasury > 0u64 {
+                _transfer(user, split.treasury, treasury.clone());
+                parts.push(FeePart::Transfer(split.treasury, treasury));
+            }
+            if staking > 0u64 {
+                parts.push(FeePart::Staking(staking));
+            }
+            if rest > 0u64 {
+                _transfer(user, fee_to, rest.clone());
+                parts.push(FeePart::Transfer(fee_to, rest));
+            }
+            parts
+        }
+    }
+}
+
+// moves the stakers' share of a fee into the staking pool,
+// there is no share while nothing is staked
+fn _charge_staking_share(user: Principal, fee: &Nat, share_bps: u64) -> Nat {
+    let share = STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        if staking.total_weight == 0 || share_bps == 0 {
+            return Nat::from(0);
+        }
+        let share = fee.clone() * Nat::from(share_bps) / Nat::from(10000);
+        if share > 0u64 {
+            staking.distribute_fee(share.clone());
+        }
+        share
+    });
+    if share > 0u64 {
+        _balance_sub(user, share.clone());
+    }
+    share
+}
+
+fn _stake_positions_of(owner: Principal) -> Vec<StakePosition> {
+    let ids: Vec<u64> = STAKE_OWNERS.with(|o| {
+        o.borrow()
+            .range(
+                AccountKey {
+                    account: owner,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(k, _)| k.account == owner)
+            .map(|(k, _)| k.id)
+            .collect()
+    });
+    STAKE_POSITIONS.with(|p| {
+        let positions = p.borrow();
+        ids.into_iter()
+            .filter_map(|id| positions.get(&id))
+            .collect()
+    })
+}
+
+fn _owned_stake_position(owner: Principal, id: u64) -> Result<StakePosition, TxError> {
+    match STAKE_POSITIONS.with(|p| p.borrow().get(&id)) {
+        Some(p) if p.owner == owner => Ok(p),
+        _ => Err(TxError::Other("position not found".to_string())),
+    }
+}
+
+// the staked amount has to be taken from the owner's balance by the caller
+fn _open_stake_position(
+    owner: Principal,
+    amount: Nat,
+    term_days: u64,
+    now: u64,
+) -> Result<u64, TxError> {
+    let position = STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        let weight_bps = match staking.terms.iter().find(|t| t.days == term_days) {
+            Some(t) => t.weight_bps,
+            None => return Err(TxError::Other("invalid staking term".to_string())),
+        };
+        staking.update_pool(now);
+
+        let weight = amount.clone() * Nat::from(weight_bps) / Nat::from(10000);
+        if weight == 0 {
+            return Err(TxError::AmountTooSmall);
+        }
+        let (fee_reward_debt, emission_reward_debt) = staking.accrued(&weight);
+        staking.next_id += 1;
+        staking.total_staked += amount.clone();
+        staking.total_weight += weight.clone();
+        Ok(StakePosition {
+            id: staking.next_id,
+            owner,
+            amount,
+            weight,
+            term_days,
+            staked_at: now,
+            unlock_at: now + term_days * 86400 * 1000000000,
+            fee_reward_debt,
+            emission_reward_debt,
+        })
+    })?;
+    let id = position.id;
+    STAKE_OWNERS.with(|o| o.borrow_mut().insert(AccountKey { account: owner, id }, ()));
+    STAKE_POSITIONS.with(|p| p.borrow_mut().insert(id, position));
+    Ok(id)
+}
+
+// returns (amount due to the owner, emission reward to mint, penalty,
+// part of the penalty that goes to fee_to as nobody is left to share it)
+fn _close_stake_position(
+    owner: Principal,
+    id: u64,
+    now: u64,
+) -> Result<(Nat, Nat, Nat, Nat), TxError> {
+    let position = _owned_stake_position(owner, id)?;
+    STAKE_POSITIONS.with(|p| p.borrow_mut().remove(&id));
+    STAKE_OWNERS.with(|o| o.borrow_mut().remove(&AccountKey { account: owner, id }));
+    STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        staking.update_pool(now);
+
+        let (fee_reward, emission_reward) = staking.pending(&position);
+        let penalty = if now < position.unlock_at {
+            position.amount.clone() * Nat::from(staking.early_unlock_penalty_bps) / Nat::from(10000)
+        } else {
+            Nat::from(0)
+        };
+        staking.total_staked -= position.amount.clone();
+        staking.total_weight -= position.weight.clone();
+        let fee_reward = staking.take_fee_reward(fee_reward);
+
+        let mut penalty_to_fee_to = Nat::from(0);
+        if penalty > 0u64 {
+            if staking.total_weight > 0u64 {
+                staking.distribute_fee(penalty.clone());
+            } else {
+                penalty_to_fee_to = penalty.clone();
+            }
+        }
+        Ok((
+            position.amount - penalty.clone() + fee_reward,
+            emission_reward,
+            penalty,
+            penalty_to_fee_to,
+        ))
+    })
+}
+
+// returns the (fee reward, emission reward) taken out of the position
+fn _claim_stake_rewards(owner: Principal, id: u64, now: u64) -> Result<(Nat, Nat), TxError> {
+    let mut position = _owned_stake_position(owner, id)?;
+    let (fee_reward, emission_reward) = STAKING.with(|s| {
+        let mut staking = s.borrow_mut();
+        staking.update_pool(now);
+        let (fee_reward, emission_reward) = staking.pending(&position);
+        let (fee_reward_debt, emission_reward_debt) = staking.accrued(&position.weight);
+        position.fee_reward_debt = fee_reward_debt;
+        position.emission_reward_debt = emission_reward_debt;
+        (staking.take_fee_reward(fee_reward), emission_reward)
+    });
+    STAKE_POSITIONS.with(|p| p.borrow_mut().insert(id, position));
+    Ok((fee_reward, emission_reward))
+}
+
+async fn _record_fee_parts(user: Principal, parts: Vec<FeePart>) {
+    for part in parts {
+        let (operation, details) = match part {
+            FeePart::Burn(amount) => (
+                "feeBurn",
+                vec![
+                    ("from", DetailValue::Principal(user)),
+                    ("amount", amount.into()),
+                ],
+            ),
+            FeePart::Transfer(to, amount) => (
+                "feeTransfer",
+                vec![
+                    ("from", DetailValue::Principal(user)),
+                    ("to", DetailValue::Principal(to)),
+                    ("amount", amount.into()),
+                ],
+            ),
+            FeePart::Staking(amount) => (
+                "feeStaking",
+                vec![
+                    ("from", DetailValue::Principal(user)),
+                    ("amount", amount.into()),
+                ],
+            ),
+        };
+        _history_inc();
+        let _ = add_event(user, operation, details).await;
+    }
+}
+
+fn _burn_from(user: Principal, amount: Nat) {
+    _balance_sub(user, amount.clone());
+    _snapshot_supply();
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.total_supply -= amount;
+    });
+    _certify_metadata();
+}
+
+async fn _mint_reward(to: Principal, amount: Nat) {
+    _balance_add(to, amount.clone());
+    _snapshot_supply();
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.total_supply += amount.clone();
+    });
+    _certify_metadata();
+    _history_inc();
+    let _ = add_record(
+        ic::id(),
+        Operation::Mint,
+        ic::id(),
+        to,
+        amount,
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+    )
+    .await;
+}
+
+fn _get_fee() -> Nat {
+    STATS.with(|s| {
+        let stats = s.borrow();
+        stats.fee.clone()
+    })
+}
+
+// the tier only applies to operations that move an amount
+fn _get_fee_for(from: Principal, to: Principal, amount: Option<&Nat>) -> Nat {
+    let exempt = FEE_EXEMPT.with(|f| {
+        let exempt = f.borrow();
+        exempt.contains_key(&PrincipalKey(from)) || exempt.contains_key(&PrincipalKey(to))
+    });
+    if exempt {
+        return Nat::from(0);
+    }
+    let tier = FEE_RULES.with(|f| f.borrow().tier.clone());
+    match (tier, amount) {
+        (Some(tier), Some(amount)) => {
+            let fee = amount.clone() * Nat::from(tier.rate_bps) / Nat::from(10000);
+            if fee < tier.min {
+                tier.min
+            } else if fee > tier.max {
+                tier.max
+            } else {
+                fee
+            }
+        }
+        _ => _get_fee(),
+    }
+}
+
+fn _get_owner() -> Principal {
+    STATS.with(|s| {
+        let stats = s.borrow();
+        stats.owner
+    })
+}
+
+fn _is_valid_snapshot(snapshot_id: u64) -> bool {
+    snapshot_id != 0 && snapshot_id <= current_snapshot_id()
+}
+
+// the value at a snapshot is held by the first entry recorded at or after it;
+// if there is none, the value has not changed since and is the live one
+fn _balance_of_at(id: Principal, snapshot_id: u64) -> Nat {
+    let recorded = SNAPSHOT_BALANCES.with(|s| {
+        s.borrow()
+            .range(
+                AccountKey {
+                    account: id,
+                    id: snapshot_id,
+                }..,
+            )
+            .next()
+            .filter(|(k, _)| k.account == id)
+            .map(|(_, balance)| balance.0)
+    });
+    recorded.unwrap_or_else(|| balance_of(id))
+}
+
+// must be called before the balance of `id` is modified
+fn _snapshot_account(id: Principal) {
+    let current_id = current_snapshot_id();
+    if current_id == 0 {
+        return;
+    }
+    let key = AccountKey {
+        account: id,
+        id: current_id,
+    };
+    if SNAPSHOT_BALANCES.with(|s| s.borrow().contains_key(&key)) {
+        return;
+    }
+    let balance = balance_of(id);
+    SNAPSHOT_BALANCES.with(|s| s.borrow_mut().insert(key, StableNat(balance)));
+}
+
+// must be called before the total supply is modified
+fn _snapshot_supply() {
+    let current_id = current_snapshot_id();
+    if current_id == 0 {
+        return;
+    }
+    if SNAPSHOT_SUPPLY.with(|s| s.borrow().contains_key(&current_id)) {
+        return;
+    }
+    let supply = total_supply();
+    SNAPSHOT_SUPPLY.with(|s| s.borrow_mut().insert(current_id, StableNat(supply)));
+}
+
+// the first account after `after` in principal order that holds a balance
+// now or had one recorded for a snapshot
+fn _next_snapshot_account(after: Option<Principal>) -> Option<Principal> {
+    let (holders_start, recorded_start) = match after {
+        Some(after) => (
+            Bound::Excluded(PrincipalKey(after)),
+            Bound::Excluded(AccountKey {
+                account: after,
+                id: u64::MAX,
+            }),
+        ),
+        None => (Bound::Unbounded, Bound::Unbounded),
+    };
+    let holder = BALANCES.with(|b| {
+        b.borrow()
+            .range((holders_start, Bound::Unbounded))
+            .next()
+            .map(|(k, _)| k.0)
+    });
+    let recorded = SNAPSHOT_BALANCES.with(|s| {
+        s.borrow()
+            .range((recorded_start, Bound::Unbounded))
+            .next()
+            .map(|(k, _)| k.account)
+    });
+    match (holder, recorded) {
+        (Some(holder), Some(recorded)) => Some(std::cmp::min(holder, recorded)),
+        (holder, recorded) => holder.or(recorded),
+    }
+}
+
+fn _snapshot_holders_after(
+    snapshot_id: u64,
+    after: Option<Principal>,
+) -> impl Iterator<Item = (Principal, Nat)> {
+    std::iter::successors(_next_snapshot_account(after), |account| {
+        _next_snapshot_account(Some(*account))
+    })
+    .map(move |account| (account, _balance_of_at(account, snapshot_id)))
+    .filter(|(_, balance)| *balance != 0)
+}
+
+fn _streams_in(
+    index: &'static LocalKey<AccountIndex>,
+    who: Principal,
+    start: usize,
+    limit: usize,
+) -> Vec<StreamInfo> {
+    let now = ic::time();
+    _account_index_page(index, who, start, limit)
+        .into_iter()
+        .filter_map(|id| STREAM_ITEMS.with(|s| s.borrow().get(&id)))
+        .map(|stream| StreamInfo {
+            withdrawable: stream.withdrawable_at(now),
+            stream,
+        })
+        .collect()
+}
+
+fn _add_stream(mut stream: Stream) -> u64 {
+    let id = STREAMS.with(|s| {
+        let mut streams = s.borrow_mut();
+        let id = streams.next_id;
+        streams.next_id += 1;
+        id
+    });
+    stream.id = id;
+    _account_index_insert(&STREAM_SENDERS, stream.sender, id);
+    _account_index_insert(&STREAM_RECIPIENTS, stream.recipient, id);
+    STREAM_ITEMS.with(|s| s.borrow_mut().insert(id, stream));
+    id
+}
+
+// returns the amount taken out of the stream for the recipient
+fn _withdraw_from_stream(recipient: Principal, id: u64, now: u64) -> Result<Nat, TxError> {
+    let mut stream = match STREAM_ITEMS.with(|s| s.borrow().get(&id)) {
+        Some(s) if s.recipient == recipient => s,
+        _ => return Err(TxError::Other("stream not found".to_string())),
+    };
+    let amount = stream.withdrawable_at(now);
+    if amount == 0 {
+        return Err(TxError::AmountTooSmall);
+    }
+    stream.withdrawn += amount.clone();
+    if stream.status == StreamStatus::Active && stream.withdrawn == stream.deposit {
+        stream.status = StreamStatus::Completed;
+    }
+    STREAM_ITEMS.with(|s| s.borrow_mut().insert(id, stream));
+    Ok(amount)
+}
+
+// returns the canceled stream and what goes to the recipient and to the sender
+fn _cancel_stream(caller: Principal, id: u64, now: u64) -> Result<(Stream, Nat, Nat), TxError> {
+    let mut stream = match STREAM_ITEMS.with(|s| s.borrow().get(&id)) {
+        Some(s) if s.sender == caller || s.recipient == caller => s,
+        _ => return Err(TxError::Other("stream not found".to_string())),
+    };
+    if stream.status != StreamStatus::Active {
+        return Err(TxError::Other("stream is closed".to_string()));
+    }
+    let to_recipient = stream.withdrawable_at(now);
+    stream.withdrawn += to_recipient.clone();
+    stream.canceled_at = Some(now);
+    stream.status = StreamStatus::Canceled;
+    let to_sender = stream.deposit.clone() - stream.withdrawn.clone();
+    STREAM_ITEMS.with(|s| s.borrow_mut().insert(id, stream.clone()));
+    Ok((stream, to_recipient, to_sender))
+}
+
+fn _account_index_insert(index: &'static LocalKey<AccountIndex>, account: Principal, id: u64) {
+    index.with(|i| {
+        i.borrow_mut().insert(
+            AccountKey {
+                account,
+                id: u64::MAX - id,
+            },
+            (),
+        )
+    });
+}
+
+// ids of the records of `account`, newest first
+fn _account_index_page(
+    index: &'static LocalKey<AccountIndex>,
+    account: Principal,
+    start: usize,
+    limit: usize,
+) -> Vec<u64> {
+    index.with(|i| {
+        i.borrow()
+            .range(AccountKey { account, id: 0 }..)
+            .take_while(|(k, _)| k.account == account)
+            .skip(start)
+            .take(limit)
+            .map(|(k, _)| u64::MAX - k.id)
+            .collect()
+    })
+}
+
+async fn _refund_escrow(caller: Principal, escrow: Escrow) -> TxReceipt {
+    _balance_add(escrow.payer, escrow.amount.clone());
+    _history_inc();
+    add_event(
+        caller,
+        "escrowRefund",
+        vec![
+            ("id", DetailValue::U64(escrow.id)),
+            ("to", DetailValue::Principal(escrow.payer)),
+            ("amount", escrow.amount.into()),
+        ],
+    )
+    .await
+}
+
+fn _add_escrow(mut escrow: Escrow) -> u64 {
+    let id = ESCROWS.with(|e| {
+        let mut escrows = e.borrow_mut();
+        let id = escrows.next_id;
+        escrows.next_id += 1;
+        id
+    });
+    escrow.id = id;
+    for account in [escrow.payer, escrow.payee, escrow.arbiter] {
+        _account_index_insert(&ESCROW_ACCOUNTS, account, id);
+    }
+    ESCROW_DEADLINES.with(|e| e.borrow_mut().insert((escrow.deadline, id), ()));
+    _escrowed_update(escrow.payer, |total| total + escrow.amount.clone());
+    ESCROW_ITEMS.with(|e| e.borrow_mut().insert(id, escrow));
+    id
+}
+
+// closes an open escrow and returns it
+fn _settle_escrow(id: u64, status: EscrowStatus) -> Option<Escrow> {
+    let mut escrow = get_escrow(id)?;
+    if escrow.status != EscrowStatus::Open {
+        return None;
+    }
+    escrow.status = status;
+    ESCROW_DEADLINES.with(|e| e.borrow_mut().remove(&(escrow.deadline, id)));
+    _escrowed_update(escrow.payer, |total| total - escrow.amount.clone());
+    ESCROW_ITEMS.with(|e| e.borrow_mut().insert(id, escrow.clone()));
+    Some(escrow)
+}
+
+fn _escrowed_update(payer: Principal, f: impl FnOnce(Nat) -> Nat) {
+    ESCROWED.with(|e| {
+        let mut escrowed = e.borrow_mut();
+        let key = PrincipalKey(payer);
+        let total = f(escrowed.get(&key).map_or_else(|| Nat::from(0), |v| v.0));
+        if total == 0 {
+            escrowed.remove(&key);
+        } else {
+            escrowed.insert(key, StableNat(total));
+        }
+    });
+}
+
+// settles up to `limit` escrows whose deadline has passed as refunded
+fn _settle_expired_escrows(now: u64, limit: usize) -> Vec<Escrow> {
+    let ids: Vec<u64> = ESCROW_DEADLINES.with(|e| {
+        e.borrow()
+            .iter()
+            .take_while(|((deadline, _), _)| *deadline <= now)
+            .take(limit)
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    ids.into_iter()
+        .filter_map(|id| _settle_escrow(id, EscrowStatus::Refunded))
+        .collect()
+}
+
+// wdn itself and the management canister would run the callback with wdn
+// as the caller
+fn _check_notification_recipient(to: Principal, canister: Principal) -> Result<(), TxError> {
+    if to == canister || to == Principal::management_canister() {
+        return Err(TxError::Other("invalid recipient".to_string()));
+    }
+    Ok(())
+}
+
+fn _deliver_notification(id: u64) {
+    let notification = match _get_notification(id) {
+        Some(n) => n,
+        None => return,
+    };
+    let args = candid::encode_one(TransferNotification {
+        id,
+        from: notification.from,
+        amount: notification.amount.clone(),
+        payload: notification.payload.clone(),
+    })
+    .unwrap();
+    _update_notification(Notification {
+        attempts: notification.attempts + 1,
+        last_attempt_at: ic::time(),
+        ..notification
+    });
+    let res = ic_cdk::api::call::notify_raw(notification.to, NOTIFICATION_METHOD, &args, 0);
+    _finish_notification(id, res.map_err(|code| format!("{:?}", code)));
+}
+
+fn _add_notification(mut notification: Notification) -> u64 {
+    let id = NOTIFICATIONS.with(|n| {
+        let mut notifications = n.borrow_mut();
+        let id = notifications.next_id;
+        notifications.next_id += 1;
+        id
+    });
+    notification.id = id;
+    _account_index_insert(&NOTIFICATION_ACCOUNTS, notification.from, id);
+    _account_index_insert(&NOTIFICATION_ACCOUNTS, notification.to, id);
+    let payload = std::mem::take(&mut notification.payload);
+    NOTIFICATION_PAYLOADS.with(|n| n.borrow_mut().insert(id, NotificationPayload(payload)));
+    NOTIFICATION_ITEMS.with(|n| n.borrow_mut().insert(id, notification));
+    id
+}
+
+fn _get_notification(id: u64) -> Option<Notification> {
+    let mut notification = NOTIFICATION_ITEMS.with(|n| n.borrow().get(&id))?;
+    if let Some(payload) = NOTIFICATION_PAYLOADS.with(|n| n.borrow().get(&id)) {
+        notification.payload = payload.0;
+    }
+    Some(notification)
+}
+
+// stores everything but the payload
+fn _update_notification(mut notification: Notification) {
+    notification.payload = Vec::new();
+    NOTIFICATION_ITEMS.with(|n| n.borrow_mut().insert(notification.id, notification));
+}
+
+fn _notification_status(id: u64) -> NotificationStatus {
+    NOTIFICATION_ITEMS.with(|n| n.borrow().get(&id).unwrap().status)
+}
+
+// marks the notification pending again when `caller` may retry it
+fn _can_retry_notification(id: u64, caller: Principal) -> Result<bool, TxError> {
+    let mut notification = match NOTIFICATION_ITEMS.with(|n| n.borrow().get(&id)) {
+        Some(n) if n.from == caller || n.to == caller => n,
+        _ => return Err(TxError::Other("notification not found".to_string())),
+    };
+    // a notification is sent in the message that made it pending
+    let retry = matches!(notification.status, NotificationStatus::Failed(_));
+    if retry {
+        notification.status = NotificationStatus::Pending;
+        _update_notification(notification);
+    }
+    Ok(retry)
+}
+
+// records the outcome of an attempt, the payload is dropped once delivered
+fn _finish_notification(id: u64, res: Result<(), String>) {
+    let mut notification = match NOTIFICATION_ITEMS.with(|n| n.borrow().get(&id)) {
+        Some(n) => n,
+        None => return,
+    };
+    notification.status = match res {
+        Ok(()) => {
+            NOTIFICATION_PAYLOADS.with(|n| n.borrow_mut().remove(&id));
+            NotificationStatus::Delivered
+        }
+        Err(mut error) => {
+            let mut end = error.len().min(MAX_NOTIFICATION_ERROR_SIZE as usize);
+            while !error.is_char_boundary(end) {
+                end -= 1;
+            }
+            error.truncate(end);
+            NotificationStatus::Failed(error)
+        }
+    };
+    _update_notification(notification);
+}
+
+fn _certified_balance_key(id: Principal) -> Vec<u8> {
+    let mut key = CERTIFIED_BALANCE_PREFIX.to_vec();
+    key.extend_from_slice(id.as_slice());
+    key
+}
+
+fn _certify_balance(id: Principal, balance: Option<&Nat>) {
+    CERTIFIED.with(|c| {
+        let mut tree = c.borrow_mut();
+        match balance {
+            Some(balance) => tree.insert(
+                _certified_balance_key(id),
+                candid::encode_one(balance).unwrap(),
+            ),
+            None => tree.delete(&_certified_balance_key(id)),
+        }
+        ic::set_certified_data(&tree.root_hash());
+    });
+}
+
+// total supply and metadata, after any change of STATS they are built from
+fn _certify_metadata() {
+    let metadata = get_metadata();
+    CERTIFIED.with(|c| {
+        let mut tree = c.borrow_mut();
+        tree.insert(
+            CERTIFIED_TOTAL_SUPPLY.to_vec(),
+            candid::encode_one(&metadata.totalSupply).unwrap(),
+        );
+        tree.insert(
+            CERTIFIED_METADATA.to_vec(),
+            candid::encode_one(&metadata).unwrap(),
+        );
+        ic::set_certified_data(&tree.root_hash());
+    });
+}
+
+fn _start_certify_backfill() {
+    CERTIFY_BACKFILL.with(|c| {
+        *c.borrow_mut() = CertifyBackfill {
+            running: true,
+            after: None,
+        };
+    });
+}
+
+// puts the next batch of balances into the certified tree
+fn _certify_backfill_step() {
+    let (running, after) = CERTIFY_BACKFILL.with(|c| {
+        let backfill = c.borrow();
+        (backfill.running, backfill.after)
+    });
+    if !running {
+        return;
+    }
+    let start = match after {
+        Some(after) => Bound::Excluded(PrincipalKey(after)),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<(Principal, Nat)> = BALANCES.with(|b| {
+        b.borrow()
+            .range((start, Bound::Unbounded))
+            .take(CERTIFY_BATCH)
+            .map(|(k, v)| (k.0, v.0))
+            .collect()
+    });
+    CERTIFIED.with(|c| {
+        let mut tree = c.borrow_mut();
+        for (id, balance) in batch.iter() {
+            tree.insert(
+                _certified_balance_key(*id),
+                candid::encode_one(balance).unwrap(),
+            );
+        }
+        ic::set_certified_data(&tree.root_hash());
+    });
+    CERTIFY_BACKFILL.with(|c| {
+        let mut backfill = c.borrow_mut();
+        backfill.running = batch.len() == CERTIFY_BATCH;
+        backfill.after = batch.last().map(|(id, _)| *id);
+    });
+}
+
+fn _is_balance_certified(id: Principal) -> bool {
+    CERTIFY_BACKFILL.with(|c| {
+        let backfill = c.borrow();
+        !backfill.running
+            || matches!(backfill.after, Some(after) if PrincipalKey(id) <= PrincipalKey(after))
+    })
+}
+
+fn _certified_witness(key: &[u8]) -> Vec<u8> {
+    CERTIFIED.with(|c| {
+        let tree = c.borrow();
+        let witness: HashTree = tree.witness(key);
+        let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+        serializer.self_describe().unwrap();
+        witness.serialize(&mut serializer).unwrap();
+        serializer.into_inner()
+    })
+}
+
+fn _http_response(status_code: u16, content_type: &str, body: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: body.into_bytes(),
+    }
+}
+
+fn _http_param(query: &str, name: &str) -> Option<usize> {
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .and_then(|(_, v)| v.parse().ok())
+}
+
+fn _http_metrics() -> String {
+    let info = get_token_info();
+    let cap_outbox = TXLOG.with(|t| t.borrow().ie_records.len());
+    let metrics = [
+        (
+            "wdn_holders",
+            "Number of accounts with a balance",
+            info.holderNumber.to_string(),
+        ),
+        (
+            "wdn_total_supply",
+            "Total supply in base units",
+            info.metadata.totalSupply.0.to_string(),
+        ),
+        (
+            "wdn_history_size",
+            "Number of recorded operations",
+            info.historySize.to_string(),
+        ),
+        (
+            "wdn_cycles",
+            "Cycle balance of the canister",
+            info.cycles.to_string(),
+        ),
+        (
+            "wdn_cap_outbox",
+            "Events waiting to be inserted into CAP",
+            cap_outbox.to_string(),
+        ),
+    ];
+    let mut out = String::new();
+    for (name, help, value) in metrics.iter() {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+            name, help, name, name, value
+        ));
+    }
+    out
+}
+
+fn _history_entry_json(index: u64, entry: HistoryEntry) -> serde_json::Value {
+    let details: serde_json::Map<String, serde_json::Value> = entry
+        .event
+        .details
+        .into_iter()
+        .map(|(k, v)| (k, _detail_value_json(v)))
+        .collect();
+    serde_json::json!({
+        "index": index,
+        "timestamp": entry.timestamp,
+        "caller": entry.event.caller.to_text(),
+        "operation": entry.event.operation,
+        "details": details,
+    })
+}
+
+fn _detail_value_json(value: DetailValue) -> serde_json::Value {
+    match value {
+        DetailValue::True => serde_json::Value::Bool(true),
+        DetailValue::False => serde_json::Value::Bool(false),
+        DetailValue::U64(v) => v.into(),
+        DetailValue::I64(v) => v.into(),
+        DetailValue::Float(v) => v.into(),
+        DetailValue::Text(v) => v.into(),
+        DetailValue::Principal(v) => v.to_text().into(),
+        DetailValue::Slice(v) => v.into(),
+        DetailValue::Vec(v) => v.into_iter().map(_detail_value_json).collect(),
+        DetailValue::TokenIdU64(v) => v.into(),
+    }
+}
+
+fn _memory(id: MemoryId) -> StableMemory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+fn _has_memory_manager() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    &magic == MEMORY_MANAGER_MAGIC
+}
+
+fn _history_inc() {
+    STATS.with(|s| {
+        let mut stats = s.borrow_mut();
+        stats.history_size += 1;
+    })
+}
+
+#[cfg(any(target_arch = "wasm32", test))]
+fn main() {}
+
+#[cfg(not(any(target_arch = "wasm32", test)))]
+fn main() {
+    candid::export_service!();
+    std::print!("{}", __export_service());
+}
+
+#[heartbeat]
+async fn heartbeat() {
+    _certify_backfill_step();
+    for escrow in _settle_expired_escrows(ic::time(), ESCROW_REFUNDS_PER_BEAT) {
+        let _ = _refund_escrow(ic::id(), escrow).await;
+    }
+    cycles::heartbeat(&CYCLES).await;
+}
+
+// Everything that is kept per account or per record lives in stable structures
+// and survives upgrades as it is. Only the counters and settings below are
+// written to the upgrades memory, prefixed with their length.
+#[derive(CandidType, Default, Deserialize)]
+struct UpgradeState {
+    stats: StatsData,
+    tx_log: TxLog,
+    snapshots: Snapshots,
+    vestings: Vestings,
+    staking: Staking,
+    fee_policy: FeePolicy,
+    fee_rules: FeeRules,
+    notifications: Notifications,
+    escrows: Escrows,
+    streams: Streams,
+    cycles: CyclesMonitor,
+}
+
+fn _save_heap_state() -> UpgradeState {
+    UpgradeState {
+        stats: STATS.with(|s| s.borrow().clone()),
+        tx_log: TXLOG.with(|t| t.borrow().clone()),
+        snapshots: SNAPSHOTS.with(|s| s.borrow().clone()),
+        vestings: VESTINGS.with(|v| v.borrow().clone()),
+        staking: STAKING.with(|s| s.borrow().clone()),
+        fee_policy: FEE_POLICY.with(|f| f.borrow().clone()),
+        fee_rules: FEE_RULES.with(|f| f.borrow().clone()),
+        notifications: NOTIFICATIONS.with(|n| n.borrow().clone()),
+        escrows: ESCROWS.with(|e| e.borrow().clone()),
+        streams: STREAMS.with(|s| s.borrow().clone()),
+        cycles: CYCLES.with(|c| c.borrow().clone()),
+    }
+}
+
+fn _restore_heap_state(state: UpgradeState) {
+    let UpgradeState {
+        stats,
+        tx_log,
+        snapshots,
+        vestings,
+        staking,
+        fee_policy,
+        fee_rules,
+        notifications,
+        escrows,
+        streams,
+        cycles,
+    } = state;
+    STATS.with(|s| *s.borrow_mut() = stats);
+    TXLOG.with(|t| *t.borrow_mut() = tx_log);
+    SNAPSHOTS.with(|s| *s.borrow_mut() = snapshots);
+    VESTINGS.with(|v| *v.borrow_mut() = vestings);
+    STAKING.with(|s| *s.borrow_mut() = staking);
+    FEE_POLICY.with(|f| *f.borrow_mut() = fee_policy);
+    FEE_RULES.with(|f| *f.borrow_mut() = fee_rules);
+    NOTIFICATIONS.with(|n| *n.borrow_mut() = notifications);
+    ESCROWS.with(|e| *e.borrow_mut() = escrows);
+    STREAMS.with(|s| *s.borrow_mut() = streams);
+    CYCLES.with(|c| *c.borrow_mut() = cycles);
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let bytes = candid::encode_one(_save_heap_state()).unwrap();
+    let mut memory = _memory(UPGRADES_MEMORY);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write_all(&(bytes.len() as u32).to_le_bytes())
+        .unwrap();
+    writer.write_all(&bytes).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    if !_has_memory_manager() {
+        _migrate_legacy_state();
+    } else {
+        let memory = _memory(UPGRADES_MEMORY);
+        let mut len_bytes = [0; 4];
+        memory.read(0, &mut len_bytes);
+        let mut bytes = vec![0; u32::from_le_bytes(len_bytes) as usize];
+        memory.read(4, &mut bytes);
+        _restore_heap_state(candid::decode_one(&bytes).unwrap());
+    }
+    _build_holder_index();
+    _certify_metadata();
+    _start_certify_backfill();
+    _certify_backfill_step();
+}
+
+// One-time move from the layout where pre_upgrade serialized every map with
+// ic::stable_store. The blob has to be decoded before the memory manager claims
+// stable memory, since initializing it overwrites the start of the old data.
+fn _migrate_legacy_state() {
+    let (metadata_stored, balances_stored, allowances_stored, tx_log_stored): (
+        StatsData,
+        Balances,
+        Allowances,
+        TxLog,
+    ) = ic::stable_restore().unwrap();
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        for (k, v) in balances_stored {
+            balances.insert(PrincipalKey(k), StableNat(v));
+        }
+    });
+    ALLOWS.with(|a| {
+        let mut allowances = a.borrow_mut();
+        for (owner, inner) in allowances_stored {
+            for (spender, v) in inner {
+                allowances.insert(AllowanceKey { owner, spender }, StableNat(v));
+            }
+        }
+    });
+    _restore_heap_state(UpgradeState {
+        stats: metadata_stored,
+        tx_log: tx_log_stored,
+        ..Default::default()
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn add_record(
+    caller: Principal,
+    op: Operation,
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+    fee: Nat,
+    timestamp: u64,
+    status: TransactionStatus,
+) -> TxReceipt {
+    insert_into_cap(_tx_record_event(TxRecord {
+        caller: Some(caller),
+        index: Nat::from(0),
+        from,
+        to,
+        amount,
+        fee,
+        timestamp: Int::from(timestamp),
+        status,
+        operation: op,
+    }))
+    .await
+}
+
+fn _tx_record_event(record: TxRecord) -> IndefiniteEvent {
+    Into::<IndefiniteEvent>::into(Into::<Event>::into(Into::<TypedEvent<DIP20Details>>::into(
+        record,
+    )))
+}
+
+// records operations that have no DIP20 counterpart
+async fn add_event(
+    caller: Principal,
+    operation: &str,
+    details: Vec<(&str, DetailValue)>,
+) -> TxReceipt {
+    insert_into_cap(IndefiniteEvent {
+        caller,
+        operation: operation.to_string(),
+        details: details
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    })
+    .await
+}
+
+pub async fn insert_into_cap(ie: IndefiniteEvent) -> TxReceipt {
+    _append_history(&ie);
+    let mut tx_log = TXLOG.with(|t| t.take());
+    if let Some(failed_ie) = tx_log.ie_records.pop_front() {
+        let _ = insert_into_cap_priv(failed_ie).await;
+    }
+    insert_into_cap_priv(ie).await
+}
+
+fn _append_history(ie: &IndefiniteEvent) {
+    HISTORY.with(|h| {
+        h.borrow()
+            .append(&HistoryEntry {
+                timestamp: ic::time(),
+                event: ie.clone(),
+            })
+            .expect("failed to append to the history log");
+    });
+}
+
+// for records that cannot be sent to cap right away, they go out with the next one
+fn _queue_event(ie: IndefiniteEvent) {
+    _append_history(&ie);
+    TXLOG.with(|t| t.borrow_mut().ie_records.push_back(ie));
+}
+
+async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
+    let insert_res = insert(ie.clone()).await.map(Nat::from).map_err(|error| {
+        TxError::Other(format!("Inserting into cap failed with error: {:?}", error))
+    });
+
+    if insert_res.is_err() {
+        TXLOG.with(|t| {
             let mut tx_log = t.borrow_mut();
             tx_log.ie_records.push_back(ie.clone());
         });
@@ -749,3 +3881,947 @@ async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
 
     insert_res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::mock_principals::{alice, bob};
+    use ic_kit::MockContext;
+
+    fn setup() -> &'static mut MockContext {
+        MockContext::new().with_caller(alice()).inject()
+    }
+
+    // opaque ids, distinct from the mock principals
+    fn account(i: u32) -> Principal {
+        let mut bytes = i.to_be_bytes().to_vec();
+        bytes.push(1);
+        Principal::from_slice(&bytes)
+    }
+
+    fn is_in_certified_tree(id: Principal) -> bool {
+        CERTIFIED.with(|c| c.borrow().get(&_certified_balance_key(id)).is_some())
+    }
+
+    #[test]
+    fn certified_balances_follow_transfers() {
+        let ctx = setup();
+        _balance_ins(alice(), Nat::from(100));
+        _transfer(alice(), bob(), Nat::from(100));
+
+        let certified = certified_balance_of(bob());
+        assert_eq!(certified.value, Nat::from(100));
+        assert!(certified.certificate.is_some());
+        assert!(is_in_certified_tree(bob()));
+        assert!(!is_in_certified_tree(alice()));
+        let root = CERTIFIED.with(|c| c.borrow().root_hash().to_vec());
+        assert_eq!(ctx.get_certified_data(), Some(root));
+    }
+
+    #[test]
+    fn certified_tree_is_refilled_in_batches_after_upgrade() {
+        setup();
+        let holders = CERTIFY_BATCH as u32 + 10;
+        BALANCES.with(|b| {
+            let mut balances = b.borrow_mut();
+            for i in 0..holders {
+                balances.insert(PrincipalKey(account(i)), StableNat(Nat::from(1)));
+            }
+        });
+        _start_certify_backfill();
+        assert!(!_is_balance_certified(account(0)));
+        assert_eq!(certified_balance_of(account(0)).certificate, None);
+
+        _certify_backfill_step();
+        let covered = (0..holders)
+            .filter(|i| is_in_certified_tree(account(*i)))
+            .count();
+        assert_eq!(covered, CERTIFY_BATCH);
+        assert!(CERTIFY_BACKFILL.with(|c| c.borrow().running));
+        for i in 0..holders {
+            assert_eq!(
+                _is_balance_certified(account(i)),
+                is_in_certified_tree(account(i))
+            );
+        }
+
+        _certify_backfill_step();
+        assert!(!CERTIFY_BACKFILL.with(|c| c.borrow().running));
+        assert!((0..holders).all(|i| is_in_certified_tree(account(i))));
+        assert!(certified_balance_of(account(0)).certificate.is_some());
+    }
+
+    #[test]
+    fn snapshots_keep_the_balance_before_the_first_change() {
+        setup();
+        _balance_ins(alice(), Nat::from(100));
+        let first = take_snapshot();
+        _transfer(alice(), bob(), Nat::from(30));
+        _transfer(alice(), bob(), Nat::from(20));
+        let second = take_snapshot();
+        _transfer(bob(), alice(), Nat::from(10));
+
+        assert_eq!(balance_of_at(alice(), first), Ok(Nat::from(100)));
+        assert_eq!(balance_of_at(bob(), first), Ok(Nat::from(0)));
+        assert_eq!(balance_of_at(alice(), second), Ok(Nat::from(50)));
+        assert_eq!(balance_of_at(bob(), second), Ok(Nat::from(50)));
+        assert!(balance_of_at(alice(), second + 1).is_err());
+        assert!(balance_of_at(alice(), 0).is_err());
+    }
+
+    #[test]
+    fn snapshots_keep_the_total_supply() {
+        setup();
+        _balance_ins(alice(), Nat::from(100));
+        STATS.with(|s| s.borrow_mut().total_supply = Nat::from(100));
+        let first = take_snapshot();
+        _burn_from(alice(), Nat::from(40));
+        let second = take_snapshot();
+
+        assert_eq!(total_supply_at(first), Ok(Nat::from(100)));
+        assert_eq!(total_supply_at(second), Ok(Nat::from(60)));
+    }
+
+    #[test]
+    fn snapshot_holders_are_paged_in_principal_order() {
+        setup();
+        for i in 0..10 {
+            _balance_ins(account(i), Nat::from(i + 1));
+        }
+        let snapshot_id = take_snapshot();
+        // emptied after the snapshot, still a holder at it
+        _transfer(account(3), account(20), Nat::from(4));
+        // only holds tokens after the snapshot
+        _balance_ins(account(30), Nat::from(7));
+
+        let expected: Vec<(Principal, Nat)> =
+            (0..10).map(|i| (account(i), Nat::from(i + 1))).collect();
+        assert_eq!(get_snapshot_holders(snapshot_id, 0, 100), expected);
+        assert_eq!(get_snapshot_holders(snapshot_id, 4, 3), expected[4..7]);
+
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let page = get_snapshot_holders_after(snapshot_id, after, 3);
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().map(|(p, _)| *p);
+            paged.extend(page);
+        }
+        assert_eq!(paged, expected);
+    }
+
+    // larger than any amount a stable record has to hold
+    fn huge_nat() -> Nat {
+        Nat(Nat::from(1u64).0 << 440)
+    }
+
+    fn long_principal() -> Principal {
+        Principal::from_slice(&[0xab; 29])
+    }
+
+    fn vesting(beneficiary: Principal, total: u64, revocable: bool) -> VestingSchedule {
+        VestingSchedule {
+            id: 0,
+            beneficiary,
+            total: Nat::from(total),
+            released: Nat::from(0),
+            start: 0,
+            cliff: 100,
+            duration: 1000,
+            interval: 10,
+            revocable,
+            revoked: false,
+        }
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn vesting_follows_cliff_and_interval() {
+        let schedule = vesting(alice(), 1000, false);
+        assert_eq!(_vested_amount(&schedule, 99 * SECOND), Nat::from(0));
+        assert_eq!(_vested_amount(&schedule, 100 * SECOND), Nat::from(100));
+        assert_eq!(_vested_amount(&schedule, 109 * SECOND), Nat::from(100));
+        assert_eq!(_vested_amount(&schedule, 555 * SECOND), Nat::from(550));
+        assert_eq!(_vested_amount(&schedule, 5000 * SECOND), Nat::from(1000));
+    }
+
+    #[test]
+    fn vesting_locks_the_unreleased_part_of_each_beneficiary() {
+        setup();
+        _balance_ins(alice(), Nat::from(1500));
+        _add_vesting_schedule(vesting(alice(), 1000, false));
+        _add_vesting_schedule(vesting(alice(), 400, false));
+        _add_vesting_schedule(vesting(bob(), 300, false));
+        assert_eq!(locked_balance_of(alice()), Nat::from(1400));
+        assert_eq!(locked_balance_of(bob()), Nat::from(300));
+        assert_eq!(_spendable_of(alice()), Nat::from(100));
+
+        assert_eq!(_release_vested(alice(), 500 * SECOND), Nat::from(700));
+        assert_eq!(_release_vested(alice(), 500 * SECOND), Nat::from(0));
+        assert_eq!(locked_balance_of(alice()), Nat::from(700));
+        assert_eq!(_spendable_of(alice()), Nat::from(800));
+        assert_eq!(locked_balance_of(bob()), Nat::from(300));
+        assert_eq!(_vesting_schedules_of(alice()).len(), 2);
+    }
+
+    #[test]
+    fn revoked_vesting_keeps_what_has_vested() {
+        setup();
+        let revocable = _add_vesting_schedule(vesting(alice(), 1000, true));
+        let fixed = _add_vesting_schedule(vesting(alice(), 1000, false));
+
+        assert!(_revoke_vesting(fixed, 500 * SECOND).is_err());
+        assert_eq!(
+            _revoke_vesting(revocable, 500 * SECOND),
+            Ok((alice(), Nat::from(500)))
+        );
+        assert!(_revoke_vesting(revocable, 600 * SECOND).is_err());
+        // the vested half stays locked until it is released
+        assert_eq!(locked_balance_of(alice()), Nat::from(1500));
+        assert_eq!(_release_vested(alice(), 2000 * SECOND), Nat::from(1500));
+        assert_eq!(locked_balance_of(alice()), Nat::from(0));
+    }
+
+    #[test]
+    fn vesting_schedules_fit_their_stable_bound() {
+        let mut schedule = vesting(long_principal(), 0, true);
+        schedule.id = u64::MAX;
+        schedule.total = huge_nat();
+        schedule.released = huge_nat();
+        assert!(schedule.to_bytes().len() <= VestingSchedule::MAX_SIZE as usize);
+    }
+
+    fn stake_at(owner: Principal, amount: u64, term_days: u64, now: u64) -> u64 {
+        _open_stake_position(owner, Nat::from(amount), term_days, now).unwrap()
+    }
+
+    #[test]
+    fn stake_rewards_follow_weight() {
+        setup();
+        let start = 1000 * SECOND;
+        STAKING.with(|s| s.borrow_mut().emission_per_second = Nat::from(10));
+        let alice_id = stake_at(alice(), 100, 30, start);
+        let bob_id = stake_at(bob(), 100, 90, start);
+        STAKING.with(|s| s.borrow_mut().distribute_fee(Nat::from(50)));
+
+        let now = start + 10 * SECOND;
+        assert_eq!(
+            _claim_stake_rewards(alice(), alice_id, now),
+            Ok((Nat::from(20), Nat::from(40)))
+        );
+        assert_eq!(
+            _claim_stake_rewards(alice(), alice_id, now),
+            Ok((Nat::from(0), Nat::from(0)))
+        );
+        assert_eq!(
+            _claim_stake_rewards(bob(), bob_id, now),
+            Ok((Nat::from(30), Nat::from(60)))
+        );
+        assert!(_claim_stake_rewards(bob(), alice_id, now).is_err());
+    }
+
+    #[test]
+    fn early_unlock_penalty_goes_to_remaining_stakers() {
+        setup();
+        let start = 1000 * SECOND;
+        set_early_unlock_penalty(1000).unwrap();
+        let alice_id = stake_at(alice(), 100, 30, start);
+        let bob_id = stake_at(bob(), 100, 30, start);
+
+        let (returned, emission, penalty, to_fee_to) =
+            _close_stake_position(alice(), alice_id, start + SECOND).unwrap();
+        assert_eq!(returned, Nat::from(90));
+        assert_eq!(emission, Nat::from(0));
+        assert_eq!(penalty, Nat::from(10));
+        assert_eq!(to_fee_to, Nat::from(0));
+        assert!(_close_stake_position(alice(), alice_id, start + SECOND).is_err());
+
+        let unlocked = start + 30 * 86400 * SECOND;
+        let (returned, _, penalty, _) = _close_stake_position(bob(), bob_id, unlocked).unwrap();
+        assert_eq!(returned, Nat::from(110));
+        assert_eq!(penalty, Nat::from(0));
+        STAKING.with(|s| {
+            let staking = s.borrow();
+            assert_eq!(staking.total_staked, Nat::from(0));
+            assert_eq!(staking.total_weight, Nat::from(0));
+        });
+    }
+
+    #[test]
+    fn early_unlock_penalty_of_the_last_staker_goes_to_fee_to() {
+        setup();
+        let start = 1000 * SECOND;
+        set_early_unlock_penalty(1000).unwrap();
+        let id = stake_at(alice(), 100, 30, start);
+        let (returned, _, penalty, to_fee_to) =
+            _close_stake_position(alice(), id, start + SECOND).unwrap();
+        assert_eq!(returned, Nat::from(90));
+        assert_eq!(to_fee_to, penalty);
+        assert!(set_early_unlock_penalty(10001).is_err());
+    }
+
+    #[test]
+    fn stake_positions_are_listed_per_owner() {
+        setup();
+        let first = stake_at(alice(), 100, 30, 0);
+        stake_at(bob(), 100, 30, 0);
+        let second = stake_at(alice(), 200, 180, 0);
+        assert!(_open_stake_position(alice(), Nat::from(100), 7, 0).is_err());
+
+        let ids: Vec<u64> = get_stake_positions(alice())
+            .iter()
+            .map(|p| p.position.id)
+            .collect();
+        assert_eq!(ids, vec![first, second]);
+        assert_eq!(get_stake_positions(bob()).len(), 1);
+        assert!(get_stake_positions(account(1)).is_empty());
+    }
+
+    #[test]
+    fn stake_position_fits_its_max_size() {
+        let position = StakePosition {
+            id: u64::MAX,
+            owner: long_principal(),
+            amount: huge_nat(),
+            weight: huge_nat(),
+            term_days: u64::MAX,
+            staked_at: u64::MAX,
+            unlock_at: u64::MAX,
+            fee_reward_debt: huge_nat(),
+            emission_reward_debt: huge_nat(),
+        };
+        assert!(position.to_bytes().len() <= StakePosition::MAX_SIZE as usize);
+    }
+
+    fn split(burn_bps: u64, staking_bps: u64, treasury_bps: u64) -> FeeSplit {
+        FeeSplit {
+            burn_bps,
+            staking_bps,
+            treasury_bps,
+            treasury: account(99),
+        }
+    }
+
+    #[test]
+    fn fee_split_is_charged_in_parts() {
+        setup();
+        let fee_to = account(98);
+        STATS.with(|s| {
+            let mut stats = s.borrow_mut();
+            stats.fee_to = fee_to;
+            stats.total_supply = Nat::from(1000);
+        });
+        _balance_ins(alice(), Nat::from(1000));
+        stake_at(bob(), 100, 30, 0);
+        set_fee_policy(FeePolicy::Split(split(1000, 2000, 3000))).unwrap();
+
+        let parts = _charge_fee(alice(), Nat::from(100));
+        assert_eq!(parts.len(), 4);
+        assert_eq!(balance_of(alice()), Nat::from(900));
+        assert_eq!(balance_of(account(99)), Nat::from(30));
+        assert_eq!(balance_of(fee_to), Nat::from(40));
+        assert_eq!(
+            STATS.with(|s| s.borrow().total_supply.clone()),
+            Nat::from(990)
+        );
+        STAKING.with(|s| assert_eq!(s.borrow().fee_reserve, Nat::from(20)));
+    }
+
+    #[test]
+    fn fee_split_above_the_whole_fee_is_rejected() {
+        setup();
+        assert!(set_fee_policy(FeePolicy::Split(split(5000, 5000, 1))).is_err());
+        assert!(set_fee_policy(FeePolicy::Split(split(u64::MAX, 1, 0))).is_err());
+        assert!(matches!(get_fee_policy(), FeePolicy::FeeTo));
+        assert!(set_fee_policy(FeePolicy::Split(split(5000, 5000, 0))).is_ok());
+    }
+
+    #[test]
+    fn staking_fee_share_updates_the_fee_policy() {
+        setup();
+        let fee_to = account(98);
+        STATS.with(|s| s.borrow_mut().fee_to = fee_to);
+
+        set_staking_fee_share(0).unwrap();
+        assert!(matches!(get_fee_policy(), FeePolicy::FeeTo));
+        set_staking_fee_share(2500).unwrap();
+        match get_fee_policy() {
+            FeePolicy::Split(s) => {
+                assert_eq!((s.burn_bps, s.staking_bps, s.treasury_bps), (0, 2500, 0));
+            }
+            p => panic!("unexpected policy {:?}", p),
+        }
+
+        set_fee_policy(FeePolicy::Burn).unwrap();
+        set_staking_fee_share(2500).unwrap();
+        match get_fee_policy() {
+            FeePolicy::Split(s) => assert_eq!((s.burn_bps, s.staking_bps), (7500, 2500)),
+            p => panic!("unexpected policy {:?}", p),
+        }
+
+        set_fee_policy(FeePolicy::Split(split(1000, 0, 8000))).unwrap();
+        assert!(set_staking_fee_share(1001).is_err());
+        set_staking_fee_share(1000).unwrap();
+        match get_fee_policy() {
+            FeePolicy::Split(s) => {
+                assert_eq!(
+                    (s.burn_bps, s.staking_bps, s.treasury_bps),
+                    (1000, 1000, 8000)
+                );
+                assert_eq!(s.treasury, account(99));
+            }
+            p => panic!("unexpected policy {:?}", p),
+        }
+    }
+
+    #[test]
+    fn fee_tier_is_clamped_and_exempt_accounts_pay_nothing() {
+        setup();
+        STATS.with(|s| s.borrow_mut().fee = Nat::from(7));
+        assert_eq!(get_fee_for(alice(), bob(), Nat::from(1000)), Nat::from(7));
+
+        set_fee_tier(Some(FeeTier {
+            rate_bps: 100,
+            min: Nat::from(2),
+            max: Nat::from(50),
+        }))
+        .unwrap();
+        assert_eq!(get_fee_for(alice(), bob(), Nat::from(1000)), Nat::from(10));
+        assert_eq!(get_fee_for(alice(), bob(), Nat::from(10)), Nat::from(2));
+        assert_eq!(
+            get_fee_for(alice(), bob(), Nat::from(100000)),
+            Nat::from(50)
+        );
+        // no amount moved, the flat fee applies
+        assert_eq!(_get_fee_for(alice(), bob(), None), Nat::from(7));
+
+        add_fee_exempt(bob());
+        assert_eq!(get_fee_exempt(), vec![bob()]);
+        assert_eq!(get_fee_for(alice(), bob(), Nat::from(1000)), Nat::from(0));
+        assert_eq!(get_fee_for(bob(), alice(), Nat::from(1000)), Nat::from(0));
+        remove_fee_exempt(bob());
+        assert!(get_fee_exempt().is_empty());
+        assert_eq!(get_fee_for(bob(), alice(), Nat::from(1000)), Nat::from(10));
+    }
+
+    #[test]
+    fn invalid_fee_tiers_are_rejected() {
+        setup();
+        let tier = |rate_bps, min: u64, max: u64| FeeTier {
+            rate_bps,
+            min: Nat::from(min),
+            max: Nat::from(max),
+        };
+        assert!(set_fee_tier(Some(tier(10001, 0, 1))).is_err());
+        assert!(set_fee_tier(Some(tier(100, 5, 4))).is_err());
+        assert!(get_fee_tier().is_none());
+        assert!(set_fee_tier(Some(tier(100, 4, 4))).is_ok());
+        assert!(set_fee_tier(None).is_ok());
+        assert!(get_fee_tier().is_none());
+    }
+
+    fn expected_holders() -> Vec<(Principal, Nat)> {
+        let mut holders: Vec<(Principal, Nat)> =
+            BALANCES.with(|b| b.borrow().iter().map(|(k, v)| (k.0, v.0)).collect());
+        holders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        holders
+    }
+
+    fn fill_holders() {
+        for i in 0..400u32 {
+            // plenty of equal balances, and a few very large ones
+            let balance = match i % 5 {
+                0 => Nat::from(1000u64),
+                1 => Nat::from(u64::from(i) * 7919),
+                2 => Nat::from(u64::from(i % 3) + 1),
+                3 => Nat::from(u64::MAX) * Nat::from(i + 1),
+                _ => Nat::from(256u64 * 256 * u64::from(i % 4 + 1)),
+            };
+            _balance_ins(account(i), balance);
+        }
+        _balance_ins(alice(), Nat::from(1000u64));
+        _balance_ins(long_principal(), Nat::from(1000u64));
+        _transfer(account(3), account(5), Nat::from(500u64));
+        _balance_sub(account(7), balance_of(account(7)));
+    }
+
+    #[test]
+    fn holder_ranks_and_offsets_follow_the_balance_order() {
+        setup();
+        fill_holders();
+        let expected = expected_holders();
+        assert_eq!(get_holders(0, 1000), expected);
+        assert_eq!(_holder_count(), expected.len() as u64);
+        for (i, (holder, _)) in expected.iter().enumerate() {
+            assert_eq!(get_holder_rank(*holder), Some(i as u64 + 1));
+        }
+        for start in [1, 57, 200, expected.len() - 3] {
+            assert_eq!(
+                get_holders(start, 10),
+                expected[start..start + 10.min(expected.len() - start)]
+            );
+        }
+        assert!(get_holders(expected.len(), 10).is_empty());
+        assert_eq!(get_holder_rank(account(7)), None);
+    }
+
+    #[test]
+    fn holders_are_paged_with_a_cursor() {
+        setup();
+        fill_holders();
+        let mut paged = Vec::new();
+        let mut after = None;
+        loop {
+            let page = get_holders_after(after, 33);
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().cloned();
+            paged.extend(page);
+        }
+        assert_eq!(paged, expected_holders());
+    }
+
+    #[test]
+    fn holder_index_is_rebuilt_from_balances() {
+        setup();
+        fill_holders();
+        // left behind by an older layout of the index
+        HOLDERS.with(|h| {
+            h.borrow_mut().insert(
+                HolderKey {
+                    balance: Nat::from(5u64),
+                    holder: account(1000),
+                },
+                (),
+            )
+        });
+        _build_holder_index();
+        let expected = expected_holders();
+        assert_eq!(get_holders(0, 1000), expected);
+        assert_eq!(_holder_count(), expected.len() as u64);
+        assert_eq!(get_holder_rank(expected[9].0), Some(10));
+    }
+
+    #[test]
+    fn holder_keys_fit_their_max_size() {
+        let key = HolderKey {
+            balance: huge_nat(),
+            holder: long_principal(),
+        };
+        let bytes = key.to_bytes();
+        assert!(bytes.len() <= HolderKey::MAX_SIZE as usize);
+        assert!(HolderKey::from_bytes(bytes.clone()) == key);
+        let depth = _holder_rank_depth(&bytes);
+        assert!(RankPrefix::new(depth, &bytes[..depth]).0.len() <= RankPrefix::MAX_SIZE as usize);
+    }
+
+    fn notification(from: Principal, to: Principal, payload: Vec<u8>) -> Notification {
+        Notification {
+            id: 0,
+            from,
+            to,
+            amount: Nat::from(10u64),
+            payload,
+            status: NotificationStatus::Pending,
+            attempts: 0,
+            created_at: 0,
+            last_attempt_at: 0,
+        }
+    }
+
+    #[test]
+    fn delivered_notifications_drop_their_payload() {
+        setup();
+        let first = _add_notification(notification(alice(), bob(), vec![1, 2, 3]));
+        let second = _add_notification(notification(bob(), account(1), vec![4]));
+
+        let ids: Vec<u64> = get_notifications(bob(), 0, 10)
+            .iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(ids, vec![second, first]);
+        assert_eq!(get_notifications(alice(), 0, 10).len(), 1);
+        assert_eq!(get_notifications(bob(), 1, 10)[0].id, first);
+
+        _finish_notification(first, Err("trapped".to_string()));
+        assert_eq!(
+            _notification_status(first),
+            NotificationStatus::Failed("trapped".to_string())
+        );
+        assert_eq!(get_notification(first).unwrap().payload, vec![1, 2, 3]);
+        assert!(_can_retry_notification(first, account(1)).is_err());
+        assert_eq!(_can_retry_notification(first, bob()), Ok(true));
+        assert_eq!(_notification_status(first), NotificationStatus::Pending);
+
+        _finish_notification(first, Ok(()));
+        assert_eq!(_notification_status(first), NotificationStatus::Delivered);
+        assert!(get_notification(first).unwrap().payload.is_empty());
+        assert!(NOTIFICATION_PAYLOADS.with(|n| n.borrow().get(&first).is_none()));
+        assert_eq!(_can_retry_notification(first, alice()), Ok(false));
+    }
+
+    #[test]
+    fn notifications_only_go_to_other_canisters() {
+        let wdn = account(9);
+        assert!(_check_notification_recipient(bob(), wdn).is_ok());
+        assert!(_check_notification_recipient(wdn, wdn).is_err());
+        assert!(_check_notification_recipient(Principal::management_canister(), wdn).is_err());
+    }
+
+    #[test]
+    fn notification_fits_its_max_size() {
+        setup();
+        let mut n = notification(long_principal(), long_principal(), Vec::new());
+        n.amount = huge_nat();
+        n.attempts = u32::MAX;
+        n.created_at = u64::MAX;
+        n.last_attempt_at = u64::MAX;
+        let id = _add_notification(n);
+        _finish_notification(id, Err("é".repeat(MAX_NOTIFICATION_ERROR_SIZE as usize)));
+
+        let stored = get_notification(id).unwrap();
+        match &stored.status {
+            NotificationStatus::Failed(e) => {
+                assert!(e.len() <= MAX_NOTIFICATION_ERROR_SIZE as usize)
+            }
+            s => panic!("unexpected status {:?}", s),
+        }
+        assert!(stored.to_bytes().len() <= Notification::MAX_SIZE as usize);
+    }
+
+    fn escrow(payer: Principal, amount: u64, deadline: u64) -> Escrow {
+        Escrow {
+            id: 0,
+            payer,
+            payee: bob(),
+            arbiter: account(50),
+            amount: Nat::from(amount),
+            deadline,
+            created_at: 0,
+            status: EscrowStatus::Open,
+        }
+    }
+
+    #[test]
+    fn escrows_are_indexed_per_party_and_totalled_per_payer() {
+        setup();
+        let first = _add_escrow(escrow(alice(), 30, 10));
+        let second = _add_escrow(escrow(alice(), 20, 20));
+        let other = _add_escrow(escrow(account(1), 5, 30));
+
+        let ids = |who| -> Vec<u64> { get_escrows(who, 0, 10).iter().map(|e| e.id).collect() };
+        assert_eq!(ids(alice()), vec![second, first]);
+        assert_eq!(ids(bob()), vec![other, second, first]);
+        assert_eq!(ids(account(50)), vec![other, second, first]);
+        assert_eq!(get_escrows(bob(), 1, 1)[0].id, second);
+        assert_eq!(escrowed_balance_of(alice()), Nat::from(50));
+
+        let released = _settle_escrow(first, EscrowStatus::Released).unwrap();
+        assert_eq!(released.amount, Nat::from(30));
+        assert!(_settle_escrow(first, EscrowStatus::Refunded).is_none());
+        assert_eq!(get_escrow(first).unwrap().status, EscrowStatus::Released);
+        assert_eq!(escrowed_balance_of(alice()), Nat::from(20));
+        _settle_escrow(second, EscrowStatus::Refunded).unwrap();
+        assert_eq!(escrowed_balance_of(alice()), Nat::from(0));
+        assert!(ESCROWED.with(|e| e.borrow().get(&PrincipalKey(alice())).is_none()));
+    }
+
+    #[test]
+    fn expired_escrows_are_refunded_in_deadline_order() {
+        setup();
+        let late = _add_escrow(escrow(alice(), 1, 300));
+        let early = _add_escrow(escrow(alice(), 1, 100));
+        let middle = _add_escrow(escrow(alice(), 1, 200));
+        _settle_escrow(middle, EscrowStatus::Released).unwrap();
+
+        assert!(_settle_expired_escrows(99, 10).is_empty());
+        let expired: Vec<u64> = _settle_expired_escrows(1000, 1)
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(expired, vec![early]);
+        let expired: Vec<u64> = _settle_expired_escrows(1000, 10)
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(expired, vec![late]);
+        assert_eq!(get_escrow(late).unwrap().status, EscrowStatus::Refunded);
+        assert!(_settle_expired_escrows(1000, 10).is_empty());
+    }
+
+    #[test]
+    fn escrow_fits_its_max_size() {
+        let mut e = escrow(long_principal(), u64::MAX, u64::MAX);
+        e.id = u64::MAX;
+        e.payee = long_principal();
+        e.arbiter = long_principal();
+        e.amount = huge_nat();
+        e.created_at = u64::MAX;
+        e.status = EscrowStatus::Refunded;
+        assert!(e.to_bytes().len() <= Escrow::MAX_SIZE as usize);
+    }
+
+    fn stream(sender: Principal, recipient: Principal, start: u64) -> Stream {
+        Stream {
+            id: 0,
+            sender,
+            recipient,
+            rate_per_second: Nat::from(10u64),
+            deposit: Nat::from(100u64),
+            withdrawn: Nat::from(0),
+            start,
+            canceled_at: None,
+            status: StreamStatus::Active,
+        }
+    }
+
+    #[test]
+    fn streams_are_withdrawn_until_completed() {
+        setup();
+        let id = _add_stream(stream(alice(), bob(), 0));
+        assert!(_withdraw_from_stream(alice(), id, 3 * SECOND).is_err());
+        assert_eq!(
+            _withdraw_from_stream(bob(), id, 3 * SECOND),
+            Ok(Nat::from(30))
+        );
+        assert_eq!(
+            _withdraw_from_stream(bob(), id, 3 * SECOND),
+            Err(TxError::AmountTooSmall)
+        );
+        assert_eq!(
+            _withdraw_from_stream(bob(), id, 60 * SECOND),
+            Ok(Nat::from(70))
+        );
+        let stored = get_stream(id).unwrap().stream;
+        assert_eq!(stored.status, StreamStatus::Completed);
+        assert!(_cancel_stream(alice(), id, 60 * SECOND).is_err());
+    }
+
+    #[test]
+    fn canceled_streams_split_the_deposit() {
+        setup();
+        let id = _add_stream(stream(alice(), bob(), 0));
+        _withdraw_from_stream(bob(), id, 2 * SECOND).unwrap();
+        assert!(_cancel_stream(account(1), id, 5 * SECOND).is_err());
+        let (canceled, to_recipient, to_sender) = _cancel_stream(alice(), id, 5 * SECOND).unwrap();
+        assert_eq!(to_recipient, Nat::from(30));
+        assert_eq!(to_sender, Nat::from(50));
+        assert_eq!(canceled.status, StreamStatus::Canceled);
+        // nothing accrues after the cancellation
+        assert_eq!(get_stream(id).unwrap().withdrawable, Nat::from(0));
+    }
+
+    #[test]
+    fn streams_are_listed_per_sender_and_recipient() {
+        setup();
+        let first = _add_stream(stream(alice(), bob(), 0));
+        let second = _add_stream(stream(bob(), alice(), 0));
+        let third = _add_stream(stream(alice(), account(1), 0));
+
+        let ids = |streams: Vec<StreamInfo>| -> Vec<u64> {
+            streams.iter().map(|s| s.stream.id).collect()
+        };
+        assert_eq!(
+            ids(get_streams_by_sender(alice(), 0, 10)),
+            vec![third, first]
+        );
+        assert_eq!(ids(get_streams_by_recipient(alice(), 0, 10)), vec![second]);
+        assert_eq!(ids(get_streams_by_sender(alice(), 1, 10)), vec![first]);
+        assert!(get_streams_by_recipient(account(2), 0, 10).is_empty());
+    }
+
+    #[test]
+    fn stream_fits_its_max_size() {
+        let mut s = stream(long_principal(), long_principal(), u64::MAX);
+        s.id = u64::MAX;
+        s.rate_per_second = huge_nat();
+        s.deposit = huge_nat();
+        s.withdrawn = huge_nat();
+        s.canceled_at = Some(u64::MAX);
+        s.status = StreamStatus::Completed;
+        assert!(s.to_bytes().len() <= Stream::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn freezes_are_audited() {
+        setup();
+        assert!(freeze_account(bob(), "stolen key".to_string()).is_ok());
+        assert!(_is_frozen(bob()));
+        assert_eq!(get_freeze(bob()).unwrap().frozen_by, alice());
+        assert_eq!(get_frozen_accounts().len(), 1);
+
+        assert!(unfreeze_account(bob(), "recovered".to_string()).is_ok());
+        // unfreezing an account that is not frozen leaves no entry
+        assert!(unfreeze_account(bob(), "again".to_string()).is_ok());
+        assert!(!_is_frozen(bob()));
+
+        let log = get_freeze_log(0, 10);
+        assert_eq!(log.len(), 2);
+        assert!(matches!(log[0].action, FreezeAction::Freeze));
+        assert!(matches!(log[1].action, FreezeAction::Unfreeze));
+        assert_eq!(log[1].reason, "recovered");
+        assert_eq!(get_freeze_log(1, 10).len(), 1);
+        assert!(get_freeze_log(5, 10).is_empty());
+    }
+
+    #[test]
+    fn freeze_reasons_are_bounded() {
+        setup();
+        let long = "x".repeat(MAX_FREEZE_REASON_SIZE as usize + 1);
+        assert!(freeze_account(bob(), long.clone()).is_err());
+        assert!(!_is_frozen(bob()));
+        assert!(unfreeze_account(bob(), long).is_err());
+
+        let longest = "x".repeat(MAX_FREEZE_REASON_SIZE as usize);
+        assert!(freeze_account(bob(), longest).is_ok());
+        assert!(get_freeze(bob()).is_some());
+    }
+
+    #[test]
+    fn force_transfer_leaves_vesting_locks_in_place() {
+        setup();
+        _balance_ins(bob(), Nat::from(1500));
+        let id = _add_vesting_schedule(vesting(bob(), 1000, true));
+        assert!(_force_transfer(bob(), alice(), Nat::from(500), String::new()).is_err());
+
+        freeze_account(bob(), String::new()).unwrap();
+        assert_eq!(
+            _force_transfer(bob(), alice(), Nat::from(501), String::new()),
+            Err(TxError::InsufficientBalance)
+        );
+        assert!(_force_transfer(bob(), alice(), Nat::from(500), String::new()).is_ok());
+        assert_eq!(balance_of(bob()), Nat::from(1000));
+        assert_eq!(balance_of(alice()), Nat::from(500));
+        // the unvested part is still there to be clawed back
+        let (_, unvested) = _revoke_vesting(id, 0).unwrap();
+        assert!(balance_of(bob()) >= unvested);
+        assert_eq!(get_freeze_log(0, 10).len(), 2);
+    }
+
+    #[test]
+    fn upgrade_state_does_not_grow_with_records() {
+        setup();
+        let empty = candid::encode_one(_save_heap_state()).unwrap().len();
+        for i in 0..50 {
+            _balance_ins(account(i), Nat::from(1000));
+            _add_vesting_schedule(vesting(account(i), 100, false));
+            _add_notification(notification(account(i), bob(), vec![0; 512]));
+            _add_escrow(escrow(account(i), 10, 1));
+            _add_stream(stream(account(i), bob(), 0));
+        }
+        freeze_account(account(0), String::new()).unwrap();
+        let full = candid::encode_one(_save_heap_state()).unwrap().len();
+        // only the id counters change
+        assert!(full < empty + 32);
+    }
+
+    #[test]
+    fn upgrade_state_round_trips() {
+        setup();
+        let id = _add_vesting_schedule(vesting(alice(), 100, false));
+        _add_stream(stream(alice(), bob(), 0));
+        set_early_unlock_penalty(1234).unwrap();
+        let bytes = candid::encode_one(_save_heap_state()).unwrap();
+
+        _restore_heap_state(UpgradeState::default());
+        assert_eq!(STAKING.with(|s| s.borrow().early_unlock_penalty_bps), 0);
+
+        _restore_heap_state(candid::decode_one(&bytes).unwrap());
+        assert_eq!(VESTINGS.with(|v| v.borrow().next_id), id);
+        assert_eq!(STREAMS.with(|s| s.borrow().next_id), 1);
+        assert_eq!(STAKING.with(|s| s.borrow().early_unlock_penalty_bps), 1234);
+    }
+
+    #[test]
+    fn queued_events_are_kept_in_the_history() {
+        setup();
+        _queue_event(_tx_record_event(TxRecord {
+            caller: Some(alice()),
+            index: Nat::from(0),
+            from: alice(),
+            to: alice(),
+            amount: Nat::from(100),
+            fee: Nat::from(0),
+            timestamp: Int::from(0),
+            status: TransactionStatus::Succeeded,
+            operation: Operation::Mint,
+        }));
+        let history = get_transactions(0, 10);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].event.operation, "Mint");
+        assert_eq!(TXLOG.with(|t| t.borrow().ie_records.len()), 1);
+    }
+
+    fn get(url: &str) -> (u16, String) {
+        let res = http_request(HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        });
+        (res.status_code, String::from_utf8(res.body).unwrap())
+    }
+
+    #[test]
+    fn http_serves_supply_and_holders_as_json() {
+        setup();
+        STATS.with(|s| s.borrow_mut().total_supply = Nat::from(300));
+        _balance_ins(account(1), Nat::from(200));
+        _balance_ins(account(2), Nat::from(100));
+
+        let (status, body) = get("/supply");
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"totalSupply":"300"}"#);
+
+        let (status, body) = get("/holders?start=1&limit=5");
+        assert_eq!(status, 200);
+        let holders: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(holders.as_array().unwrap().len(), 1);
+        assert_eq!(holders[0]["principal"], account(2).to_text());
+        assert_eq!(holders[0]["balance"], "100");
+
+        let (_, body) = get("/metadata");
+        let metadata: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(metadata["totalSupply"], "300");
+        assert_eq!(metadata["holderNumber"], 2);
+    }
+
+    #[test]
+    fn http_serves_transactions_and_metrics() {
+        setup();
+        _queue_event(IndefiniteEvent {
+            caller: alice(),
+            operation: "freeze".to_string(),
+            details: Vec::new(),
+        });
+        let (status, body) = get("/tx/0");
+        assert_eq!(status, 200);
+        assert!(body.contains("freeze"));
+        assert_eq!(get("/tx/1").0, 404);
+        assert_eq!(get("/tx/x").0, 404);
+        assert_eq!(get("/unknown").0, 404);
+
+        let (status, body) = get("/metrics");
+        assert_eq!(status, 200);
+        assert!(body.contains("# TYPE wdn_cap_outbox gauge\nwdn_cap_outbox 1\n"));
+
+        let res = http_request(HttpRequest {
+            method: "POST".to_string(),
+            url: "/supply".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        });
+        assert_eq!(res.status_code, 405);
+    }
+
+    #[test]
+    fn http_params_are_parsed_from_the_query() {
+        assert_eq!(_http_param("start=5&limit=10", "limit"), Some(10));
+        assert_eq!(_http_param("start=5&limit=x", "limit"), None);
+        assert_eq!(_http_param("", "start"), None);
+    }
+}
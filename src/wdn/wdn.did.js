@@ -1,4 +1,5 @@
 export const idlFactory = ({ IDL }) => {
+  const DetailValue = IDL.Rec();
   const TxError = IDL.Variant({
     'InsufficientAllowance' : IDL.Null,
     'InsufficientBalance' : IDL.Null,
@@ -7,6 +8,7 @@ export const idlFactory = ({ IDL }) => {
     'LedgerTrap' : IDL.Null,
     'ErrorTo' : IDL.Null,
     'Other' : IDL.Text,
+    'Frozen' : IDL.Null,
     'BlockUsed' : IDL.Null,
     'AmountTooSmall' : IDL.Null,
   });
@@ -28,7 +30,209 @@ export const idlFactory = ({ IDL }) => {
     'cycles' : IDL.Nat64,
     'feeTo' : IDL.Principal,
   });
+  DetailValue.fill(
+    IDL.Variant({
+      'I64' : IDL.Int64,
+      'U64' : IDL.Nat64,
+      'Vec' : IDL.Vec(DetailValue),
+      'Slice' : IDL.Vec(IDL.Nat8),
+      'TokenIdU64' : IDL.Nat64,
+      'Text' : IDL.Text,
+      'True' : IDL.Null,
+      'False' : IDL.Null,
+      'Float' : IDL.Float64,
+      'Principal' : IDL.Principal,
+    })
+  );
+  const IndefiniteEvent = IDL.Record({
+    'operation' : IDL.Text,
+    'details' : IDL.Vec(IDL.Tuple(IDL.Text, DetailValue)),
+    'caller' : IDL.Principal,
+  });
+  const HistoryEntry = IDL.Record({
+    'event' : IndefiniteEvent,
+    'timestamp' : IDL.Nat64,
+  });
+  const VestingSchedule = IDL.Record({
+    'id' : IDL.Nat64,
+    'total' : IDL.Nat,
+    'duration' : IDL.Nat64,
+    'revoked' : IDL.Bool,
+    'interval' : IDL.Nat64,
+    'beneficiary' : IDL.Principal,
+    'cliff' : IDL.Nat64,
+    'revocable' : IDL.Bool,
+    'released' : IDL.Nat,
+    'start' : IDL.Nat64,
+  });
+  const VestingStatus = IDL.Record({
+    'vested' : IDL.Nat,
+    'releasable' : IDL.Nat,
+    'schedule' : VestingSchedule,
+  });
+  const StakePosition = IDL.Record({
+    'id' : IDL.Nat64,
+    'weight' : IDL.Nat,
+    'unlock_at' : IDL.Nat64,
+    'term_days' : IDL.Nat64,
+    'owner' : IDL.Principal,
+    'fee_reward_debt' : IDL.Nat,
+    'staked_at' : IDL.Nat64,
+    'emission_reward_debt' : IDL.Nat,
+    'amount' : IDL.Nat,
+  });
+  const StakePositionInfo = IDL.Record({
+    'pending_reward' : IDL.Nat,
+    'position' : StakePosition,
+  });
+  const StakingTerm = IDL.Record({
+    'days' : IDL.Nat64,
+    'weight_bps' : IDL.Nat64,
+  });
+  const StakingInfo = IDL.Record({
+    'terms' : IDL.Vec(StakingTerm),
+    'total_weight' : IDL.Nat,
+    'total_staked' : IDL.Nat,
+    'apr_bps' : IDL.Vec(IDL.Tuple(IDL.Nat64, IDL.Nat)),
+    'fee_share_bps' : IDL.Nat64,
+    'early_unlock_penalty_bps' : IDL.Nat64,
+    'emission_per_second' : IDL.Nat,
+  });
+  const FeeSplit = IDL.Record({
+    'burn_bps' : IDL.Nat64,
+    'treasury_bps' : IDL.Nat64,
+    'staking_bps' : IDL.Nat64,
+    'treasury' : IDL.Principal,
+  });
+  const FeePolicy = IDL.Variant({
+    'Split' : FeeSplit,
+    'Burn' : IDL.Null,
+    'FeeTo' : IDL.Null,
+  });
+  const FeeTier = IDL.Record({
+    'max' : IDL.Nat,
+    'min' : IDL.Nat,
+    'rate_bps' : IDL.Nat64,
+  });
+  const NotificationStatus = IDL.Variant({
+    'Failed' : IDL.Text,
+    'Delivered' : IDL.Null,
+    'Pending' : IDL.Null,
+  });
+  const Notification = IDL.Record({
+    'id' : IDL.Nat64,
+    'to' : IDL.Principal,
+    'status' : NotificationStatus,
+    'from' : IDL.Principal,
+    'attempts' : IDL.Nat32,
+    'created_at' : IDL.Nat64,
+    'last_attempt_at' : IDL.Nat64,
+    'amount' : IDL.Nat,
+    'payload' : IDL.Vec(IDL.Nat8),
+  });
+  const Result_1 = IDL.Variant({ 'Ok' : IDL.Nat64, 'Err' : TxError });
+  const Result_2 = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : TxError });
+  const Result_3 = IDL.Variant({ 'Ok' : NotificationStatus, 'Err' : TxError });
+  const EscrowStatus = IDL.Variant({
+    'Refunded' : IDL.Null,
+    'Open' : IDL.Null,
+    'Released' : IDL.Null,
+  });
+  const Escrow = IDL.Record({
+    'id' : IDL.Nat64,
+    'status' : EscrowStatus,
+    'arbiter' : IDL.Principal,
+    'deadline' : IDL.Nat64,
+    'created_at' : IDL.Nat64,
+    'payee' : IDL.Principal,
+    'payer' : IDL.Principal,
+    'amount' : IDL.Nat,
+  });
+  const StreamStatus = IDL.Variant({
+    'Active' : IDL.Null,
+    'Completed' : IDL.Null,
+    'Canceled' : IDL.Null,
+  });
+  const Stream = IDL.Record({
+    'id' : IDL.Nat64,
+    'status' : StreamStatus,
+    'rate_per_second' : IDL.Nat,
+    'recipient' : IDL.Principal,
+    'deposit' : IDL.Nat,
+    'sender' : IDL.Principal,
+    'canceled_at' : IDL.Opt(IDL.Nat64),
+    'start' : IDL.Nat64,
+    'withdrawn' : IDL.Nat,
+  });
+  const StreamInfo = IDL.Record({
+    'stream' : Stream,
+    'withdrawable' : IDL.Nat,
+  });
+  const FreezeRecord = IDL.Record({
+    'frozen_at' : IDL.Nat64,
+    'frozen_by' : IDL.Principal,
+    'reason' : IDL.Text,
+  });
+  const FreezeAction = IDL.Variant({
+    'ForceTransfer' : IDL.Null,
+    'Unfreeze' : IDL.Null,
+    'Freeze' : IDL.Null,
+  });
+  const FreezeLogEntry = IDL.Record({
+    'by' : IDL.Principal,
+    'action' : FreezeAction,
+    'account' : IDL.Principal,
+    'timestamp' : IDL.Nat64,
+    'reason' : IDL.Text,
+  });
+  const CertifiedNat = IDL.Record({
+    'certificate' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'value' : IDL.Nat,
+    'witness' : IDL.Vec(IDL.Nat8),
+  });
+  const CertifiedMetadata = IDL.Record({
+    'certificate' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'value' : Metadata,
+    'witness' : IDL.Vec(IDL.Nat8),
+  });
+  const HttpRequest = IDL.Record({
+    'url' : IDL.Text,
+    'method' : IDL.Text,
+    'body' : IDL.Vec(IDL.Nat8),
+    'headers' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
+  });
+  const HttpResponse = IDL.Record({
+    'body' : IDL.Vec(IDL.Nat8),
+    'headers' : IDL.Vec(IDL.Tuple(IDL.Text, IDL.Text)),
+    'status_code' : IDL.Nat16,
+  });
+  const TopUpSource = IDL.Variant({
+    'CyclesMinting' : IDL.Record({ 'e8s' : IDL.Nat64 }),
+    'Canister' : IDL.Record({ 'cycles' : IDL.Nat64, 'canister' : IDL.Principal }),
+  });
+  const TopUpConfig = IDL.Record({
+    'source' : TopUpSource,
+    'threshold' : IDL.Nat64,
+  });
+  const CyclesSample = IDL.Record({
+    'balance' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const TopUpRecord = IDL.Record({
+    'source' : TopUpSource,
+    'error' : IDL.Opt(IDL.Text),
+    'cycles' : IDL.Nat64,
+    'timestamp' : IDL.Nat64,
+  });
+  const CyclesInfo = IDL.Record({
+    'balance' : IDL.Nat64,
+    'burn_rate' : IDL.Nat64,
+    'last_sample' : IDL.Opt(CyclesSample),
+    'config' : IDL.Opt(TopUpConfig),
+    'top_ups' : IDL.Vec(TopUpRecord),
+  });
   return IDL.Service({
+    'addFeeExempt' : IDL.Func([IDL.Principal], [], []),
     'allowance' : IDL.Func(
         [IDL.Principal, IDL.Principal],
         [IDL.Nat],
@@ -36,41 +240,202 @@ export const idlFactory = ({ IDL }) => {
       ),
     'approve' : IDL.Func([IDL.Principal, IDL.Nat], [Result], []),
     'balanceOf' : IDL.Func([IDL.Principal], [IDL.Nat], ['query']),
+    'balanceOfAt' : IDL.Func([IDL.Principal, IDL.Nat64], [Result], ['query']),
     'burn' : IDL.Func([IDL.Nat], [Result], []),
+    'cancelStream' : IDL.Func([IDL.Nat64], [Result], []),
+    'certifiedBalanceOf' : IDL.Func(
+        [IDL.Principal],
+        [CertifiedNat],
+        ['query'],
+      ),
+    'certifiedMetadata' : IDL.Func([], [CertifiedMetadata], ['query']),
+    'certifiedTotalSupply' : IDL.Func([], [CertifiedNat], ['query']),
+    'claimStakeRewards' : IDL.Func([IDL.Nat64], [Result], []),
+    'createEscrow' : IDL.Func(
+        [IDL.Principal, IDL.Principal, IDL.Nat, IDL.Nat64],
+        [Result_1],
+        [],
+      ),
+    'createStream' : IDL.Func(
+        [IDL.Principal, IDL.Nat, IDL.Nat],
+        [Result_1],
+        [],
+      ),
+    'createVesting' : IDL.Func(
+        [
+          IDL.Principal,
+          IDL.Nat,
+          IDL.Nat64,
+          IDL.Nat64,
+          IDL.Nat64,
+          IDL.Nat64,
+          IDL.Bool,
+        ],
+        [Result],
+        [],
+      ),
+    'currentSnapshotId' : IDL.Func([], [IDL.Nat64], ['query']),
     'decimals' : IDL.Func([], [IDL.Nat8], ['query']),
+    'escrowedBalanceOf' : IDL.Func([IDL.Principal], [IDL.Nat], ['query']),
+    'forceTransfer' : IDL.Func(
+        [IDL.Principal, IDL.Principal, IDL.Nat, IDL.Text],
+        [Result],
+        [],
+      ),
+    'freezeAccount' : IDL.Func([IDL.Principal, IDL.Text], [Result_2], []),
     'getAllowanceSize' : IDL.Func([], [IDL.Nat64], ['query']),
+    'getCyclesInfo' : IDL.Func([], [CyclesInfo], ['query']),
+    'getEscrow' : IDL.Func([IDL.Nat64], [IDL.Opt(Escrow)], ['query']),
+    'getEscrows' : IDL.Func(
+        [IDL.Principal, IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(Escrow)],
+        ['query'],
+      ),
+    'getFeeExempt' : IDL.Func([], [IDL.Vec(IDL.Principal)], ['query']),
+    'getFeeFor' : IDL.Func(
+        [IDL.Principal, IDL.Principal, IDL.Nat],
+        [IDL.Nat],
+        ['query'],
+      ),
+    'getFeePolicy' : IDL.Func([], [FeePolicy], ['query']),
+    'getFeeTier' : IDL.Func([], [IDL.Opt(FeeTier)], ['query']),
+    'getFreeze' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Opt(FreezeRecord)],
+        ['query'],
+      ),
+    'getFreezeLog' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(FreezeLogEntry)],
+        ['query'],
+      ),
+    'getFrozenAccounts' : IDL.Func(
+        [],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, FreezeRecord))],
+        ['query'],
+      ),
+    'getHolderRank' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Opt(IDL.Nat64)],
+        ['query'],
+      ),
     'getHolders' : IDL.Func(
         [IDL.Nat64, IDL.Nat64],
         [IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat))],
         ['query'],
       ),
+    'getHoldersAfter' : IDL.Func(
+        [IDL.Opt(IDL.Tuple(IDL.Principal, IDL.Nat)), IDL.Nat64],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat))],
+        ['query'],
+      ),
     'getMetadata' : IDL.Func([], [Metadata], ['query']),
+    'getNotification' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(Notification)],
+        ['query'],
+      ),
+    'getNotifications' : IDL.Func(
+        [IDL.Principal, IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(Notification)],
+        ['query'],
+      ),
+    'getSnapshotHolders' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat))],
+        ['query'],
+      ),
+    'getSnapshotHoldersAfter' : IDL.Func(
+        [IDL.Nat64, IDL.Opt(IDL.Principal), IDL.Nat64],
+        [IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat))],
+        ['query'],
+      ),
+    'getStakePositions' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Vec(StakePositionInfo)],
+        ['query'],
+      ),
+    'getStakingInfo' : IDL.Func([], [StakingInfo], ['query']),
+    'getStream' : IDL.Func([IDL.Nat64], [IDL.Opt(StreamInfo)], ['query']),
+    'getStreamsByRecipient' : IDL.Func(
+        [IDL.Principal, IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(StreamInfo)],
+        ['query'],
+      ),
+    'getStreamsBySender' : IDL.Func(
+        [IDL.Principal, IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(StreamInfo)],
+        ['query'],
+      ),
     'getTokenInfo' : IDL.Func([], [TokenInfo], ['query']),
+    'getTransaction' : IDL.Func(
+        [IDL.Nat64],
+        [IDL.Opt(HistoryEntry)],
+        ['query'],
+      ),
+    'getTransactions' : IDL.Func(
+        [IDL.Nat64, IDL.Nat64],
+        [IDL.Vec(HistoryEntry)],
+        ['query'],
+      ),
     'getUserApprovals' : IDL.Func(
         [IDL.Principal],
         [IDL.Vec(IDL.Tuple(IDL.Principal, IDL.Nat))],
         ['query'],
       ),
+    'getVestingSchedules' : IDL.Func(
+        [IDL.Principal],
+        [IDL.Vec(VestingStatus)],
+        ['query'],
+      ),
+    'governance' : IDL.Func([], [IDL.Opt(IDL.Principal)], ['query']),
     'historySize' : IDL.Func([], [IDL.Nat64], ['query']),
+    'http_request' : IDL.Func([HttpRequest], [HttpResponse], ['query']),
+    'lockedBalanceOf' : IDL.Func([IDL.Principal], [IDL.Nat], ['query']),
     'logo' : IDL.Func([], [IDL.Text], ['query']),
     'mint' : IDL.Func([IDL.Principal, IDL.Nat], [Result], []),
     'minter' : IDL.Func([], [IDL.Principal], ['query']),
     'name' : IDL.Func([], [IDL.Text], ['query']),
     'owner' : IDL.Func([], [IDL.Principal], ['query']),
+    'refundEscrow' : IDL.Func([IDL.Nat64], [Result], []),
+    'releaseEscrow' : IDL.Func([IDL.Nat64], [Result], []),
+    'releaseVested' : IDL.Func([], [Result], []),
+    'removeFeeExempt' : IDL.Func([IDL.Principal], [], []),
+    'retryNotification' : IDL.Func([IDL.Nat64], [Result_3], []),
+    'revokeVesting' : IDL.Func([IDL.Nat64], [Result], []),
+    'setEarlyUnlockPenalty' : IDL.Func([IDL.Nat64], [Result_2], []),
     'setFee' : IDL.Func([IDL.Nat], [], []),
+    'setFeePolicy' : IDL.Func([FeePolicy], [Result_2], []),
+    'setFeeTier' : IDL.Func([IDL.Opt(FeeTier)], [Result_2], []),
     'setFeeTo' : IDL.Func([IDL.Principal], [], []),
+    'setGovernance' : IDL.Func([IDL.Opt(IDL.Principal)], [], []),
     'setLogo' : IDL.Func([IDL.Text], [], []),
     'setMinter' : IDL.Func([IDL.Principal], [], []),
     'setName' : IDL.Func([IDL.Text], [], []),
     'setOwner' : IDL.Func([IDL.Principal], [], []),
+    'setStakingEmission' : IDL.Func([IDL.Nat], [], []),
+    'setStakingFeeShare' : IDL.Func([IDL.Nat64], [Result_2], []),
+    'setStakingTerms' : IDL.Func([IDL.Vec(StakingTerm)], [], []),
+    'setTopUpConfig' : IDL.Func([IDL.Opt(TopUpConfig)], [], []),
+    'stake' : IDL.Func([IDL.Nat, IDL.Nat64], [Result], []),
     'symbol' : IDL.Func([], [IDL.Text], ['query']),
+    'takeSnapshot' : IDL.Func([], [IDL.Nat64], []),
     'totalSupply' : IDL.Func([], [IDL.Nat], ['query']),
+    'totalSupplyAt' : IDL.Func([IDL.Nat64], [Result], ['query']),
     'transfer' : IDL.Func([IDL.Principal, IDL.Nat], [Result], []),
+    'transferAndCall' : IDL.Func(
+        [IDL.Principal, IDL.Nat, IDL.Vec(IDL.Nat8)],
+        [Result_1],
+        [],
+      ),
     'transferFrom' : IDL.Func(
         [IDL.Principal, IDL.Principal, IDL.Nat],
         [Result],
         [],
       ),
+    'unfreezeAccount' : IDL.Func([IDL.Principal, IDL.Text], [Result_2], []),
+    'unstake' : IDL.Func([IDL.Nat64], [Result], []),
+    'withdrawFromStream' : IDL.Func([IDL.Nat64], [Result], []),
   });
 };
 export const init = ({ IDL }) => {